@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::Poll;
+
+use indexmap::IndexSet;
+
+use crate::fs::FileSystem;
+
+/// The future returned by the methods of [`AsyncFileSystem`].
+pub type FsFuture<'a, T> = Pin<Box<dyn Future<Output = io::Result<T>> + Send + 'a>>;
+
+/// An asynchronous source of the files read by [`crate::Loader::load_async`], for example one
+/// backed by `tokio::fs` or an HTTP client.
+///
+/// The methods behave like the methods of [`FileSystem`] with the same name.
+pub trait AsyncFileSystem: Send + Sync {
+    fn canonicalize<'a>(&'a self, path: &'a Path) -> FsFuture<'a, PathBuf>;
+
+    fn read<'a>(&'a self, path: &'a Path) -> FsFuture<'a, Vec<u8>>;
+
+    fn read_dir<'a>(&'a self, path: &'a Path) -> FsFuture<'a, Vec<PathBuf>>;
+
+    /// Whether `path` itself is a symbolic link. File systems without links can keep the default.
+    fn is_symlink<'a>(&'a self, _path: &'a Path) -> FsFuture<'a, bool> {
+        Box::pin(async { Ok(false) })
+    }
+}
+
+/// The files fetched from an [`AsyncFileSystem`] so far, served to the loader synchronously.
+///
+/// Paths that were not fetched yet are recorded as misses and do not exist, so that the loader
+/// can fetch all of them at once and resolve the document again.
+#[derive(Default)]
+pub(crate) struct Snapshot {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Fetched paths, both as they were requested and in canonical form.
+    entries: HashMap<PathBuf, Fetched>,
+    misses: IndexSet<PathBuf>,
+}
+
+#[derive(Clone)]
+enum Fetched {
+    File {
+        canonical: PathBuf,
+        contents: Vec<u8>,
+        /// Whether the requested path is a symbolic link.
+        symlink: bool,
+    },
+    Dir {
+        canonical: PathBuf,
+        entries: Vec<PathBuf>,
+        symlink: bool,
+    },
+    Missing(io::ErrorKind),
+}
+
+impl Snapshot {
+    /// Fetches all of `paths` from `fs` concurrently.
+    pub(crate) async fn fetch(&self, fs: &dyn AsyncFileSystem, paths: Vec<PathBuf>) {
+        let fetched = join_all(paths.iter().map(|path| fetch(fs, path)).collect()).await;
+        let mut state = self.state.lock().unwrap();
+        for (path, fetched) in paths.into_iter().zip(fetched) {
+            let mut resolved = fetched.clone();
+            if let Fetched::File {
+                canonical, symlink, ..
+            }
+            | Fetched::Dir {
+                canonical, symlink, ..
+            } = &mut resolved
+            {
+                // The canonical path of a file is never a link.
+                *symlink = false;
+                let canonical = canonical.clone();
+                state.entries.insert(canonical, resolved);
+            }
+            state.entries.insert(path, fetched);
+        }
+    }
+
+    /// Returns the paths that were requested since the last call but not fetched yet.
+    pub(crate) fn take_misses(&self) -> Vec<PathBuf> {
+        let mut state = self.state.lock().unwrap();
+        std::mem::take(&mut state.misses).into_iter().collect()
+    }
+
+    fn get(&self, path: &Path) -> io::Result<Fetched> {
+        let mut state = self.state.lock().unwrap();
+        match state.entries.get(path) {
+            Some(Fetched::Missing(kind)) => Err(error(*kind, path)),
+            Some(fetched) => Ok(fetched.clone()),
+            None => {
+                state.misses.insert(path.to_path_buf());
+                Err(error(io::ErrorKind::NotFound, path))
+            }
+        }
+    }
+}
+
+impl FileSystem for Snapshot {
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        match self.get(path)? {
+            Fetched::File { canonical, .. } | Fetched::Dir { canonical, .. } => Ok(canonical),
+            Fetched::Missing(_) => unreachable!(),
+        }
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self.get(path)? {
+            Fetched::File { contents, .. } => Ok(contents),
+            _ => Err(error(io::ErrorKind::InvalidInput, path)),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        match self.get(path)? {
+            Fetched::Dir { entries, .. } => Ok(entries),
+            _ => Err(error(io::ErrorKind::InvalidInput, path)),
+        }
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        matches!(self.get(path), Ok(Fetched::File { .. }))
+    }
+
+    fn is_symlink(&self, path: &Path) -> bool {
+        matches!(
+            self.get(path),
+            Ok(Fetched::File { symlink: true, .. } | Fetched::Dir { symlink: true, .. })
+        )
+    }
+}
+
+/// Fetches `path`, which is a directory if it cannot be read as a file.
+async fn fetch(fs: &dyn AsyncFileSystem, path: &Path) -> Fetched {
+    let canonical = match fs.canonicalize(path).await {
+        Ok(canonical) => canonical,
+        Err(err) => return Fetched::Missing(err.kind()),
+    };
+    let symlink = fs.is_symlink(path).await.unwrap_or(false);
+    if let Ok(contents) = fs.read(&canonical).await {
+        return Fetched::File {
+            canonical,
+            contents,
+            symlink,
+        };
+    }
+    match fs.read_dir(&canonical).await {
+        Ok(entries) => Fetched::Dir {
+            canonical,
+            entries,
+            symlink,
+        },
+        Err(err) => Fetched::Missing(err.kind()),
+    }
+}
+
+/// Polls all of `futures` concurrently and returns their outputs in order.
+async fn join_all<F: Future>(futures: Vec<F>) -> Vec<F::Output> {
+    let mut futures = futures.into_iter().map(Box::pin).collect::<Vec<_>>();
+    let mut outputs = futures.iter().map(|_| None).collect::<Vec<_>>();
+    std::future::poll_fn(|cx| {
+        let mut done = true;
+        for (future, output) in futures.iter_mut().zip(&mut outputs) {
+            if output.is_none() {
+                match future.as_mut().poll(cx) {
+                    Poll::Ready(value) => *output = Some(value),
+                    Poll::Pending => done = false,
+                }
+            }
+        }
+        if done {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await;
+    outputs.into_iter().map(Option::unwrap).collect()
+}
+
+fn error(kind: io::ErrorKind, path: &Path) -> io::Error {
+    io::Error::new(kind, format!("Cannot access {}", path.display()))
+}
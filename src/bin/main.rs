@@ -3,6 +3,10 @@ use ron_parser::{load, parse};
 fn main() {
     // Parse input arguments
     let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("deps") {
+        deps(&args[2..]);
+        return;
+    }
     for arg in args.iter().skip(1) {
         if arg.ends_with(".ron") {
             let parse = load(arg).unwrap();
@@ -19,3 +23,37 @@ fn main() {
         }
     }
 }
+
+/// `ron-parser deps [--dot] [--target <target>] <file.ron>` prints the files that a document
+/// depends on, as a Makefile rule or as a Graphviz graph.
+fn deps(args: &[String]) {
+    let usage = "Usage: ron-parser deps [--dot] [--target <target>] <file.ron>";
+    let mut dot = false;
+    let mut target = None;
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dot" => dot = true,
+            "--target" => match args.next() {
+                Some(name) => target = Some(name.clone()),
+                None => exit_with(usage),
+            },
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(arg.clone()),
+            _ => exit_with(usage),
+        }
+    }
+    let path = path.unwrap_or_else(|| exit_with(usage));
+    let parse = load(&path).unwrap_or_else(|err| exit_with(&format!("{}: {}", path, err)));
+    parse.emit();
+    if dot {
+        print!("{}", parse.graph.dot());
+    } else {
+        print!("{}", parse.graph.makefile(target.as_deref().unwrap_or(&path)));
+    }
+}
+
+fn exit_with(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1)
+}
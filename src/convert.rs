@@ -0,0 +1,416 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::Hash;
+
+use indexmap::IndexMap;
+
+use crate::provenance;
+use crate::value::{Map, Number, Struct, Value};
+
+/// Error returned when a [`Value`] is converted to a Rust type that it does not match.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TypeError {
+    /// Describes the expected value, e.g. "a string".
+    pub expected: &'static str,
+    /// Describes the value that was found instead, e.g. "a list".
+    pub found: &'static str,
+    /// Path of the mismatching value inside the converted value, such as `spells[1].damage`,
+    /// or the empty string if the converted value itself does not match.
+    pub path: String,
+}
+
+impl TypeError {
+    pub fn new(expected: &'static str, found: &Value) -> TypeError {
+        TypeError {
+            expected,
+            found: describe(found),
+            path: String::new(),
+        }
+    }
+
+    /// Moves the error into the field or entry `name` of the value that is being converted.
+    fn in_field(mut self, name: &str) -> TypeError {
+        self.path = join(&provenance::field("", name), &self.path);
+        self
+    }
+
+    /// Moves the error into the element `index` of the value that is being converted.
+    fn in_element(mut self, index: usize) -> TypeError {
+        self.path = join(&provenance::element("", index), &self.path);
+        self
+    }
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "Expected {}, found {}", self.expected, self.found)
+        } else {
+            write!(
+                f,
+                "Expected {} at `{}`, found {}",
+                self.expected, self.path, self.found
+            )
+        }
+    }
+}
+
+impl std::error::Error for TypeError {}
+
+/// Conversion from a resolved [`Value`].
+///
+/// This is what the `TryFrom<Value>` implementations use, and it is implemented for nested types
+/// such as `Vec<HashMap<String, i64>>` or `Option<Vec<String>>` that `TryFrom` cannot cover.
+pub trait FromValue: Sized {
+    fn from_value(value: Value) -> Result<Self, TypeError>;
+}
+
+impl FromValue for Value {
+    fn from_value(value: Value) -> Result<Self, TypeError> {
+        Ok(value)
+    }
+}
+
+impl FromValue for () {
+    fn from_value(value: Value) -> Result<Self, TypeError> {
+        match value {
+            Value::Unit => Ok(()),
+            value => Err(TypeError::new("unit", &value)),
+        }
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: Value) -> Result<Self, TypeError> {
+        value
+            .as_bool()
+            .ok_or_else(|| TypeError::new("a bool", &value))
+    }
+}
+
+impl FromValue for char {
+    fn from_value(value: Value) -> Result<Self, TypeError> {
+        value
+            .as_char()
+            .ok_or_else(|| TypeError::new("a char", &value))
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: Value) -> Result<Self, TypeError> {
+        match value {
+            Value::String(s) => Ok(s),
+            value => Err(TypeError::new("a string", &value)),
+        }
+    }
+}
+
+macro_rules! integer_from_value {
+    ($($ty:ty),*) => {
+        $(
+            impl FromValue for $ty {
+                fn from_value(value: Value) -> Result<Self, TypeError> {
+                    integer(
+                        value,
+                        concat!("an integer in the range of `", stringify!($ty), "`"),
+                    )
+                }
+            }
+        )*
+    };
+}
+
+integer_from_value!(i8, i16, i32, i64, isize, u8, u16, u32, usize);
+
+/// Also accepts the integral floats that `Value::from(u64)` produces above `i64::MAX`. Such values
+/// only keep the precision of an `f64`, so the values closest to `u64::MAX` round up to 2^64 and
+/// do not convert back.
+impl FromValue for u64 {
+    fn from_value(value: Value) -> Result<Self, TypeError> {
+        match value {
+            Value::Number(Number::Float(f))
+                if f.get().fract() == 0.0 && f.get() >= 9_223_372_036_854_775_808.0 =>
+            {
+                if f.get() < 18_446_744_073_709_551_616.0 {
+                    Ok(f.get() as u64)
+                } else {
+                    Err(TypeError::new("an integer in the range of `u64`", &value))
+                }
+            }
+            value => integer(value, "an integer in the range of `u64`"),
+        }
+    }
+}
+
+/// Converts an integer to `T`, or describes the value as `expected` if it is out of range.
+fn integer<T: TryFrom<i64>>(value: Value, expected: &'static str) -> Result<T, TypeError> {
+    match value.as_i64() {
+        Some(i) => T::try_from(i).map_err(|_| TypeError::new(expected, &value)),
+        None => Err(TypeError::new("an integer", &value)),
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(value: Value) -> Result<Self, TypeError> {
+        value
+            .as_f64()
+            .ok_or_else(|| TypeError::new("a number", &value))
+    }
+}
+
+impl FromValue for f32 {
+    fn from_value(value: Value) -> Result<Self, TypeError> {
+        f64::from_value(value).map(|f| f as f32)
+    }
+}
+
+/// Accepts `None`, `Some(value)` and unit for `None`.
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: Value) -> Result<Self, TypeError> {
+        match value.as_option() {
+            Some(_) => match value {
+                Value::Option(Some(value)) => T::from_value(*value).map(Some),
+                Value::Tuple(_, mut values) => T::from_value(values.remove(0)).map(Some),
+                _ => Ok(None),
+            },
+            None if value.is_unit() => Ok(None),
+            None => Err(TypeError::new("an option", &value)),
+        }
+    }
+}
+
+/// Accepts lists, tuples and bytes. `Some(...)` is an option, not a tuple.
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: Value) -> Result<Self, TypeError> {
+        let values = match value {
+            Value::Tuple(Some(ref name), _) if name == "Some" => {
+                return Err(TypeError::new("a list", &value))
+            }
+            Value::Seq(values) | Value::Tuple(_, values) => values,
+            Value::Bytes(bytes) => bytes
+                .into_iter()
+                .map(|b| Value::Number(Number::from(i64::from(b))))
+                .collect(),
+            value => return Err(TypeError::new("a list", &value)),
+        };
+        values
+            .into_iter()
+            .enumerate()
+            .map(|(i, value)| T::from_value(value).map_err(|err| err.in_element(i)))
+            .collect()
+    }
+}
+
+/// Accepts maps and the fields of structs.
+impl<K: FromValue + Eq + Hash, V: FromValue> FromValue for HashMap<K, V> {
+    fn from_value(value: Value) -> Result<Self, TypeError> {
+        entries(value)?.collect()
+    }
+}
+
+/// Accepts maps and the fields of structs, and keeps their order.
+impl<K: FromValue + Eq + Hash, V: FromValue> FromValue for IndexMap<K, V> {
+    fn from_value(value: Value) -> Result<Self, TypeError> {
+        entries(value)?.collect()
+    }
+}
+
+/// Converts the entries of a map or the fields of a struct.
+fn entries<K: FromValue, V: FromValue>(
+    value: Value,
+) -> Result<impl Iterator<Item = Result<(K, V), TypeError>>, TypeError> {
+    let entries: Vec<(Value, Value)> = match value {
+        Value::Map(map) => map.0.into_iter().collect(),
+        Value::Struct(s) => s
+            .fields
+            .into_iter()
+            .map(|(name, value)| (Value::String(name), value))
+            .collect(),
+        value => return Err(TypeError::new("a map", &value)),
+    };
+    Ok(entries.into_iter().map(|(key, value)| {
+        let name = match &key {
+            Value::String(s) => s.clone(),
+            Value::Number(Number::Integer(i)) => i.to_string(),
+            _ => String::new(),
+        };
+        let key = K::from_value(key)?;
+        let value = V::from_value(value).map_err(|err| err.in_field(&name))?;
+        Ok((key, value))
+    }))
+}
+
+macro_rules! try_from_value {
+    ($($ty:ty),*) => {
+        $(
+            impl TryFrom<Value> for $ty {
+                type Error = TypeError;
+
+                fn try_from(value: Value) -> Result<Self, TypeError> {
+                    <$ty>::from_value(value)
+                }
+            }
+
+            impl TryFrom<Value> for Option<$ty> {
+                type Error = TypeError;
+
+                fn try_from(value: Value) -> Result<Self, TypeError> {
+                    Option::<$ty>::from_value(value)
+                }
+            }
+        )*
+    };
+}
+
+try_from_value!(
+    (),
+    bool,
+    char,
+    String,
+    i8,
+    i16,
+    i32,
+    i64,
+    isize,
+    u8,
+    u16,
+    u32,
+    u64,
+    usize,
+    f32,
+    f64
+);
+
+impl<T: FromValue> TryFrom<Value> for Vec<T> {
+    type Error = TypeError;
+
+    fn try_from(value: Value) -> Result<Self, TypeError> {
+        Vec::from_value(value)
+    }
+}
+
+impl<K: FromValue + Eq + Hash, V: FromValue> TryFrom<Value> for HashMap<K, V> {
+    type Error = TypeError;
+
+    fn try_from(value: Value) -> Result<Self, TypeError> {
+        HashMap::from_value(value)
+    }
+}
+
+impl<K: FromValue + Eq + Hash, V: FromValue> TryFrom<Value> for IndexMap<K, V> {
+    type Error = TypeError;
+
+    fn try_from(value: Value) -> Result<Self, TypeError> {
+        IndexMap::from_value(value)
+    }
+}
+
+impl From<()> for Value {
+    fn from(_: ()) -> Value {
+        Value::Unit
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+
+impl From<char> for Value {
+    fn from(c: char) -> Value {
+        Value::Char(c)
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::String(s)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+}
+
+macro_rules! number_into_value {
+    ($($ty:ty => $via:ty),*) => {
+        $(
+            impl From<$ty> for Value {
+                fn from(n: $ty) -> Value {
+                    Value::Number(Number::from(<$via>::from(n)))
+                }
+            }
+        )*
+    };
+}
+
+number_into_value!(
+    i8 => i64, i16 => i64, i32 => i64, i64 => i64,
+    u8 => i64, u16 => i64, u32 => i64, u64 => u64,
+    f32 => f64, f64 => f64
+);
+
+impl From<Number> for Value {
+    fn from(n: Number) -> Value {
+        Value::Number(n)
+    }
+}
+
+impl From<Map> for Value {
+    fn from(map: Map) -> Value {
+        Value::Map(map)
+    }
+}
+
+impl From<Struct> for Value {
+    fn from(s: Struct) -> Value {
+        Value::Struct(s)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(option: Option<T>) -> Value {
+        Value::Option(option.map(|value| Box::new(value.into())))
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(values: Vec<T>) -> Value {
+        Value::Seq(values.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<K: Into<Value>, V: Into<Value>> From<HashMap<K, V>> for Value {
+    fn from(map: HashMap<K, V>) -> Value {
+        Value::Map(map.into_iter().map(|(k, v)| (k.into(), v.into())).collect())
+    }
+}
+
+impl<K: Into<Value>, V: Into<Value>> From<IndexMap<K, V>> for Value {
+    fn from(map: IndexMap<K, V>) -> Value {
+        Value::Map(map.into_iter().map(|(k, v)| (k.into(), v.into())).collect())
+    }
+}
+
+/// Describes `value` like [`Value::kind_name`], but tells integers and floats apart.
+fn describe(value: &Value) -> &'static str {
+    match value {
+        Value::Number(Number::Integer(_)) => "an integer",
+        Value::Number(Number::Float(_)) => "a float",
+        Value::Tuple(Some(name), _) if name == "Some" => "an option",
+        value => value.kind_name(),
+    }
+}
+
+/// Appends the relative path `inner` to the path `outer`.
+fn join(outer: &str, inner: &str) -> String {
+    if inner.is_empty() || inner.starts_with('[') {
+        format!("{}{}", outer, inner)
+    } else {
+        format!("{}.{}", outer, inner)
+    }
+}
@@ -0,0 +1,127 @@
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use ariadne::{Label, Report, ReportBuilder, ReportKind};
+
+use crate::graph::EdgeKind;
+use crate::token::Span;
+use crate::value::{DirectiveCall, Value};
+use crate::{LoadOptions, Loader};
+
+/// An error or warning produced by a custom directive.
+pub type Diagnostic = ReportBuilder<(String, Range<usize>)>;
+
+/// A custom directive such as `#asset("icon.png")` or `#color("#ff0000")`.
+///
+/// Directives are registered with [`LoadOptions::directive`]. The loader resolves the arguments
+/// of a directive before passing them to [`Directive::resolve`], and replaces the directive with
+/// the returned value. Builtin directives take precedence over custom directives of the same name.
+pub trait Directive: Send + Sync {
+    fn resolve(&self, call: &DirectiveCall, cx: &mut DirectiveContext)
+        -> Result<Value, Diagnostic>;
+}
+
+impl<F> Directive for F
+where
+    F: Fn(&DirectiveCall, &mut DirectiveContext) -> Result<Value, Diagnostic> + Send + Sync,
+{
+    fn resolve(
+        &self,
+        call: &DirectiveCall,
+        cx: &mut DirectiveContext,
+    ) -> Result<Value, Diagnostic> {
+        self(call, cx)
+    }
+}
+
+impl fmt::Debug for dyn Directive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Directive")
+    }
+}
+
+/// Gives a custom directive access to the loader that is resolving it.
+pub struct DirectiveContext<'a> {
+    loader: &'a mut Loader,
+    origin: &'a Path,
+    call: &'a DirectiveCall,
+}
+
+impl<'a> DirectiveContext<'a> {
+    pub(crate) fn new(
+        loader: &'a mut Loader,
+        origin: &'a Path,
+        call: &'a DirectiveCall,
+    ) -> DirectiveContext<'a> {
+        DirectiveContext {
+            loader,
+            origin,
+            call,
+        }
+    }
+
+    /// Path of the file that contains the directive.
+    pub fn origin(&self) -> &Path {
+        self.origin
+    }
+
+    pub fn options(&self) -> &LoadOptions {
+        &self.loader.options
+    }
+
+    /// Loads and resolves a RON file. Paths are resolved like `#include` paths, so they can be
+    /// relative to the file that contains the directive, to a search path or to a named root.
+    pub fn load(&mut self, path: &str) -> Result<Value, std::io::Error> {
+        let path = self.find(path)?;
+        self.loader.load_file(&path)
+    }
+
+    /// Reads a file without parsing it. Paths are resolved like `#include` paths. The file is
+    /// recorded in [`crate::Parse::dependencies`].
+    pub fn read(&mut self, path: &str) -> Result<Vec<u8>, std::io::Error> {
+        let path = self.find(path)?;
+        self.loader.read(&path)
+    }
+
+    /// Resolves `path` and records it in [`crate::Parse::graph`] as a dependency through this
+    /// directive.
+    fn find(&mut self, path: &str) -> Result<PathBuf, std::io::Error> {
+        let path = self
+            .loader
+            .find(path, self.origin)
+            .map_err(|err| err.io_error(path))?;
+        let kind = EdgeKind::Directive(self.call.name.clone());
+        self.loader.link(&path, kind, Some(self.call.span));
+        Ok(path)
+    }
+
+    /// Starts an error with a label at `span` in the file that contains the directive.
+    pub fn error<M: ToString>(&self, span: Span, message: M) -> Diagnostic {
+        self.report(ReportKind::Error, span, message)
+    }
+
+    /// Starts a warning with a label at `span` in the file that contains the directive. Report it
+    /// with [`DirectiveContext::emit_warning`].
+    pub fn warning<M: ToString>(&self, span: Span, message: M) -> Diagnostic {
+        self.report(ReportKind::Warning, span, message)
+    }
+
+    /// Reports an error without failing the directive.
+    pub fn emit(&mut self, diagnostic: Diagnostic) {
+        self.loader.errors.push(diagnostic.finish());
+    }
+
+    /// Reports a warning, which is added to [`crate::Parse::warnings`] and does not make the
+    /// document invalid.
+    pub fn emit_warning(&mut self, diagnostic: Diagnostic) {
+        self.loader.warn(diagnostic.finish());
+    }
+
+    fn report<M: ToString>(&self, kind: ReportKind, span: Span, message: M) -> Diagnostic {
+        let source_name = self.origin.to_str().unwrap().to_string();
+        Report::build(kind, source_name.clone(), span.start)
+            .with_message(message)
+            .with_label(Label::new((source_name, span.start..span.end)))
+    }
+}
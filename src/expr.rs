@@ -0,0 +1,399 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::Range;
+
+use ariadne::{Label, Report, ReportBuilder, ReportKind};
+
+use crate::token::Span;
+use crate::value::{CfgPredicate, Number, Value};
+
+type RB = ReportBuilder<(String, Range<usize>)>;
+
+/// Resolves a name with an optional selection path to a value.
+type Lookup<'a> = dyn FnMut(&str, Option<&str>, Span) -> Result<Value, RB> + 'a;
+
+/// An arithmetic expression written as `#expr(BASE_HP * 1.5)` or as a bare `60 / 4`.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum ExprKind {
+    /// A literal or directive such as `#ref(BASE_HP)`.
+    Value(Box<Value>),
+    /// A constant defined with `#defs`/`#let` or, if the expression is the value of a struct
+    /// field, a sibling field. `select` is a path into the named value, as for `#ref`.
+    Name {
+        name: String,
+        select: Option<String>,
+    },
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    /// A call to one of the builtin functions `min` and `max`.
+    Call(String, Vec<Expr>),
+    /// A string literal with `${...}` placeholders, split into literal parts and placeholders.
+    Format(Vec<Expr>),
+    /// `cfg(debug)`, which is `true` if the predicate matches the active profile and flags.
+    Cfg(CfgPredicate),
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+impl BinaryOp {
+    /// Binding strength of the operator, higher binds tighter.
+    pub fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::And => 2,
+            BinaryOp::Eq
+            | BinaryOp::Ne
+            | BinaryOp::Lt
+            | BinaryOp::Le
+            | BinaryOp::Gt
+            | BinaryOp::Ge => 3,
+            BinaryOp::Add | BinaryOp::Sub => 4,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => 5,
+        }
+    }
+
+    pub fn is_arithmetic(self) -> bool {
+        matches!(
+            self,
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem
+        )
+    }
+
+    pub fn str(self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Rem => "%",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::And => "&&",
+            BinaryOp::Or => "||",
+        }
+    }
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.str())
+    }
+}
+
+impl Expr {
+    /// Calls `f` on every value embedded in the expression.
+    pub fn values_mut<E>(
+        &mut self,
+        f: &mut impl FnMut(&mut Value) -> Result<(), E>,
+    ) -> Result<(), E> {
+        match &mut self.kind {
+            ExprKind::Value(value) => f(value),
+            ExprKind::Name { .. } | ExprKind::Cfg(_) => Ok(()),
+            ExprKind::Unary(_, operand) => operand.values_mut(f),
+            ExprKind::Binary(_, lhs, rhs) => {
+                lhs.values_mut(f)?;
+                rhs.values_mut(f)
+            }
+            ExprKind::Call(_, args) | ExprKind::Format(args) => {
+                args.iter_mut().try_for_each(|arg| arg.values_mut(f))
+            }
+        }
+    }
+
+    pub fn fmt_as_rust(&self) -> String {
+        let kind = match &self.kind {
+            ExprKind::Value(value) => format!("ExprKind::Value({})", value.fmt_as_rust()),
+            ExprKind::Name { name, select } => format!(
+                "ExprKind::Name{{name: \"{}\".to_string(), select: {}}}",
+                name,
+                match select {
+                    None => "None".to_string(),
+                    Some(s) => format!("Some(\"{}\".to_string())", s),
+                },
+            ),
+            ExprKind::Unary(op, operand) => format!(
+                "ExprKind::Unary(UnaryOp::{:?}, Box::new({}))",
+                op,
+                operand.fmt_as_rust()
+            ),
+            ExprKind::Binary(op, lhs, rhs) => format!(
+                "ExprKind::Binary(BinaryOp::{:?}, Box::new({}), Box::new({}))",
+                op,
+                lhs.fmt_as_rust(),
+                rhs.fmt_as_rust()
+            ),
+            ExprKind::Call(name, args) => format!(
+                "ExprKind::Call(\"{}\".to_string(), vec![{}])",
+                name,
+                args.iter()
+                    .map(|arg| arg.fmt_as_rust())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            ExprKind::Cfg(predicate) => format!("ExprKind::Cfg({})", predicate.fmt_as_rust()),
+            ExprKind::Format(parts) => format!(
+                "ExprKind::Format(vec![{}])",
+                parts
+                    .iter()
+                    .map(|part| part.fmt_as_rust())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
+        format!(
+            "Expr{{kind: {}, span: Span{{start: {}, end: {}}} }}",
+            kind, self.span.start, self.span.end
+        )
+    }
+}
+
+/// Evaluates an expression whose embedded values have already been resolved.
+///
+/// `lookup` resolves names to values and `is_set` decides which names match in `cfg(...)`.
+/// Integer arithmetic stays integral, except for divisions
+/// with a remainder, which produce a float.
+pub(crate) fn eval(
+    expr: &Expr,
+    source_name: &str,
+    lookup: &mut Lookup,
+    is_set: &dyn Fn(&str) -> bool,
+) -> Result<Value, RB> {
+    let error = |span: Span, message: String, label: String| {
+        Report::build(ReportKind::Error, source_name.to_string(), span.start)
+            .with_message(message)
+            .with_label(
+                Label::new((source_name.to_string(), span.start..span.end)).with_message(label),
+            )
+    };
+    match &expr.kind {
+        ExprKind::Value(value) => Ok((**value).clone()),
+        ExprKind::Name { name, select } => lookup(name, select.as_deref(), expr.span),
+        ExprKind::Unary(UnaryOp::Neg, operand) => match eval(operand, source_name, lookup, is_set)?
+        {
+            Value::Number(Number::Integer(i)) => match i.checked_neg() {
+                Some(i) => Ok(Value::Number(Number::Integer(i))),
+                None => Err(error(
+                    expr.span,
+                    "Integer overflow".to_string(),
+                    "Result does not fit into a 64-bit integer".to_string(),
+                )),
+            },
+            Value::Number(Number::Float(f)) => Ok(Value::Number(Number::from(-f.get()))),
+            value => Err(error(
+                operand.span,
+                format!("Cannot negate {}", value.kind_name()),
+                format!("Expected a number, found {}", value.kind_name()),
+            )),
+        },
+        ExprKind::Unary(UnaryOp::Not, operand) => match eval(operand, source_name, lookup, is_set)?
+        {
+            Value::Bool(b) => Ok(Value::Bool(!b)),
+            value => Err(error(
+                operand.span,
+                format!("Cannot apply `!` to {}", value.kind_name()),
+                format!("Expected a bool, found {}", value.kind_name()),
+            )),
+        },
+        ExprKind::Binary(op, lhs_expr, rhs_expr) => {
+            let lhs = eval(lhs_expr, source_name, lookup, is_set)?;
+            if let (BinaryOp::And | BinaryOp::Or, Value::Bool(l)) = (op, &lhs) {
+                // Short-circuit so that the right-hand side may refer to undefined names.
+                if *l == (*op == BinaryOp::Or) {
+                    return Ok(Value::Bool(*l));
+                }
+            }
+            let rhs = eval(rhs_expr, source_name, lookup, is_set)?;
+            let ordering = compare(&lhs, &rhs);
+            match (&lhs, &rhs) {
+                (Value::Bool(_), Value::Bool(r)) if matches!(op, BinaryOp::And | BinaryOp::Or) => {
+                    Ok(Value::Bool(*r))
+                }
+                _ if matches!(op, BinaryOp::Eq | BinaryOp::Ne) => {
+                    let equal = ordering.map_or(lhs == rhs, Ordering::is_eq);
+                    Ok(Value::Bool(equal == (*op == BinaryOp::Eq)))
+                }
+                _ if matches!(
+                    op,
+                    BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge
+                ) && ordering.is_some() =>
+                {
+                    let ordering = ordering.unwrap();
+                    Ok(Value::Bool(match op {
+                        BinaryOp::Lt => ordering.is_lt(),
+                        BinaryOp::Le => ordering.is_le(),
+                        BinaryOp::Gt => ordering.is_gt(),
+                        _ => ordering.is_ge(),
+                    }))
+                }
+                (Value::Number(l), Value::Number(r)) if op.is_arithmetic() => {
+                    arithmetic(*op, *l, *r).ok_or_else(|| {
+                        if matches!(op, BinaryOp::Div | BinaryOp::Rem) && r.into_f64() == 0.0 {
+                            error(
+                                rhs_expr.span,
+                                "Division by zero".to_string(),
+                                "This evaluates to zero".to_string(),
+                            )
+                        } else {
+                            error(
+                                expr.span,
+                                "Integer overflow".to_string(),
+                                "Result does not fit into a 64-bit integer".to_string(),
+                            )
+                        }
+                    })
+                }
+                (Value::String(_), _) | (_, Value::String(_))
+                    if *op == BinaryOp::Add && lhs.is_scalar() && rhs.is_scalar() =>
+                {
+                    Ok(Value::String(format!(
+                        "{}{}",
+                        lhs.to_plain_string(),
+                        rhs.to_plain_string()
+                    )))
+                }
+                _ => {
+                    Err(
+                        Report::build(ReportKind::Error, source_name.to_string(), expr.span.start)
+                            .with_message(format!(
+                                "Cannot apply `{}` to {} and {}",
+                                op,
+                                lhs.kind_name(),
+                                rhs.kind_name()
+                            ))
+                            .with_label(
+                                Label::new((
+                                    source_name.to_string(),
+                                    lhs_expr.span.start..lhs_expr.span.end,
+                                ))
+                                .with_message(format!("This is {}", lhs.kind_name())),
+                            )
+                            .with_label(
+                                Label::new((
+                                    source_name.to_string(),
+                                    rhs_expr.span.start..rhs_expr.span.end,
+                                ))
+                                .with_message(format!("This is {}", rhs.kind_name())),
+                            ),
+                    )
+                }
+            }
+        }
+        ExprKind::Call(name, args) => {
+            let mut result: Option<Number> = None;
+            for arg in args {
+                let n = match eval(arg, source_name, lookup, is_set)? {
+                    Value::Number(n) => n,
+                    value => {
+                        return Err(error(
+                            arg.span,
+                            format!("`{}` expects numbers", name),
+                            format!("Expected a number, found {}", value.kind_name()),
+                        ))
+                    }
+                };
+                result = Some(match result {
+                    None => n,
+                    Some(m) if (name == "min") == (n.into_f64() < m.into_f64()) => n,
+                    Some(m) => m,
+                });
+            }
+            result.map(Value::Number).ok_or_else(|| {
+                error(
+                    expr.span,
+                    format!("`{}` expects at least one argument", name),
+                    "No arguments given".to_string(),
+                )
+            })
+        }
+        ExprKind::Cfg(predicate) => Ok(Value::Bool(predicate.eval(is_set))),
+        ExprKind::Format(parts) => {
+            let mut string = String::new();
+            for part in parts {
+                let value = eval(part, source_name, lookup, is_set)?;
+                if !value.is_scalar() {
+                    return Err(error(
+                        part.span,
+                        format!("Cannot interpolate {}", value.kind_name()),
+                        format!(
+                            "This is {}, expected a string, number, bool or char",
+                            value.kind_name()
+                        ),
+                    ));
+                }
+                string.push_str(&value.to_plain_string());
+            }
+            Ok(Value::String(string))
+        }
+    }
+}
+
+/// Orders two numbers, strings or chars. Integers and floats are compared by value.
+fn compare(lhs: &Value, rhs: &Value) -> Option<Ordering> {
+    match (lhs, rhs) {
+        (Value::Number(Number::Integer(l)), Value::Number(Number::Integer(r))) => Some(l.cmp(r)),
+        (Value::Number(l), Value::Number(r)) => l.into_f64().partial_cmp(&r.into_f64()),
+        (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+        (Value::Char(l), Value::Char(r)) => Some(l.cmp(r)),
+        _ => None,
+    }
+}
+
+/// Applies an arithmetic operator to two numbers, returning `None` on division by zero or
+/// integer overflow.
+fn arithmetic(op: BinaryOp, lhs: Number, rhs: Number) -> Option<Value> {
+    let number = match (lhs, rhs) {
+        (Number::Integer(l), Number::Integer(r)) => match op {
+            BinaryOp::Add => Number::Integer(l.checked_add(r)?),
+            BinaryOp::Sub => Number::Integer(l.checked_sub(r)?),
+            BinaryOp::Mul => Number::Integer(l.checked_mul(r)?),
+            BinaryOp::Div if l.checked_rem(r)? != 0 => Number::from(l as f64 / r as f64),
+            BinaryOp::Div => Number::Integer(l.checked_div(r)?),
+            BinaryOp::Rem => Number::Integer(l.checked_rem(r)?),
+            _ => return None,
+        },
+        _ => {
+            let (l, r) = (lhs.into_f64(), rhs.into_f64());
+            match op {
+                BinaryOp::Add => Number::from(l + r),
+                BinaryOp::Sub => Number::from(l - r),
+                BinaryOp::Mul => Number::from(l * r),
+                BinaryOp::Div | BinaryOp::Rem if r == 0.0 => return None,
+                BinaryOp::Div => Number::from(l / r),
+                BinaryOp::Rem => Number::from(l % r),
+                _ => return None,
+            }
+        }
+    };
+    Some(Value::Number(number))
+}
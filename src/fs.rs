@@ -0,0 +1,205 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+/// The source of the files read by a [`crate::Loader`].
+pub trait FileSystem: Send + Sync {
+    /// Returns the path that identifies the file or directory at `path`, with all `.` and `..`
+    /// components resolved. Fails if nothing exists at `path`.
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        String::from_utf8(self.read(path)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Returns the paths of all files and directories directly inside the directory at `path`.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    fn is_file(&self, path: &Path) -> bool;
+
+    /// Whether `path` itself is a symbolic link. File systems without links can keep the default.
+    fn is_symlink(&self, _path: &Path) -> bool {
+        false
+    }
+
+    /// Size of the file at `path` in bytes.
+    fn len(&self, path: &Path) -> io::Result<u64> {
+        Ok(self.read(path)?.len() as u64)
+    }
+
+    /// Last modification time of the file at `path`, used to detect changed files without
+    /// reading them. File systems that do not track it fall back to comparing contents.
+    fn modified(&self, _path: &Path) -> io::Result<SystemTime> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Modification times are not supported",
+        ))
+    }
+}
+
+/// Reads files from the local file system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdFs;
+
+impl FileSystem for StdFs {
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        path.canonicalize()
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        std::fs::read_to_string(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        std::fs::read_dir(path)?
+            .map(|entry| Ok(entry?.path()))
+            .collect()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn is_symlink(&self, path: &Path) -> bool {
+        path.is_symlink()
+    }
+
+    fn len(&self, path: &Path) -> io::Result<u64> {
+        Ok(std::fs::metadata(path)?.len())
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        std::fs::metadata(path)?.modified()
+    }
+}
+
+/// Serves files from memory, e.g. for bundled assets or test fixtures.
+///
+/// Relative paths are interpreted relative to the root `/`. Directories exist implicitly for
+/// every prefix of a file path.
+///
+/// Clones share the same files, so a clone can be kept to change the files seen by a
+/// [`crate::Loader`] or [`crate::Watcher`] that owns the file system.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryFs {
+    files: Arc<RwLock<BTreeMap<PathBuf, Vec<u8>>>>,
+}
+
+impl MemoryFs {
+    pub fn new() -> MemoryFs {
+        MemoryFs::default()
+    }
+
+    /// Adds a file, replacing any previous file at the same path.
+    pub fn insert<P: AsRef<Path>, C: Into<Vec<u8>>>(&self, path: P, contents: C) {
+        self.files
+            .write()
+            .unwrap()
+            .insert(normalize(path.as_ref()), contents.into());
+    }
+
+    /// Adds a file and returns `self`, for building a file system in a single expression.
+    pub fn with_file<P: AsRef<Path>, C: Into<Vec<u8>>>(self, path: P, contents: C) -> Self {
+        self.insert(path, contents);
+        self
+    }
+
+    /// Removes a file, returning its contents if it existed.
+    pub fn remove<P: AsRef<Path>>(&self, path: P) -> Option<Vec<u8>> {
+        self.files
+            .write()
+            .unwrap()
+            .remove(&normalize(path.as_ref()))
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.files
+            .read()
+            .unwrap()
+            .keys()
+            .any(|file| file != path && file.starts_with(path))
+    }
+}
+
+impl FileSystem for MemoryFs {
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        let path = normalize(path);
+        if self.is_file(&path) || self.is_dir(&path) {
+            Ok(path)
+        } else {
+            Err(not_found(&path))
+        }
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let path = normalize(path);
+        self.files
+            .read()
+            .unwrap()
+            .get(&path)
+            .cloned()
+            .ok_or_else(|| not_found(&path))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let path = normalize(path);
+        if !self.is_dir(&path) {
+            return Err(not_found(&path));
+        }
+        let mut entries = self
+            .files
+            .read()
+            .unwrap()
+            .keys()
+            .filter_map(|file| file.strip_prefix(&path).ok()?.components().next())
+            .map(|child| path.join(child))
+            .collect::<Vec<_>>();
+        entries.dedup();
+        Ok(entries)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.files.read().unwrap().contains_key(&normalize(path))
+    }
+
+    fn len(&self, path: &Path) -> io::Result<u64> {
+        let path = normalize(path);
+        self.files
+            .read()
+            .unwrap()
+            .get(&path)
+            .map(|contents| contents.len() as u64)
+            .ok_or_else(|| not_found(&path))
+    }
+}
+
+/// Makes `path` absolute and resolves `.` and `..` components without accessing the disk.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::Normal(name) => normalized.push(name),
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+        }
+    }
+    normalized
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("No such file or directory: {}", path.display()),
+    )
+}
@@ -0,0 +1,99 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use crate::token::Span;
+use crate::value::IncludeKind;
+
+/// The files that a loaded document depends on, and the directives that connect them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DependencyGraph {
+    /// Canonical paths of all files, in the order they were first loaded. The first file is the
+    /// loaded document itself.
+    pub nodes: Vec<PathBuf>,
+    /// The directives that loaded or read a file, ordered by the file that contains them.
+    pub edges: Vec<Edge>,
+}
+
+/// A directive in the file `from` that loaded or read the file `to`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Edge {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    pub kind: EdgeKind,
+    /// Location of the directive in `from`. `None` for structs that were not parsed from a file.
+    pub span: Option<Span>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EdgeKind {
+    Include(IncludeKind),
+    Prototype,
+    /// A custom directive, by name.
+    Directive(String),
+}
+
+impl EdgeKind {
+    /// The name of the directive, without the leading `#`.
+    pub fn name(&self) -> &str {
+        match self {
+            EdgeKind::Include(kind) => kind.directive_name(),
+            EdgeKind::Prototype => "prototype",
+            EdgeKind::Directive(name) => name,
+        }
+    }
+}
+
+impl DependencyGraph {
+    /// Formats the graph as a Makefile rule that makes `target` depend on every file, like the
+    /// `.d` files written by `gcc -MD -MP`. Every file except the document itself also gets an
+    /// empty rule, so that deleting a file does not break the build.
+    pub fn makefile(&self, target: &str) -> String {
+        let mut out = escape_make(target);
+        out.push(':');
+        for node in &self.nodes {
+            out.push_str(" \\\n  ");
+            out.push_str(&escape_make(&node.to_string_lossy()));
+        }
+        out.push('\n');
+        for node in self.nodes.iter().skip(1) {
+            writeln!(out, "\n{}:", escape_make(&node.to_string_lossy())).unwrap();
+        }
+        out
+    }
+
+    /// Formats the graph in the DOT language of Graphviz, with an edge for every directive
+    /// labeled with the directive name.
+    pub fn dot(&self) -> String {
+        let mut out = "digraph dependencies {\n".to_string();
+        for node in &self.nodes {
+            writeln!(out, "    {};", quote_dot(node)).unwrap();
+        }
+        for edge in &self.edges {
+            writeln!(
+                out,
+                "    {} -> {} [label=\"#{}\"];",
+                quote_dot(&edge.from),
+                quote_dot(&edge.to),
+                edge.kind.name().replace('\\', "\\\\").replace('"', "\\\"")
+            )
+            .unwrap();
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+fn escape_make(path: &str) -> String {
+    path.replace('$', "$$")
+        .replace(' ', "\\ ")
+        .replace('#', "\\#")
+}
+
+fn quote_dot(path: &Path) -> String {
+    format!(
+        "\"{}\"",
+        path.to_string_lossy()
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+    )
+}
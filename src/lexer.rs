@@ -89,6 +89,7 @@ impl<'a> Lexer<'a> {
                 ']' => TokenKind::RightBracket,
                 ':' => TokenKind::Colon,
                 ',' => TokenKind::Comma,
+                '.' => TokenKind::Dot,
                 '#' => TokenKind::Hash,
                 '/' if self.consume('/') => {
                     while self.peek().is_some() && self.peek() != Some('\n') {
@@ -104,8 +105,22 @@ impl<'a> Lexer<'a> {
                     self.advance();
                     TokenKind::Comment
                 }
+                '/' => TokenKind::Slash,
+                '+' => TokenKind::Plus,
+                '*' => TokenKind::Star,
+                '%' => TokenKind::Percent,
+                '!' if self.consume('=') => TokenKind::BangEqual,
+                '!' => TokenKind::Bang,
+                '=' if self.consume('=') => TokenKind::EqualEqual,
+                '<' if self.consume('=') => TokenKind::LessEqual,
+                '<' => TokenKind::Less,
+                '>' if self.consume('=') => TokenKind::GreaterEqual,
+                '>' => TokenKind::Greater,
+                '&' if self.consume('&') => TokenKind::AndAnd,
+                '|' if self.consume('|') => TokenKind::OrOr,
                 ' ' | '\r' | '\t' => TokenKind::Whitespace,
                 '\n' => TokenKind::Newline,
+                '-' if self.follows_operand() || !self.starts_number() => TokenKind::Minus,
                 '0'..='9' | '-' => {
                     self.number();
                     TokenKind::Number
@@ -142,8 +157,19 @@ impl<'a> Lexer<'a> {
     }
 
     fn number(&mut self) {
-        while let Some('0'..='9' | '-' | '+' | '.' | 'e') = self.peek() {
-            self.advance();
+        while let Some(c) = self.peek() {
+            match c {
+                '0'..='9' | '.' => {
+                    self.advance();
+                }
+                'e' | 'E' => {
+                    self.advance();
+                    if let Some('+' | '-') = self.peek() {
+                        self.advance();
+                    }
+                }
+                _ => break,
+            }
         }
     }
 
@@ -178,6 +204,30 @@ impl<'a> Lexer<'a> {
         }
     }
 
+    /// Whether the next characters start a number after a `-`, such as `1` or `.5`.
+    fn starts_number(&mut self) -> bool {
+        match self.peek() {
+            Some('0'..='9') => true,
+            Some('.') => matches!(self.source.clone().nth(1), Some('0'..='9')),
+            _ => false,
+        }
+    }
+
+    /// Whether the previous token ends an operand, so that a `-` is a subtraction as in `4 -1`.
+    fn follows_operand(&self) -> bool {
+        matches!(
+            self.tokens.last().map(|token| token.kind),
+            Some(
+                TokenKind::Number
+                    | TokenKind::String
+                    | TokenKind::Ident
+                    | TokenKind::True
+                    | TokenKind::False
+                    | TokenKind::RightParen
+            )
+        )
+    }
+
     fn peek(&mut self) -> Option<char> {
         self.source.peek().cloned()
     }
@@ -1,36 +1,101 @@
+#[cfg(feature = "async")]
+pub mod async_fs;
+pub mod convert;
+pub mod directive;
+pub mod expr;
+pub mod fs;
+pub mod graph;
 pub mod lexer;
+mod loader;
 mod parser;
+pub mod provenance;
 #[cfg(test)]
 mod tests;
 pub mod token;
 pub mod value;
+pub mod watch;
 
-use std::collections::HashMap;
 use std::ops::Range;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
+use std::sync::Arc;
 
 use ariadne::Report;
+#[cfg(feature = "async")]
+pub use async_fs::{AsyncFileSystem, FsFuture};
+pub use convert::{FromValue, TypeError};
+pub use directive::{Diagnostic, Directive, DirectiveContext};
+pub use fs::{FileSystem, MemoryFs, StdFs};
+pub use graph::{DependencyGraph, Edge, EdgeKind};
+use indexmap::IndexMap;
 use lexer::Lexer;
-pub use value::{Float, Map, Number, Struct, Value};
+pub use loader::Loader;
+pub use provenance::{Origin, OriginKind, Provenance};
+pub use value::{
+    Argument, Cfg, CfgBranch, CfgPredicate, Def, DirectiveCall, Env, EnvType, Float, If, Include,
+    IncludeKind, IntoRonError, Map, Number, Patch, PatchOp, Ref, Scope, Struct, TupleNames, Value,
+};
+pub use watch::{WatchEvent, Watcher};
 
 pub struct Parse {
     pub value: value::Value,
     pub errors: Vec<Report<(String, Range<usize>)>>,
+    /// Diagnostics that do not make the document invalid, such as shadowed definitions and
+    /// unresolved directives.
+    pub warnings: Vec<Report<(String, Range<usize>)>>,
     pub sources: Vec<(String, String)>,
+    /// Canonical paths of all files that were read to produce `value`, in the order they were
+    /// first loaded.
+    pub dependencies: Vec<PathBuf>,
+    /// The directives through which the files in `dependencies` were loaded.
+    pub graph: DependencyGraph,
+    /// Where each value was declared, if [`LoadOptions::provenance`] is set.
+    pub provenance: Provenance,
 }
 
 impl Parse {
     pub fn emit(&self) {
-        for error in &self.errors {
+        for error in self.errors.iter().chain(&self.warnings) {
             let cache = ariadne::sources(self.sources.clone());
             error.eprint(cache).unwrap();
         }
     }
+
+    /// Returns the line on which `origin` starts, counting from 1, if the source of its file is
+    /// in `sources`.
+    pub fn line_of(&self, origin: &Origin) -> Option<usize> {
+        let file = origin.file.to_str()?;
+        let (_, source) = self.sources.iter().find(|(name, _)| name == file)?;
+        let before = source.chars().take(origin.span.start);
+        Some(before.filter(|&c| c == '\n').count() + 1)
+    }
+}
+
+/// Options that control how [`parse_with_options`] interprets the source.
+#[derive(Clone, Debug, Default)]
+pub struct ParseOptions {
+    /// Whether `${name}` placeholders in strings are parsed as interpolations. When disabled,
+    /// placeholders are kept verbatim. See [`LoadOptions::interpolate`] for files read by
+    /// [`load`].
+    pub interpolate: bool,
+    /// Names of custom directives that are accepted in addition to the builtin ones. They are
+    /// parsed into [`Value::Directive`].
+    pub directives: Vec<String>,
+    /// Whether unknown directives are parsed into [`Value::Directive`] instead of being
+    /// reported as errors, so that they can be handled by a separate pass.
+    pub keep_unknown_directives: bool,
 }
 
 pub fn parse(source: &str, source_name: Option<&str>) -> Result<value::Value, Parse> {
+    parse_with_options(source, source_name, &ParseOptions::default())
+}
+
+pub fn parse_with_options(
+    source: &str,
+    source_name: Option<&str>,
+    options: &ParseOptions,
+) -> Result<value::Value, Parse> {
     let source_name = source_name.unwrap_or("<unknown>");
-    let parser = parser::Parser::new(source, source_name);
+    let parser = parser::Parser::new(source, source_name).with_options(options);
     let _tokens = parser.tokens.clone();
     let (val, errors) = parser.parse();
 
@@ -43,109 +108,191 @@ pub fn parse(source: &str, source_name: Option<&str>) -> Result<value::Value, Pa
                 .into_iter()
                 .map(|report_builder| report_builder.finish())
                 .collect(),
+            warnings: vec![],
             sources: vec![(source_name.to_string(), source.to_string())],
+            dependencies: vec![],
+            graph: DependencyGraph::default(),
+            provenance: Provenance::default(),
         })
     }
 }
 
-fn _load<P: AsRef<std::path::Path>>(path: P) -> Result<Parse, std::io::Error> {
-    let source = std::fs::read_to_string(path.as_ref())?;
-    let source_name = path.as_ref().to_str().unwrap();
-    match parse(&source, Some(source_name)) {
-        Err(err) => Ok(err),
-        Ok(val) => Ok(Parse {
-            value: val,
-            errors: vec![],
-            sources: vec![(source_name.to_string(), source.to_string())],
-        }),
-    }
+/// Options that control how [`load_with_options`] resolves directives.
+#[derive(Clone, Debug)]
+pub struct LoadOptions {
+    /// Whether `#env` directives may read environment variables. Disable this when loading
+    /// untrusted input.
+    pub allow_env: bool,
+    /// The active build profile, such as `"debug"` or `"release"`. It is available as `profile`
+    /// in expressions, which is `None` if no profile is set, and matches `#cfg(<profile>)`.
+    pub profile: Option<String>,
+    /// Additional names that match in `#cfg(...)` and `cfg(...)`.
+    pub flags: Vec<String>,
+    /// Custom directives by name, usually registered with [`LoadOptions::directive`].
+    pub directives: IndexMap<String, Arc<dyn Directive>>,
+    /// Whether directives that are neither builtin nor registered are kept as
+    /// [`Value::Directive`]. Each one is reported as a warning once loading is complete.
+    pub keep_unknown_directives: bool,
+    /// Directories in which relative paths in directives are looked up, in order, if they do
+    /// not exist relative to the file that contains the directive.
+    pub search_paths: Vec<PathBuf>,
+    /// Named roots by name. A path such as `@core/creatures/goblin.ron` is relative to the root
+    /// named `core`.
+    pub roots: IndexMap<String, PathBuf>,
+    /// Restrictions on the files that directives can access, for loading untrusted input.
+    pub sandbox: Option<Sandbox>,
+    /// Whether to record where each value was declared, in [`Parse::provenance`].
+    pub provenance: bool,
+    /// Number of threads that read and parse files concurrently, see [`Loader`]. `1` loads
+    /// everything on the calling thread, `0` uses one thread per available core.
+    pub threads: usize,
+    /// Whether `${name}` placeholders in strings are resolved against fields, definitions and
+    /// `${env:NAME}` variables, like [`ParseOptions::interpolate`]. Disabled by default, so that
+    /// existing documents that contain a literal `${` load unchanged.
+    pub interpolate: bool,
 }
 
-struct Loader {
-    errors: Vec<Report<(String, Range<usize>)>>,
-    sources: Vec<(String, String)>,
-    resolve_stack: Vec<PathBuf>,
-    cache: HashMap<PathBuf, Option<Value>>,
+impl LoadOptions {
+    /// Registers a custom directive that is used as `#name(...)`.
+    pub fn directive<D: Directive + 'static>(mut self, name: &str, directive: D) -> Self {
+        self.directives
+            .insert(name.to_string(), Arc::new(directive));
+        self
+    }
+
+    /// Adds a search path, see [`LoadOptions::search_paths`].
+    pub fn search_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.search_paths.push(path.into());
+        self
+    }
+
+    /// Adds a named root that is used as `@name/...`, see [`LoadOptions::roots`].
+    pub fn root<P: Into<PathBuf>>(mut self, name: &str, path: P) -> Self {
+        self.roots.insert(name.to_string(), path.into());
+        self
+    }
+
+    /// Sets the number of threads, see [`LoadOptions::threads`].
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Restricts the files that directives can access, see [`Sandbox`].
+    pub fn sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.sandbox = Some(sandbox);
+        self
+    }
 }
 
-impl Loader {
-    fn load(&mut self, path: &Path) -> Result<Value, std::io::Error> {
-        let path = path.canonicalize()?;
-        match self.cache.get(&path) {
-            Some(None) => todo!("CYCLE"),
-            Some(Some(val)) => return Ok(val.clone()),
-            None => {}
+impl Default for LoadOptions {
+    fn default() -> Self {
+        LoadOptions {
+            allow_env: true,
+            profile: None,
+            flags: vec![],
+            directives: IndexMap::new(),
+            keep_unknown_directives: false,
+            search_paths: vec![],
+            roots: IndexMap::new(),
+            sandbox: None,
+            provenance: false,
+            threads: 1,
+            interpolate: false,
         }
-        self.resolve_stack.push(path.clone());
-        let mut parse = _load(&path)?;
-        self.errors.append(&mut parse.errors);
-        self.sources.append(&mut parse.sources);
-        self.resolve(&mut parse.value, &path)?;
-        self.resolve_stack.pop();
-        self.cache.insert(path, Some(parse.value.clone()));
-        Ok(parse.value)
     }
+}
 
-    fn resolve(&mut self, value: &mut Value, origin: &Path) -> Result<(), std::io::Error> {
-        match value {
-            Value::Include(path) => *value = self.load(&origin.parent().unwrap().join(path))?,
-            Value::Struct(Struct {
-                name: _,
-                prototype,
-                fields,
-            }) => {
-                if let Some(path) = prototype.as_ref() {
-                    let include_path = origin.parent().unwrap().join(path);
-                    let include_value = self.load(&include_path)?;
-                    match include_value {
-                        Value::Struct(include_struct) => {
-                            for (name, field) in include_struct.fields.into_iter() {
-                                if !fields.contains_key(&name) {
-                                    fields.insert(name, field.clone());
-                                }
-                            }
-                        }
-                        _ => eprintln!("MUST BE STRUCT {:?}", include_value),
-                    }
-                    *prototype = None;
-                }
-                for field in fields.values_mut() {
-                    self.resolve(field, origin)?;
-                }
-            }
-            Value::Map(items) => {
-                for value in items.0.values_mut() {
-                    self.resolve(value, origin)?;
-                }
-            }
-            Value::Seq(values) => {
-                for value in values {
-                    self.resolve(value, origin)?;
-                }
-            }
-            Value::Bool(_)
-            | Value::Char(_)
-            | Value::Number(_)
-            | Value::Option(_)
-            | Value::String(_)
-            | Value::Tuple(_, _)
-            | Value::Unit => {}
+/// Restrictions on the files that directives such as `#include` can access.
+///
+/// Every path is resolved to its canonical form before it is checked, so neither `..` nor a
+/// symbolic link can be used to escape the root. Violations are reported as errors and the
+/// directive resolves to `()`. The file passed to [`load`] itself is trusted, but counts towards
+/// the limits.
+#[derive(Clone, Debug)]
+pub struct Sandbox {
+    /// The directory that all loaded files must be inside of.
+    pub root: PathBuf,
+    /// Whether paths in directives that go through a symbolic link are rejected, even if the
+    /// link points inside the root.
+    pub reject_symlinks: bool,
+    /// The maximum number of files that can be loaded.
+    pub max_files: Option<usize>,
+    /// The maximum total size of all loaded files in bytes.
+    pub max_bytes: Option<u64>,
+}
+
+impl Sandbox {
+    /// A sandbox that restricts files to `root`, without any limits.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Sandbox {
+        Sandbox {
+            root: root.into(),
+            reject_symlinks: false,
+            max_files: None,
+            max_bytes: None,
         }
-        Ok(())
+    }
+
+    pub fn reject_symlinks(mut self) -> Self {
+        self.reject_symlinks = true;
+        self
+    }
+
+    pub fn max_files(mut self, max_files: usize) -> Self {
+        self.max_files = Some(max_files);
+        self
+    }
+
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
     }
 }
 
 pub fn load<P: AsRef<std::path::Path>>(path: P) -> Result<Parse, std::io::Error> {
-    let mut loader = Loader {
-        errors: vec![],
-        sources: vec![],
-        resolve_stack: vec![],
-        cache: HashMap::new(),
-    };
-    let value = loader.load(path.as_ref())?;
-    Ok(Parse {
-        value,
-        errors: loader.errors,
-        sources: loader.sources,
-    })
+    load_with_options(path, &LoadOptions::default())
+}
+
+pub fn load_with_options<P: AsRef<std::path::Path>>(
+    path: P,
+    options: &LoadOptions,
+) -> Result<Parse, std::io::Error> {
+    Loader::new(options.clone()).load(path)
+}
+
+/// Loads `source` as if it were the contents of the file at `virtual_path`, so that directives
+/// such as `#include` are resolved relative to it.
+///
+/// See [`Loader::load_str`].
+pub fn load_str<P: AsRef<std::path::Path>>(
+    source: &str,
+    virtual_path: P,
+) -> Result<Parse, std::io::Error> {
+    load_str_with_options(source, virtual_path, &LoadOptions::default())
+}
+
+pub fn load_str_with_options<P: AsRef<std::path::Path>>(
+    source: &str,
+    virtual_path: P,
+    options: &LoadOptions,
+) -> Result<Parse, std::io::Error> {
+    Loader::new(options.clone()).load_str(source, virtual_path)
+}
+
+/// Loads `base` and applies the patches in each of the `overlays` to it, in order.
+///
+/// See [`Loader::load_with_overlays`] for the format of overlay files.
+pub fn load_with_overlays<P: AsRef<std::path::Path>, O: AsRef<std::path::Path>>(
+    base: P,
+    overlays: &[O],
+) -> Result<Parse, std::io::Error> {
+    Loader::new(LoadOptions::default()).load_with_overlays(base, overlays)
+}
+
+pub fn load_with_overlays_and_options<P: AsRef<std::path::Path>, O: AsRef<std::path::Path>>(
+    base: P,
+    overlays: &[O],
+    options: &LoadOptions,
+) -> Result<Parse, std::io::Error> {
+    Loader::new(options.clone()).load_with_overlays(base, overlays)
 }
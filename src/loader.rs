@@ -0,0 +1,2295 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::SystemTime;
+
+use ariadne::{Label, Report, ReportBuilder, ReportKind};
+use indexmap::{IndexMap, IndexSet};
+
+#[cfg(feature = "async")]
+use crate::async_fs::{AsyncFileSystem, Snapshot};
+use crate::directive::DirectiveContext;
+use crate::expr::{self, Expr};
+use crate::fs::{FileSystem, StdFs};
+use crate::graph::{DependencyGraph, Edge, EdgeKind};
+use crate::parser::Parser;
+use crate::provenance::{self, Origin, OriginKind, Provenance};
+use crate::token::Span;
+use crate::value::{
+    Cfg, Def, DirectiveCall, Env, EnvType, If, Include, IncludeKind, Map, Number, Patch, PatchOp,
+    Ref, Struct, Value,
+};
+use crate::{LoadOptions, Parse, ParseOptions};
+
+type RB = ReportBuilder<(String, Range<usize>)>;
+
+/// Loads RON files and resolves their directives.
+///
+/// A loader reads files through a [`FileSystem`], which is the local file system for
+/// [`Loader::new`].
+///
+/// Resolved files are cached, so loading several documents that include the same files with one
+/// loader parses those files only once. Files that produced diagnostics are not cached across
+/// loads, so their diagnostics are reported by every load. The cache is not updated when files
+/// change on disk, use [`Loader::refresh`] or [`Loader::invalidate`] for that. Environment
+/// variables read by `#env` are also captured when a file is first loaded.
+///
+/// With [`LoadOptions::threads`], files are read and parsed on several threads before their
+/// directives are resolved. Resolution itself always happens on the calling thread, so the result
+/// and the order of diagnostics are the same for any number of threads.
+pub struct Loader {
+    pub(crate) options: LoadOptions,
+    fs: Arc<dyn FileSystem>,
+    pub(crate) errors: Vec<Report<(String, Range<usize>)>>,
+    pub(crate) warnings: Vec<Report<(String, Range<usize>)>>,
+    sources: Vec<(String, String)>,
+    /// Files that are being resolved, innermost file last.
+    resolve_stack: Vec<Frame>,
+    cache: HashMap<PathBuf, Entry>,
+    /// The state of every file that was loaded or read when it was last read, and of the
+    /// directories and missing paths that were searched.
+    stamps: HashMap<PathBuf, Stamp>,
+    dependencies: IndexSet<PathBuf>,
+    /// Directories that were listed and missing paths that were searched by the current load.
+    /// Creating a file in them can change the result.
+    pub(crate) watched: IndexSet<PathBuf>,
+    /// Definitions visible at the current point of resolution, innermost scope last.
+    scopes: Vec<IndexMap<String, (Value, Span)>>,
+    /// The overlay that is being loaded, the only file in which `#set`, `#remove` and `#append`
+    /// are allowed.
+    overlay: Option<PathBuf>,
+    /// Unknown directives kept because of [`LoadOptions::keep_unknown_directives`].
+    unresolved: IndexSet<(PathBuf, String, Span)>,
+    /// Total size of the files in `dependencies`, limited by [`crate::Sandbox::max_bytes`].
+    bytes_read: u64,
+    /// Origins of the values of the loaded document, see [`LoadOptions::provenance`].
+    provenance: Provenance,
+    /// Files that were parsed ahead of resolution by [`Loader::prefetch`].
+    prefetched: HashMap<PathBuf, Parsed>,
+}
+
+impl Loader {
+    pub fn new(options: LoadOptions) -> Loader {
+        Loader::with_file_system(options, StdFs)
+    }
+
+    pub fn with_file_system<F: FileSystem + 'static>(options: LoadOptions, fs: F) -> Loader {
+        Loader {
+            options,
+            fs: Arc::new(fs),
+            errors: vec![],
+            warnings: vec![],
+            sources: vec![],
+            resolve_stack: vec![],
+            cache: HashMap::new(),
+            stamps: HashMap::new(),
+            dependencies: IndexSet::new(),
+            watched: IndexSet::new(),
+            scopes: vec![],
+            overlay: None,
+            unresolved: IndexSet::new(),
+            bytes_read: 0,
+            provenance: Provenance::default(),
+            prefetched: HashMap::new(),
+        }
+    }
+
+    pub fn options(&self) -> &LoadOptions {
+        &self.options
+    }
+
+    /// Loads the file at `path` and resolves all directives in it.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<Parse, std::io::Error> {
+        self.prefetch(&[path.as_ref()]);
+        let result = self.load_entry(path.as_ref());
+        self.prefetched.clear();
+        result
+    }
+
+    /// Loads each of `paths` like [`Loader::load`], sharing the cache between them.
+    ///
+    /// With [`LoadOptions::threads`], the files of all documents are parsed concurrently before
+    /// the first document is resolved.
+    pub fn load_all<P: AsRef<Path>>(&mut self, paths: &[P]) -> Vec<Result<Parse, std::io::Error>> {
+        let entries = paths.iter().map(AsRef::as_ref).collect::<Vec<_>>();
+        self.prefetch(&entries);
+        let results = entries.iter().map(|path| self.load_entry(path)).collect();
+        self.prefetched.clear();
+        results
+    }
+
+    /// Loads `source` as if it were the contents of the file at `path`, and resolves all
+    /// directives in it relative to `path`.
+    ///
+    /// The file at `path` does not need to exist, and if it does, `source` is used instead of its
+    /// contents, e.g. to check an unsaved buffer in an editor. The cache of the loader only ever
+    /// holds the contents of the file on disk.
+    pub fn load_str<P: AsRef<Path>>(
+        &mut self,
+        source: &str,
+        path: P,
+    ) -> Result<Parse, std::io::Error> {
+        let path = self.cache_key(path.as_ref());
+        let parsed = self.parse_source(source.to_string(), &path);
+        let mut dependencies = vec![];
+        self.static_dependencies(&parsed.value, &path, &mut dependencies);
+        self.prefetch(
+            &dependencies
+                .iter()
+                .map(PathBuf::as_path)
+                .collect::<Vec<_>>(),
+        );
+        self.reset();
+        self.cache.remove(&path);
+        self.prefetched.insert(path.clone(), parsed);
+        let result = match self.load_canonical(path.clone()) {
+            Ok(value) => {
+                if let Some(entry) = self.cache.get(&path) {
+                    self.provenance = entry.provenance.clone();
+                }
+                Ok(self.finish(value))
+            }
+            Err(err) => Err(err),
+        };
+        self.prefetched.clear();
+        self.cache.remove(&path);
+        self.stamps.remove(&path);
+        result
+    }
+
+    /// Like [`Loader::load`], but reads the files from `fs` instead of the file system of the
+    /// loader, without blocking.
+    ///
+    /// Files are fetched concurrently, one level of includes at a time: the document is resolved
+    /// with the files fetched so far, and all files that were missing are fetched together before
+    /// it is resolved again. Directives themselves are still resolved synchronously, so custom
+    /// directives should not block. [`Loader::is_stale`] and [`Loader::refresh`] keep using the
+    /// file system of the loader.
+    #[cfg(feature = "async")]
+    pub async fn load_async<P: AsRef<Path>>(
+        &mut self,
+        fs: &dyn AsyncFileSystem,
+        path: P,
+    ) -> Result<Parse, std::io::Error> {
+        let snapshot = Arc::new(Snapshot::default());
+        // The file system is restored even if the future is dropped before it completes.
+        let guard = Restore {
+            fs: Some(std::mem::replace(&mut self.fs, snapshot.clone())),
+            loader: self,
+        };
+        let mut pending = vec![path.as_ref().to_path_buf()];
+        loop {
+            snapshot.fetch(fs, pending).await;
+            let cached = guard.loader.cache.keys().cloned().collect::<HashSet<_>>();
+            let result = guard.loader.load_entry(path.as_ref());
+            pending = snapshot.take_misses();
+            if pending.is_empty() {
+                return result;
+            }
+            // Files resolved without all of their dependencies, such as the matches of an
+            // `#include_glob`, must not be reused by the next pass.
+            guard.loader.cache.retain(|path, _| cached.contains(path));
+        }
+    }
+
+    fn load_entry(&mut self, path: &Path) -> Result<Parse, std::io::Error> {
+        self.reset();
+        let value = self.load_file(path)?;
+        self.provenance = self.provenance_of(path);
+        Ok(self.finish(value))
+    }
+
+    /// Whether the cached result for `path` is out of date, because `path` or any file it
+    /// depends on has changed since it was read. Files that are not cached are always stale.
+    ///
+    /// A file has changed if its modification time differs and its contents hash to a different
+    /// value, so files that are only touched stay fresh.
+    pub fn is_stale<P: AsRef<Path>>(&self, path: P) -> bool {
+        let path = self.cache_key(path.as_ref());
+        if !self.stamps.contains_key(&path) {
+            return true;
+        }
+        let mut stack = vec![path];
+        let mut visited = IndexSet::new();
+        while let Some(path) = stack.pop() {
+            if !visited.insert(path.clone()) {
+                continue;
+            }
+            match self.stamps.get(&path) {
+                Some(stamp) if !self.has_changed(&path, stamp) => {}
+                _ => return true,
+            }
+            if let Some(entry) = self.cache.get(&path) {
+                stack.extend(entry.edges.iter().map(|edge| edge.to.clone()));
+                stack.extend(entry.watched.iter().cloned());
+            }
+        }
+        false
+    }
+
+    /// Removes `path` from the cache, together with every cached file that depends on it.
+    pub fn invalidate<P: AsRef<Path>>(&mut self, path: P) {
+        let mut invalid = IndexSet::new();
+        invalid.insert(self.cache_key(path.as_ref()));
+        loop {
+            let dependents = self
+                .cache
+                .iter()
+                .filter(|(path, entry)| {
+                    !invalid.contains(*path)
+                        && (entry.edges.iter().any(|edge| invalid.contains(&edge.to))
+                            || entry.watched.iter().any(|path| invalid.contains(path)))
+                })
+                .map(|(path, _)| path.clone())
+                .collect::<Vec<_>>();
+            if dependents.is_empty() {
+                break;
+            }
+            invalid.extend(dependents);
+        }
+        for path in invalid {
+            self.cache.remove(&path);
+            self.stamps.remove(&path);
+        }
+    }
+
+    /// Invalidates every cached file that has changed since it was read, and returns the paths
+    /// of the changed files.
+    pub fn refresh(&mut self) -> Vec<PathBuf> {
+        let mut changed = self
+            .stamps
+            .iter()
+            .filter(|(path, stamp)| self.has_changed(path, stamp))
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        changed.sort();
+        for path in &changed {
+            self.invalidate(path);
+        }
+        changed
+    }
+
+    /// Removes all files from the cache.
+    pub fn clear_cache(&mut self) {
+        self.cache.clear();
+        self.stamps.clear();
+    }
+
+    fn has_changed(&self, path: &Path, stamp: &Stamp) -> bool {
+        match *stamp {
+            Stamp::File {
+                modified,
+                hash: old,
+            } => {
+                if let (Some(modified), Ok(now)) = (modified, self.fs.modified(path)) {
+                    if modified == now {
+                        return false;
+                    }
+                }
+                match self.fs.read(path) {
+                    Ok(contents) => hash(&contents) != old,
+                    Err(_) => true,
+                }
+            }
+            Stamp::Dir { hash: old } => match self.fs.read_dir(path) {
+                Ok(entries) => hash_listing(&entries) != old,
+                Err(_) => true,
+            },
+            Stamp::Missing => self.fs.canonicalize(path).is_ok(),
+        }
+    }
+
+    /// The path under which `path` is cached. Deleted files can no longer be canonicalized, so
+    /// their parent directory is canonicalized instead.
+    fn cache_key(&self, path: &Path) -> PathBuf {
+        self.fs
+            .canonicalize(path)
+            .unwrap_or_else(|_| match (path.parent(), path.file_name()) {
+                (Some(dir), Some(name)) => match self.fs.canonicalize(dir) {
+                    Ok(dir) => dir.join(name),
+                    Err(_) => path.to_path_buf(),
+                },
+                _ => path.to_path_buf(),
+            })
+    }
+
+    /// Records that the file being resolved depends on the file `to` through a directive.
+    pub(crate) fn link(&mut self, to: &Path, kind: EdgeKind, span: Option<Span>) {
+        if let Some(frame) = self.resolve_stack.last_mut() {
+            frame.edges.push(Edge {
+                from: frame.path.clone(),
+                to: to.to_path_buf(),
+                kind,
+                span,
+            });
+        }
+    }
+
+    /// Records that `path` was read with the given `contents`.
+    fn record(&mut self, path: &Path, contents: &[u8]) {
+        if self.dependencies.insert(path.to_path_buf()) {
+            self.bytes_read += contents.len() as u64;
+        }
+        self.stamps.insert(
+            path.to_path_buf(),
+            Stamp::File {
+                modified: self.fs.modified(path).ok(),
+                hash: hash(contents),
+            },
+        );
+    }
+
+    /// Records that the file being resolved depends on the listing of the directory at `path`, or
+    /// on `path` not existing.
+    fn watch(&mut self, path: &Path, stamp: Stamp) {
+        let path = self.cache_key(path);
+        self.stamps.insert(path.clone(), stamp);
+        self.watched.insert(path.clone());
+        if let Some(frame) = self.resolve_stack.last_mut() {
+            frame.watched.push(path);
+        }
+    }
+
+    /// Adds a file that was cached by a previous load, and the files it depends on, to the
+    /// current load.
+    fn replay(&mut self, path: &Path) {
+        if !self.dependencies.insert(path.to_path_buf()) {
+            return;
+        }
+        match self.cache.get(path) {
+            Some(entry) => {
+                self.bytes_read += entry.source.len() as u64;
+                self.sources
+                    .push((path.to_str().unwrap().to_string(), entry.source.clone()));
+                let warnings = entry
+                    .shadowed
+                    .iter()
+                    .map(|shadowed| self.shadowing_warning(path, shadowed))
+                    .collect::<Vec<_>>();
+                self.warnings.extend(warnings);
+                self.watched.extend(entry.watched.iter().cloned());
+                let dependencies = entry
+                    .edges
+                    .iter()
+                    .map(|edge| edge.to.clone())
+                    .collect::<Vec<_>>();
+                for dependency in dependencies {
+                    self.replay(&dependency);
+                }
+            }
+            None => {
+                self.bytes_read += self.fs.len(path).unwrap_or(0);
+            }
+        }
+    }
+
+    /// Whether replaying the cached file at `path` keeps the load within the limits of the
+    /// sandbox.
+    fn replay_within_limits(&self, path: &Path) -> bool {
+        let sandbox = match &self.options.sandbox {
+            Some(sandbox) => sandbox,
+            None => return true,
+        };
+        let mut files = HashSet::new();
+        let mut bytes = 0;
+        let mut pending = vec![path.to_path_buf()];
+        while let Some(path) = pending.pop() {
+            if self.dependencies.contains(&path) || !files.insert(path.clone()) {
+                continue;
+            }
+            match self.cache.get(&path) {
+                Some(entry) => {
+                    bytes += entry.source.len() as u64;
+                    pending.extend(entry.edges.iter().map(|edge| edge.to.clone()));
+                }
+                None => bytes += self.fs.len(&path).unwrap_or(0),
+            }
+        }
+        !matches!(sandbox.max_files, Some(max_files) if self.dependencies.len() + files.len() > max_files)
+            && !matches!(sandbox.max_bytes, Some(max_bytes) if self.bytes_read + bytes > max_bytes)
+    }
+
+    /// Loads `base` and applies the patches in each of the `overlays` to it, in order.
+    ///
+    /// An overlay file contains a list of patches such as
+    /// `[#set("server.port", 443), #remove("debug"), #append("spells", "Fireball")]`. Paths use
+    /// the same syntax as `#include` selections. Patches whose path does not exist in the base
+    /// document are reported as errors and skipped.
+    pub fn load_with_overlays<P: AsRef<Path>, O: AsRef<Path>>(
+        &mut self,
+        base: P,
+        overlays: &[O],
+    ) -> Result<Parse, std::io::Error> {
+        let mut entries = vec![base.as_ref()];
+        entries.extend(overlays.iter().map(AsRef::as_ref));
+        self.prefetch(&entries);
+        let result = self.resolve_overlays(base.as_ref(), overlays);
+        self.prefetched.clear();
+        result
+    }
+
+    fn resolve_overlays<O: AsRef<Path>>(
+        &mut self,
+        base: &Path,
+        overlays: &[O],
+    ) -> Result<Parse, std::io::Error> {
+        self.reset();
+        let mut value = self.load_file(base)?;
+        self.provenance = self.provenance_of(base);
+        let base_name = base.to_string_lossy();
+        for overlay in overlays {
+            let origin = self.fs.canonicalize(overlay.as_ref())?;
+            self.overlay = Some(origin.clone());
+            let patches = self.load_file(overlay.as_ref());
+            self.overlay = None;
+            match patches? {
+                Value::Seq(patches) => {
+                    for patch in patches {
+                        self.patch(&mut value, patch, &origin, &base_name);
+                    }
+                }
+                patch => self.patch(&mut value, patch, &origin, &base_name),
+            }
+        }
+        Ok(self.finish(value))
+    }
+
+    fn reset(&mut self) {
+        self.errors.clear();
+        self.warnings.clear();
+        self.sources.clear();
+        self.resolve_stack.clear();
+        self.cache.retain(|_, entry| entry.clean);
+        self.dependencies.clear();
+        self.watched.clear();
+        self.scopes.clear();
+        self.overlay = None;
+        self.unresolved.clear();
+        self.bytes_read = 0;
+        self.provenance = Provenance::default();
+    }
+
+    fn finish(&mut self, value: Value) -> Parse {
+        for (origin, name, span) in std::mem::take(&mut self.unresolved) {
+            self.warnings.push(
+                Report::build(
+                    ReportKind::Warning,
+                    origin.to_str().unwrap().to_string(),
+                    span.start,
+                )
+                .with_message(format!("Unresolved directive `#{}`", name))
+                .with_label(
+                    self.label(&origin, span)
+                        .with_message("Kept as `Value::Directive`"),
+                )
+                .finish(),
+            );
+        }
+        let dependencies = std::mem::take(&mut self.dependencies)
+            .into_iter()
+            .collect::<Vec<_>>();
+        let edges = dependencies
+            .iter()
+            .filter_map(|path| self.cache.get(path))
+            .flat_map(|entry| entry.edges.iter().cloned())
+            .collect();
+        Parse {
+            value,
+            errors: std::mem::take(&mut self.errors),
+            warnings: std::mem::take(&mut self.warnings),
+            sources: std::mem::take(&mut self.sources),
+            graph: DependencyGraph {
+                nodes: dependencies.clone(),
+                edges,
+            },
+            dependencies,
+            provenance: std::mem::take(&mut self.provenance),
+        }
+    }
+
+    pub(crate) fn load_file(&mut self, path: &Path) -> Result<Value, std::io::Error> {
+        let path = self.fs.canonicalize(path)?;
+        self.load_canonical(path)
+    }
+
+    fn load_canonical(&mut self, path: PathBuf) -> Result<Value, std::io::Error> {
+        if let Some(start) = self
+            .resolve_stack
+            .iter()
+            .position(|frame| frame.path == path)
+        {
+            self.report_cycle(start, &path);
+            return Ok(Value::Unit);
+        }
+        // A cached file that would exceed the limits of the sandbox is resolved again, so that
+        // the limit is reported at the directive that exceeds it.
+        if let Some(entry) = self
+            .cache
+            .get(&path)
+            .filter(|_| self.replay_within_limits(&path))
+        {
+            let value = entry.value.clone();
+            self.replay(&path);
+            return Ok(value);
+        }
+        let Parsed {
+            source,
+            mut value,
+            errors,
+            spans,
+        } = match self.prefetched.remove(&path) {
+            Some(parsed) => parsed,
+            None => self.parse_file(&path)?,
+        };
+        let source_name = path.to_str().unwrap();
+        self.record(&path, source.as_bytes());
+        let mut provenance = Provenance::default();
+        for (value_path, span) in spans {
+            let kind = OriginKind::Declared;
+            let file = path.clone();
+            provenance.insert(value_path, Origin { file, span, kind });
+        }
+        self.resolve_stack.push(Frame {
+            path: path.clone(),
+            edges: vec![],
+            provenance,
+            value_path: self.options.provenance.then(String::new),
+            shadowed: vec![],
+            watched: vec![],
+            warned: false,
+        });
+        let errors_before = self.errors.len();
+        self.errors
+            .extend(errors.into_iter().map(|report| report.finish()));
+        self.sources.push((source_name.to_string(), source.clone()));
+        // Definitions are scoped to the file they appear in.
+        let scopes = std::mem::take(&mut self.scopes);
+        self.resolve(&mut value, &path)?;
+        self.scopes = scopes;
+        let frame = self.resolve_stack.pop().unwrap();
+        let clean = self.errors.len() == errors_before
+            && !frame.warned
+            && !self.unresolved.iter().any(|(origin, _, _)| *origin == path)
+            && frame
+                .edges
+                .iter()
+                .all(|edge| !matches!(self.cache.get(&edge.to), Some(entry) if !entry.clean));
+        self.cache.insert(
+            path,
+            Entry {
+                value: value.clone(),
+                source,
+                edges: frame.edges,
+                provenance: frame.provenance,
+                shadowed: frame.shadowed,
+                watched: frame.watched,
+                clean,
+            },
+        );
+        Ok(value)
+    }
+
+    fn parse_file(&self, path: &Path) -> Result<Parsed, std::io::Error> {
+        let source = self.fs.read_to_string(path)?;
+        Ok(self.parse_source(source, path))
+    }
+
+    fn parse_source(&self, source: String, path: &Path) -> Parsed {
+        let options = ParseOptions {
+            interpolate: self.options.interpolate,
+            directives: self.options.directives.keys().cloned().collect(),
+            keep_unknown_directives: self.options.keep_unknown_directives,
+        };
+        let mut parser = Parser::new(&source, path.to_str().unwrap()).with_options(&options);
+        if self.options.provenance {
+            parser = parser.with_spans();
+        }
+        let (value, errors, spans) = parser.parse_with_spans();
+        Parsed {
+            source,
+            value,
+            errors,
+            spans,
+        }
+    }
+
+    /// Reports that `path` depends on itself through the files on the resolve stack from
+    /// `start` onwards.
+    fn report_cycle(&mut self, start: usize, path: &Path) {
+        let frame = self.resolve_stack.last().unwrap();
+        let origin = frame.path.clone();
+        // The directive that closes the cycle was linked just before loading `path`.
+        let span = frame
+            .edges
+            .last()
+            .filter(|edge| edge.to == path)
+            .and_then(|edge| edge.span)
+            .unwrap_or(Span { start: 0, end: 0 });
+        let cycle = self.resolve_stack[start..]
+            .iter()
+            .map(|frame| frame.path.as_path())
+            .chain(std::iter::once(path))
+            .map(|path| format!("`{}`", path.display()))
+            .collect::<Vec<_>>()
+            .join(" -> ");
+        self.errors.push(
+            self.error(&origin, span)
+                .with_message(format!("`{}` depends on itself", path.display()))
+                .with_label(self.label(&origin, span).with_message("Cycle closed here"))
+                .with_note(format!("Dependency cycle: {}", cycle))
+                .finish(),
+        );
+    }
+
+    /// Reads and parses the files at `paths`, and the files they load through `#include`,
+    /// `#include_glob`, `#include_dir` and `#prototype`, on [`LoadOptions::threads`] threads.
+    ///
+    /// Files that are already cached are skipped, and errors are ignored, so that they are
+    /// reported when the file is loaded. Files behind directives that are not taken, such as the
+    /// other branch of an `#if`, are parsed as well but never used.
+    fn prefetch(&mut self, paths: &[&Path]) {
+        let threads = match self.options.threads {
+            0 => std::thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            threads => threads,
+        };
+        if threads <= 1 {
+            return;
+        }
+        let mut seen = HashSet::new();
+        let pending = paths
+            .iter()
+            .filter_map(|path| self.fs.canonicalize(path).ok())
+            .filter(|path| !self.cache.contains_key(path) && seen.insert(path.clone()))
+            .collect();
+        let queue = Mutex::new(Queue {
+            pending,
+            seen,
+            busy: 0,
+            bytes_read: 0,
+            parsed: HashMap::new(),
+        });
+        let ready = Condvar::new();
+        let loader = &*self;
+        std::thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| loader.prefetch_worker(&queue, &ready));
+            }
+        });
+        self.prefetched = queue.into_inner().unwrap().parsed;
+    }
+
+    fn prefetch_worker(&self, queue: &Mutex<Queue>, ready: &Condvar) {
+        loop {
+            let (path, bytes_read) = {
+                let mut queue = queue.lock().unwrap();
+                loop {
+                    if let Some(path) = queue.pending.pop() {
+                        queue.busy += 1;
+                        break (path, queue.bytes_read);
+                    }
+                    if queue.busy == 0 {
+                        return;
+                    }
+                    queue = ready.wait(queue).unwrap();
+                }
+            };
+            // Files that do not fit into the size limit of the sandbox are not read at all.
+            let max_bytes = self.options.sandbox.as_ref().and_then(|s| s.max_bytes);
+            let fits = match max_bytes {
+                Some(max_bytes) => {
+                    matches!(self.fs.len(&path), Ok(len) if bytes_read + len <= max_bytes)
+                }
+                None => true,
+            };
+            let parsed = fits.then(|| self.parse_file(&path).ok()).flatten();
+            let mut dependencies = vec![];
+            if let Some(parsed) = &parsed {
+                self.static_dependencies(&parsed.value, &path, &mut dependencies);
+            }
+            let mut queue = queue.lock().unwrap();
+            if let Some(parsed) = parsed {
+                queue.bytes_read += parsed.source.len() as u64;
+                queue.parsed.insert(path, parsed);
+            }
+            for dependency in dependencies {
+                if !self.within_limits(queue.seen.len(), queue.bytes_read) {
+                    break;
+                }
+                if !self.cache.contains_key(&dependency) && queue.seen.insert(dependency.clone()) {
+                    queue.pending.push(dependency);
+                }
+            }
+            queue.busy -= 1;
+            ready.notify_all();
+        }
+    }
+
+    /// Whether `files` files with a total size of `bytes` are within the limits of the sandbox.
+    fn within_limits(&self, files: usize, bytes: u64) -> bool {
+        match &self.options.sandbox {
+            Some(sandbox) => {
+                !matches!(sandbox.max_files, Some(max_files) if files >= max_files)
+                    && !matches!(sandbox.max_bytes, Some(max_bytes) if bytes >= max_bytes)
+            }
+            None => true,
+        }
+    }
+
+    /// Collects the canonical paths of the files that resolving `value` would load, as far as
+    /// they are known without resolving it.
+    fn static_dependencies(&self, value: &Value, origin: &Path, out: &mut Vec<PathBuf>) {
+        match value {
+            Value::Include(include) => match include.kind {
+                IncludeKind::File => out.extend(self.find(&include.path, origin).ok()),
+                IncludeKind::Glob | IncludeKind::Dir => {
+                    let (dir, pattern) =
+                        include.path.rsplit_once('/').unwrap_or(("", &include.path));
+                    let dir = if dir.is_empty() { "." } else { dir };
+                    if let Ok(dir) = self.find(dir, origin) {
+                        let matches = self
+                            .fs
+                            .read_dir(&dir)
+                            .and_then(|entries| self.glob(entries, pattern, origin))
+                            .unwrap_or_default();
+                        out.extend(
+                            matches
+                                .iter()
+                                .filter_map(|path| self.find_match(&dir, path).ok()),
+                        );
+                    }
+                }
+                IncludeKind::Str | IncludeKind::Bytes => {}
+            },
+            Value::Struct(Struct {
+                prototype, fields, ..
+            }) => {
+                if let Some(prototype) = prototype {
+                    out.extend(self.find(prototype, origin).ok());
+                }
+                for field in fields.values() {
+                    self.static_dependencies(field, origin, out);
+                }
+            }
+            Value::Map(map) => {
+                for (key, value) in map.iter() {
+                    self.static_dependencies(key, origin, out);
+                    self.static_dependencies(value, origin, out);
+                }
+            }
+            Value::Seq(values) | Value::Tuple(_, values) => {
+                for value in values {
+                    self.static_dependencies(value, origin, out);
+                }
+            }
+            Value::Option(Some(value)) => self.static_dependencies(value, origin, out),
+            Value::Scope(scope) => {
+                for def in &scope.defs {
+                    self.static_dependencies(&def.value, origin, out);
+                }
+                self.static_dependencies(&scope.value, origin, out);
+            }
+            Value::If(If {
+                then, otherwise, ..
+            }) => {
+                self.static_dependencies(then, origin, out);
+                self.static_dependencies(otherwise, origin, out);
+            }
+            Value::Cfg(cfg) => {
+                for branch in &cfg.branches {
+                    self.static_dependencies(&branch.value, origin, out);
+                }
+            }
+            Value::Patch(Patch {
+                op: PatchOp::Set(value) | PatchOp::Append(value),
+                ..
+            }) => self.static_dependencies(value, origin, out),
+            Value::Directive(call) => {
+                for arg in &call.args {
+                    self.static_dependencies(&arg.value, origin, out);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns the origins of the values of the cached file at `path`.
+    fn provenance_of(&self, path: &Path) -> Provenance {
+        self.fs
+            .canonicalize(path)
+            .ok()
+            .and_then(|path| self.cache.get(&path))
+            .map(|entry| entry.provenance.clone())
+            .unwrap_or_default()
+    }
+
+    /// Path of the value that is being resolved, if provenance is recorded for it.
+    fn value_path(&self) -> Option<String> {
+        self.resolve_stack.last()?.value_path.clone()
+    }
+
+    /// Sets the path of the value that is being resolved and returns the previous path.
+    fn set_value_path(&mut self, value_path: Option<String>) -> Option<String> {
+        match self.resolve_stack.last_mut() {
+            Some(frame) => std::mem::replace(&mut frame.value_path, value_path),
+            None => None,
+        }
+    }
+
+    /// Records that the value at `value_path` of the current file was loaded from `file`.
+    fn splice(&mut self, value_path: &str, file: &Path, select: Option<&str>, kind: OriginKind) {
+        let provenance = match self.cache.get(file) {
+            Some(entry) => match select {
+                Some(select) => entry.provenance.subtree(select),
+                None => entry.provenance.clone(),
+            },
+            None => return,
+        };
+        if let Some(frame) = self.resolve_stack.last_mut() {
+            frame.provenance.splice(value_path, &provenance, kind);
+        }
+    }
+
+    /// Records that the value at `value_path` of the current file is the contents of `file`.
+    fn splice_contents(&mut self, value_path: &str, file: &Path, span: Span) {
+        if let Some(frame) = self.resolve_stack.last_mut() {
+            frame.provenance.remove(value_path);
+            let origin = Origin {
+                file: file.to_path_buf(),
+                span,
+                kind: OriginKind::Included,
+            };
+            frame.provenance.insert(value_path.to_string(), origin);
+        }
+    }
+
+    fn include(&mut self, include: &Include, origin: &Path) -> Result<Value, std::io::Error> {
+        if matches!(include.kind, IncludeKind::Glob | IncludeKind::Dir) {
+            return self.include_glob(include, origin);
+        }
+        let path = match self.locate(&include.path, origin, include.span) {
+            Some(path) => path,
+            None => return Ok(Value::Unit),
+        };
+        self.link(&path, EdgeKind::Include(include.kind), Some(include.span));
+        let value_path = self.value_path();
+        match include.kind {
+            IncludeKind::File => {
+                let value = self.load_file(&path)?;
+                if let Some(value_path) = value_path {
+                    let select = include.select.as_deref();
+                    self.splice(&value_path, &path, select, OriginKind::Included);
+                }
+                Ok(self.select(value, include, origin))
+            }
+            IncludeKind::Str => {
+                let bytes = self.read(&path)?;
+                Ok(match String::from_utf8(bytes) {
+                    Ok(string) => {
+                        if let Some(value_path) = value_path {
+                            let span = Span {
+                                start: 0,
+                                end: string.chars().count(),
+                            };
+                            self.splice_contents(&value_path, &path, span);
+                        }
+                        Value::String(string)
+                    }
+                    Err(err) => {
+                        self.errors.push(
+                            self.error(origin, include.span)
+                                .with_message(format!("`{}` is not valid UTF-8", include.path))
+                                .with_label(
+                                    self.label(origin, include.span).with_message(format!(
+                                        "Invalid UTF-8: {}",
+                                        err.utf8_error()
+                                    )),
+                                )
+                                .with_note("Use `#include_bytes` to include binary files")
+                                .finish(),
+                        );
+                        Value::Unit
+                    }
+                })
+            }
+            IncludeKind::Bytes => {
+                let bytes = self.read(&path)?;
+                if let Some(value_path) = value_path {
+                    self.splice_contents(&value_path, &path, Span { start: 0, end: 0 });
+                }
+                Ok(Value::Bytes(bytes))
+            }
+            IncludeKind::Glob | IncludeKind::Dir => unreachable!(),
+        }
+    }
+
+    fn include_glob(&mut self, include: &Include, origin: &Path) -> Result<Value, std::io::Error> {
+        let (dir, pattern) = include.path.rsplit_once('/').unwrap_or(("", &include.path));
+        if dir.contains(['*', '?']) {
+            self.errors.push(
+                self.error(origin, include.span)
+                    .with_message("Wildcards are only supported in the file name")
+                    .with_label(self.label(origin, include.span).with_message(format!(
+                        "`{}` contains a wildcard in a directory component",
+                        include.path
+                    )))
+                    .finish(),
+            );
+            return Ok(Value::Unit);
+        }
+        // Directories that cannot be found or listed match no files.
+        let empty = match include.kind {
+            IncludeKind::Glob => Value::Seq(vec![]),
+            _ => Value::Map(Map::new()),
+        };
+        let written = if dir.is_empty() { "." } else { dir };
+        let dir = match self.locate(written, origin, include.span) {
+            Some(dir) => dir,
+            None => return Ok(empty),
+        };
+        let entries = match self.fs.read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                let err = FindError::ReadDir {
+                    dir,
+                    error: err.to_string(),
+                };
+                self.errors
+                    .push(err.report(written, origin, include.span).finish());
+                return Ok(empty);
+            }
+        };
+        self.watch(
+            &dir,
+            Stamp::Dir {
+                hash: hash_listing(&entries),
+            },
+        );
+        let paths = self.glob(entries, pattern, origin)?;
+
+        let value_path = self.value_path();
+        if let Some(value_path) = &value_path {
+            self.resolve_stack
+                .last_mut()
+                .unwrap()
+                .provenance
+                .remove(value_path);
+        }
+        match include.kind {
+            IncludeKind::Glob => {
+                let mut values = vec![];
+                for path in paths {
+                    let path = match self.admit(&dir, &path, include, origin) {
+                        Some(path) => path,
+                        None => continue,
+                    };
+                    let value = self.load_file(&path)?;
+                    if let Some(value_path) = &value_path {
+                        let element = provenance::element(value_path, values.len());
+                        let select = include.select.as_deref();
+                        self.splice(&element, &path, select, OriginKind::Included);
+                    }
+                    values.push(self.select(value, include, origin));
+                }
+                Ok(Value::Seq(values))
+            }
+            _ => {
+                let mut map = Map::new();
+                for path in paths {
+                    let path = match self.admit(&dir, &path, include, origin) {
+                        Some(path) => path,
+                        None => continue,
+                    };
+                    let stem = path.file_stem().unwrap().to_string_lossy().to_string();
+                    let value = self.load_file(&path)?;
+                    if let Some(value_path) = &value_path {
+                        let field = provenance::field(value_path, &stem);
+                        let select = include.select.as_deref();
+                        self.splice(&field, &path, select, OriginKind::Included);
+                    }
+                    let value = self.select(value, include, origin);
+                    if map.insert(Value::String(stem.clone()), value).is_some() {
+                        self.errors.push(
+                            self.error(origin, include.span)
+                                .with_message(format!(
+                                    "Multiple files named `{}` match `{}`",
+                                    stem, include.path
+                                ))
+                                .with_label(
+                                    self.label(origin, include.span)
+                                        .with_message(format!("Duplicate key `{}`", stem)),
+                                )
+                                .with_note("Only the last file in sorted order is included")
+                                .finish(),
+                        );
+                    }
+                }
+                Ok(Value::Map(map))
+            }
+        }
+    }
+
+    /// Returns the files in the directory listing `entries` whose name matches `pattern`, except
+    /// `origin`, in sorted order.
+    fn glob(
+        &self,
+        entries: Vec<PathBuf>,
+        pattern: &str,
+        origin: &Path,
+    ) -> Result<Vec<PathBuf>, std::io::Error> {
+        let mut paths = vec![];
+        for path in entries {
+            if self.fs.is_file(&path)
+                && glob_match(pattern, &path.file_name().unwrap().to_string_lossy())
+                && self.fs.canonicalize(&path)? != origin
+            {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Resolves a path written in a directive to the canonical path of an existing file or
+    /// directory.
+    ///
+    /// Paths of the form `@name/rest` are relative to the root `name`. Other relative paths are
+    /// tried relative to the directory of `origin` and then relative to each search path.
+    ///
+    /// If a sandbox is configured, the path must also satisfy its restrictions.
+    pub(crate) fn find(&self, path: &str, origin: &Path) -> Result<PathBuf, FindError> {
+        self.search(path, origin).map(|(found, _)| found)
+    }
+
+    /// Like [`Loader::find`], but also returns the candidates that were tried before the path
+    /// was found and do not exist.
+    fn search(&self, path: &str, origin: &Path) -> Result<(PathBuf, Vec<PathBuf>), FindError> {
+        // Each candidate is a directory and the path relative to it.
+        let candidates: Vec<(&Path, &str)> = match path.strip_prefix('@') {
+            Some(rooted) => {
+                let (name, rest) = rooted.split_once('/').unwrap_or((rooted, ""));
+                match self.options.roots.get(name) {
+                    Some(root) => vec![(root, rest)],
+                    None => {
+                        return Err(FindError::UnknownRoot {
+                            name: name.to_string(),
+                            available: self.options.roots.keys().cloned().collect(),
+                        })
+                    }
+                }
+            }
+            None if Path::new(path).is_absolute() => vec![(Path::new("/"), path)],
+            None => std::iter::once(origin.parent().unwrap())
+                .chain(self.options.search_paths.iter().map(PathBuf::as_path))
+                .map(|dir| (dir, path))
+                .collect(),
+        };
+        let mut missing = vec![];
+        for &(dir, relative) in &candidates {
+            let candidate = dir.join(relative);
+            match self.fs.canonicalize(&candidate) {
+                Ok(found) => {
+                    self.check_sandbox(dir, Path::new(relative), &found)?;
+                    return Ok((found, missing));
+                }
+                Err(_) => missing.push(candidate),
+            }
+        }
+        Err(FindError::NotFound {
+            searched: candidates
+                .iter()
+                .map(|(dir, relative)| dir.join(relative))
+                .collect(),
+        })
+    }
+
+    /// Checks that the file `found`, which was reached by following `relative` from `dir`, may
+    /// be loaded in the sandbox.
+    fn check_sandbox(&self, dir: &Path, relative: &Path, found: &Path) -> Result<(), FindError> {
+        let sandbox = match &self.options.sandbox {
+            Some(sandbox) => sandbox,
+            None => return Ok(()),
+        };
+        let root = self
+            .fs
+            .canonicalize(&sandbox.root)
+            .unwrap_or_else(|_| sandbox.root.clone());
+        if !found.starts_with(&root) {
+            return Err(FindError::OutsideSandbox {
+                found: found.to_path_buf(),
+                root,
+            });
+        }
+        if sandbox.reject_symlinks {
+            // Only the components written in the directive are checked, the directory they are
+            // relative to is trusted.
+            let mut current = dir.to_path_buf();
+            for component in relative.components() {
+                match component {
+                    Component::CurDir => {}
+                    Component::ParentDir => {
+                        current.pop();
+                    }
+                    _ => {
+                        current.push(component);
+                        if self.fs.is_symlink(&current) {
+                            return Err(FindError::Symlink { link: current });
+                        }
+                    }
+                }
+            }
+        }
+        if self.fs.is_file(found) && !self.dependencies.contains(found) {
+            if let Some(max_files) = sandbox.max_files {
+                if self.dependencies.len() >= max_files {
+                    return Err(FindError::FileLimit { max_files });
+                }
+            }
+            if let Some(max_bytes) = sandbox.max_bytes {
+                let len = self.fs.len(found).unwrap_or(0);
+                if self.bytes_read + len > max_bytes {
+                    return Err(FindError::ByteLimit { max_bytes });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the canonical path of `path` in `dir`, which was matched by `#include_glob` or
+    /// `#include_dir`, if the sandbox allows it.
+    fn find_match(&self, dir: &Path, path: &Path) -> Result<PathBuf, FindError> {
+        let relative = path.strip_prefix(dir).unwrap_or(path);
+        let found = self
+            .fs
+            .canonicalize(path)
+            .map_err(|_| FindError::NotFound {
+                searched: vec![path.to_path_buf()],
+            })?;
+        self.check_sandbox(dir, relative, &found)?;
+        Ok(found)
+    }
+
+    /// Checks a file matched by `#include_glob` or `#include_dir` against the sandbox, and
+    /// returns its canonical path. Reports a diagnostic if it is rejected.
+    fn admit(
+        &mut self,
+        dir: &Path,
+        path: &Path,
+        include: &Include,
+        origin: &Path,
+    ) -> Option<PathBuf> {
+        match self.find_match(dir, path) {
+            Ok(found) => {
+                self.link(&found, EdgeKind::Include(include.kind), Some(include.span));
+                Some(found)
+            }
+            Err(err) => {
+                let report = err.report(&path.to_string_lossy(), origin, include.span);
+                self.errors.push(report.finish());
+                None
+            }
+        }
+    }
+
+    /// Like [`Loader::find`], but reports a diagnostic if the path cannot be resolved. The
+    /// candidates that do not exist are watched, since creating one of them changes the result.
+    fn locate(&mut self, path: &str, origin: &Path, span: Span) -> Option<PathBuf> {
+        match self.search(path, origin) {
+            Ok((found, missing)) => {
+                for candidate in missing {
+                    self.watch(&candidate, Stamp::Missing);
+                }
+                Some(found)
+            }
+            Err(err) => {
+                if let FindError::NotFound { searched } = &err {
+                    for candidate in searched {
+                        self.watch(candidate, Stamp::Missing);
+                    }
+                }
+                self.errors.push(err.report(path, origin, span).finish());
+                None
+            }
+        }
+    }
+
+    fn env(&mut self, env: &Env, origin: &Path) -> Result<Value, std::io::Error> {
+        if !self.options.allow_env {
+            self.errors.push(
+                self.error(origin, env.span)
+                    .with_message("Environment variables are disabled")
+                    .with_label(
+                        self.label(origin, env.span)
+                            .with_message(format!("Cannot read `{}`", env.name)),
+                    )
+                    .finish(),
+            );
+            return Ok(Value::Unit);
+        }
+        let text = match std::env::var(&env.name) {
+            Ok(text) => text,
+            Err(std::env::VarError::NotPresent) => match &env.default {
+                Some(default) => {
+                    let mut default = (**default).clone();
+                    self.resolve(&mut default, origin)?;
+                    return Ok(default);
+                }
+                None => {
+                    self.errors.push(
+                        self.error(origin, env.span)
+                            .with_message(format!("Environment variable `{}` is not set", env.name))
+                            .with_label(
+                                self.label(origin, env.span)
+                                    .with_message("Required by this directive"),
+                            )
+                            .with_note(format!(
+                                "Set `{}` or provide a fallback with `default: <value>`",
+                                env.name
+                            ))
+                            .finish(),
+                    );
+                    return Ok(Value::Unit);
+                }
+            },
+            Err(std::env::VarError::NotUnicode(_)) => {
+                self.errors.push(
+                    self.error(origin, env.span)
+                        .with_message(format!(
+                            "Environment variable `{}` is not valid unicode",
+                            env.name
+                        ))
+                        .with_label(
+                            self.label(origin, env.span)
+                                .with_message("Value is not valid UTF-8"),
+                        )
+                        .finish(),
+                );
+                return Ok(Value::Unit);
+            }
+        };
+        let value = match env.ty {
+            EnvType::String => Some(Value::String(text.clone())),
+            EnvType::Number => match text.trim().parse::<i64>() {
+                Ok(int) => Some(Value::Number(Number::Integer(int))),
+                Err(_) => text
+                    .trim()
+                    .parse::<f64>()
+                    .ok()
+                    .map(|f| Value::Number(f.into())),
+            },
+            EnvType::Bool => match text.trim() {
+                "true" => Some(Value::Bool(true)),
+                "false" => Some(Value::Bool(false)),
+                _ => None,
+            },
+        };
+        Ok(value.unwrap_or_else(|| {
+            self.errors.push(
+                self.error(origin, env.span)
+                    .with_message(format!(
+                        "Environment variable `{}` is not a valid {}",
+                        env.name,
+                        format!("{:?}", env.ty).to_lowercase()
+                    ))
+                    .with_label(
+                        self.label(origin, env.span)
+                            .with_message(format!("`{}` has value `{}`", env.name, text)),
+                    )
+                    .finish(),
+            );
+            Value::Unit
+        }))
+    }
+
+    fn check_shadowing(&mut self, def: &Def, origin: &Path) {
+        if let Some((_, span)) = self.scopes.iter().rev().find_map(|s| s.get(&def.name)) {
+            let shadowed = Shadowed {
+                name: def.name.clone(),
+                span: def.span,
+                previous: *span,
+            };
+            self.warnings
+                .push(self.shadowing_warning(origin, &shadowed));
+            let frame = self.resolve_stack.last_mut().unwrap();
+            frame.shadowed.push(shadowed);
+        }
+    }
+
+    /// Reports a warning of a directive in the file that is being resolved.
+    pub(crate) fn warn(&mut self, warning: Report<(String, Range<usize>)>) {
+        self.warnings.push(warning);
+        if let Some(frame) = self.resolve_stack.last_mut() {
+            frame.warned = true;
+        }
+    }
+
+    fn shadowing_warning(
+        &self,
+        origin: &Path,
+        shadowed: &Shadowed,
+    ) -> Report<(String, Range<usize>)> {
+        Report::build(
+            ReportKind::Warning,
+            origin.to_str().unwrap().to_string(),
+            shadowed.span.start,
+        )
+        .with_message(format!(
+            "Definition of `{}` shadows an outer definition",
+            shadowed.name
+        ))
+        .with_label(
+            self.label(origin, shadowed.span)
+                .with_message(format!("`{}` redefined here", shadowed.name)),
+        )
+        .with_label(
+            self.label(origin, shadowed.previous)
+                .with_message("Previous definition is shadowed"),
+        )
+        .finish()
+    }
+
+    fn reference(&mut self, r: &Ref, origin: &Path) -> Value {
+        match self.lookup(&r.name, r.select.as_deref(), r.span, origin, None) {
+            Ok(value) => value,
+            Err(report) => {
+                self.errors.push(report.finish());
+                Value::Unit
+            }
+        }
+    }
+
+    fn expr(
+        &mut self,
+        expr: &Expr,
+        origin: &Path,
+        siblings: Option<&IndexMap<String, Value>>,
+    ) -> Result<Value, std::io::Error> {
+        Ok(self.eval(expr, origin, siblings)?.unwrap_or_else(|report| {
+            self.errors.push(report.finish());
+            Value::Unit
+        }))
+    }
+
+    fn eval(
+        &mut self,
+        expr: &Expr,
+        origin: &Path,
+        siblings: Option<&IndexMap<String, Value>>,
+    ) -> Result<Result<Value, RB>, std::io::Error> {
+        let mut expr = expr.clone();
+        expr.values_mut(&mut |value| self.resolve(value, origin))?;
+        Ok(expr::eval(
+            &expr,
+            origin.to_str().unwrap(),
+            &mut |name, select, span| self.lookup(name, select, span, origin, siblings),
+            &|name| self.is_set(name),
+        ))
+    }
+
+    fn if_(
+        &mut self,
+        i: &If,
+        origin: &Path,
+        siblings: Option<&IndexMap<String, Value>>,
+    ) -> Result<Value, std::io::Error> {
+        let branch = match self.eval(&i.condition, origin, siblings)? {
+            Ok(Value::Bool(true)) => &i.then,
+            Ok(Value::Bool(false)) => &i.otherwise,
+            Ok(value) => {
+                self.errors.push(
+                    self.error(origin, i.condition.span)
+                        .with_message("Condition of `#if` must be a bool")
+                        .with_label(
+                            self.label(origin, i.condition.span)
+                                .with_message(format!("This is {}", value.kind_name())),
+                        )
+                        .finish(),
+                );
+                return Ok(Value::Unit);
+            }
+            Err(report) => {
+                self.errors.push(report.finish());
+                return Ok(Value::Unit);
+            }
+        };
+        match &**branch {
+            Value::Expr(expr) => self.expr(expr, origin, siblings),
+            Value::If(i) => self.if_(i, origin, siblings),
+            branch => {
+                let mut branch = branch.clone();
+                self.resolve(&mut branch, origin)?;
+                Ok(branch)
+            }
+        }
+    }
+
+    /// Returns the value of the last declaration of a `#cfg` field whose predicate matches.
+    fn cfg(&self, cfg: &Cfg) -> Option<Value> {
+        cfg.branches
+            .iter()
+            .rev()
+            .find(|branch| match &branch.predicate {
+                Some(predicate) => predicate.eval(&|name| self.is_set(name)),
+                None => true,
+            })
+            .map(|branch| branch.value.clone())
+    }
+
+    /// Whether `name` is the active profile or an enabled flag.
+    fn is_set(&self, name: &str) -> bool {
+        self.options.profile.as_deref() == Some(name)
+            || self.options.flags.iter().any(|f| f == name)
+    }
+
+    /// Looks up a definition or, if `siblings` is given, a field of the enclosing struct.
+    fn lookup(
+        &self,
+        name: &str,
+        select: Option<&str>,
+        span: Span,
+        origin: &Path,
+        siblings: Option<&IndexMap<String, Value>>,
+    ) -> Result<Value, ReportBuilder<(String, Range<usize>)>> {
+        let profile = match &self.options.profile {
+            Some(profile) => Value::String(profile.clone()),
+            None => Value::Option(None),
+        };
+        let value = match self.scopes.iter().rev().find_map(|s| s.get(name)) {
+            Some((value, _)) => value,
+            None => match siblings.and_then(|fields| fields.get(name)) {
+                Some(Value::Expr(_) | Value::If(_)) => {
+                    return Err(self
+                        .error(origin, span)
+                        .with_message(format!("Field `{}` has not been evaluated yet", name))
+                        .with_label(
+                            self.label(origin, span)
+                                .with_message("Refers to a later expression"),
+                        )
+                        .with_note("Expressions can only refer to fields computed before them"))
+                }
+                Some(value) => value,
+                None if name == "profile" => &profile,
+                None => {
+                    let mut names = self
+                        .scopes
+                        .iter()
+                        .flat_map(|s| s.keys())
+                        .chain(siblings.into_iter().flat_map(|fields| fields.keys()))
+                        .map(|name| format!("`{}`", name))
+                        .collect::<Vec<_>>();
+                    names.sort();
+                    names.dedup();
+                    let mut report = self
+                        .error(origin, span)
+                        .with_message(format!("Undefined name `{}`", name))
+                        .with_label(
+                            self.label(origin, span)
+                                .with_message("Not found in this scope"),
+                        );
+                    if !names.is_empty() {
+                        report = report.with_note(format!("Defined names: {}", names.join(", ")));
+                    }
+                    return Err(report);
+                }
+            },
+        };
+        match select {
+            None => Ok(value.clone()),
+            Some(select) => select_path(value, select)
+                .cloned()
+                .map_err(|err| err.report(origin, name, select, span)),
+        }
+    }
+
+    /// Reads a file that is included verbatim rather than parsed.
+    pub(crate) fn read(&mut self, path: &Path) -> Result<Vec<u8>, std::io::Error> {
+        let path = self.fs.canonicalize(path)?;
+        let bytes = self.fs.read(&path)?;
+        self.record(&path, &bytes);
+        Ok(bytes)
+    }
+
+    fn select(&mut self, value: Value, include: &Include, origin: &Path) -> Value {
+        match &include.select {
+            None => value,
+            Some(select) => match select_path(&value, select) {
+                Ok(selected) => selected.clone(),
+                Err(err) => {
+                    self.errors.push(
+                        err.report(origin, &include.path, select, include.span)
+                            .finish(),
+                    );
+                    Value::Unit
+                }
+            },
+        }
+    }
+
+    fn directive(&mut self, call: &DirectiveCall, origin: &Path) -> Result<Value, std::io::Error> {
+        let mut call = call.clone();
+        // Arguments are not part of the document, so no provenance is recorded for them.
+        let value_path = self.set_value_path(None);
+        for arg in &mut call.args {
+            self.resolve(&mut arg.value, origin)?;
+        }
+        self.set_value_path(value_path);
+        let result = match self.options.directives.get(&call.name).cloned() {
+            Some(directive) => {
+                directive.resolve(&call, &mut DirectiveContext::new(self, origin, &call))
+            }
+            None if self.options.keep_unknown_directives => {
+                self.unresolved
+                    .insert((origin.to_path_buf(), call.name.clone(), call.span));
+                return Ok(Value::Directive(call));
+            }
+            None => Err(self
+                .error(origin, call.span)
+                .with_message(format!("Unknown directive `#{}`", call.name))
+                .with_label(
+                    self.label(origin, call.span)
+                        .with_message("No directive with this name is registered"),
+                )),
+        };
+        Ok(result.unwrap_or_else(|report| {
+            self.errors.push(report.finish());
+            Value::Unit
+        }))
+    }
+
+    /// Applies a patch from an overlay file to `target`, the document named `base`.
+    fn patch(&mut self, target: &mut Value, patch: Value, origin: &Path, base: &str) {
+        let patch = match patch {
+            Value::Patch(patch) => patch,
+            // Errors for invalid patches have already been reported.
+            Value::Unit => return,
+            value => {
+                self.errors.push(
+                    Report::build(ReportKind::Error, origin.to_str().unwrap().to_string(), 0)
+                        .with_message(format!(
+                            "Expected `#set`, `#remove` or `#append` in overlay, found {}",
+                            value.kind_name()
+                        ))
+                        .with_note("An overlay must be a list of patches")
+                        .finish(),
+                );
+                return;
+            }
+        };
+        if let Err(report) = apply_patch(target, &patch, origin, base) {
+            self.errors.push(report.finish());
+            return;
+        }
+        if !self.options.provenance {
+            return;
+        }
+        let overlay = Origin {
+            file: origin.to_path_buf(),
+            span: patch.span,
+            kind: OriginKind::Overlay,
+        };
+        match patch.op {
+            PatchOp::Set(_) => {
+                self.provenance.remove(&patch.path);
+                self.provenance.insert(patch.path.clone(), overlay);
+            }
+            PatchOp::Remove => {
+                // Removing an element shifts the origins of the elements after it. Map entries
+                // are recorded like fields, even when the patch selects them in brackets.
+                let (parent, segment) = split_last_segment(&patch.path);
+                let is_list = matches!(
+                    select_path(target, parent),
+                    Ok(Value::Seq(_) | Value::Tuple(_, _))
+                );
+                match segment.parse() {
+                    Ok(index) if is_list && patch.path.ends_with(']') => {
+                        self.provenance.remove_element(parent, index)
+                    }
+                    _ => {
+                        self.provenance.remove(&patch.path);
+                        self.provenance.remove(&provenance::field(parent, segment));
+                    }
+                }
+            }
+            PatchOp::Append(_) => {
+                if let Ok(Value::Seq(values)) = select_path(target, &patch.path) {
+                    let element = provenance::element(&patch.path, values.len() - 1);
+                    self.provenance.insert(element, overlay);
+                }
+            }
+        }
+    }
+
+    fn error(&self, origin: &Path, span: Span) -> ReportBuilder<(String, Range<usize>)> {
+        Report::build(
+            ReportKind::Error,
+            origin.to_str().unwrap().to_string(),
+            span.start,
+        )
+    }
+
+    fn label(&self, origin: &Path, span: Span) -> Label<(String, Range<usize>)> {
+        Label::new((origin.to_str().unwrap().to_string(), span.start..span.end))
+    }
+
+    fn resolve(&mut self, value: &mut Value, origin: &Path) -> Result<(), std::io::Error> {
+        match value {
+            Value::Include(include) => *value = self.include(include, origin)?,
+            Value::Env(env) => *value = self.env(env, origin)?,
+            Value::Scope(scope) => {
+                self.scopes.push(IndexMap::new());
+                for def in &scope.defs {
+                    self.check_shadowing(def, origin);
+                    let mut value = def.value.clone();
+                    self.resolve(&mut value, origin)?;
+                    self.scopes
+                        .last_mut()
+                        .unwrap()
+                        .insert(def.name.clone(), (value, def.span));
+                }
+                let mut body = (*scope.value).clone();
+                self.resolve(&mut body, origin)?;
+                self.scopes.pop();
+                *value = body;
+            }
+            Value::Ref(r) => *value = self.reference(r, origin),
+            Value::Expr(expr) => *value = self.expr(expr, origin, None)?,
+            Value::If(i) => *value = self.if_(i, origin, None)?,
+            Value::Cfg(cfg) => {
+                *value = self.cfg(cfg).unwrap_or(Value::Unit);
+                self.resolve(value, origin)?;
+            }
+            Value::Directive(call) => *value = self.directive(call, origin)?,
+            Value::Patch(patch) => {
+                if self.overlay.as_deref() != Some(origin) {
+                    self.errors.push(
+                        self.error(origin, patch.span)
+                            .with_message("Patches can only be used in overlay files")
+                            .with_label(
+                                self.label(origin, patch.span)
+                                    .with_message("Not loaded as an overlay"),
+                            )
+                            .with_note("Load overlays with `load_with_overlays`")
+                            .finish(),
+                    );
+                    *value = Value::Unit;
+                } else if let PatchOp::Set(value) | PatchOp::Append(value) = &mut patch.op {
+                    self.resolve(value, origin)?;
+                }
+            }
+            Value::Struct(Struct {
+                name: _,
+                prototype,
+                prototype_span,
+                fields,
+            }) => {
+                // Fields without a matching `#cfg` declaration are omitted, so they can still
+                // be inherited from the prototype.
+                let declared = std::mem::take(fields);
+                for (name, field) in declared {
+                    match field {
+                        Value::Cfg(cfg) => {
+                            if let Some(field) = self.cfg(&cfg) {
+                                fields.insert(name, field);
+                            }
+                        }
+                        field => {
+                            fields.insert(name, field);
+                        }
+                    }
+                }
+                let found = prototype.as_ref().and_then(|path| {
+                    let span = prototype_span.unwrap_or(Span { start: 0, end: 0 });
+                    self.locate(path, origin, span)
+                });
+                if let Some(include_path) = found {
+                    self.link(&include_path, EdgeKind::Prototype, *prototype_span);
+                    let include_value = self.load_file(&include_path)?;
+                    let value_path = self.value_path();
+                    match include_value {
+                        Value::Struct(include_struct) => {
+                            for (name, field) in include_struct.fields.into_iter() {
+                                if !fields.contains_key(&name) {
+                                    if let Some(value_path) = &value_path {
+                                        let field = provenance::field(value_path, &name);
+                                        let kind = OriginKind::Inherited;
+                                        self.splice(&field, &include_path, Some(&name), kind);
+                                    }
+                                    fields.insert(name, field.clone());
+                                }
+                            }
+                        }
+                        // `()` has no fields to inherit, and cycles have already been reported.
+                        Value::Unit => {}
+                        include_value => {
+                            let span = prototype_span.unwrap_or(Span { start: 0, end: 0 });
+                            let path = prototype.as_deref().unwrap_or_default();
+                            self.errors.push(
+                                self.error(origin, span)
+                                    .with_message(format!(
+                                        "Prototype must be a struct, found {}",
+                                        include_value.kind_name()
+                                    ))
+                                    .with_label(
+                                        self.label(origin, span)
+                                            .with_message(format!("`{}` is not a struct", path)),
+                                    )
+                                    .finish(),
+                            );
+                        }
+                    }
+                }
+                *prototype = None;
+                *prototype_span = None;
+                let value_path = self.value_path();
+                for (name, field) in fields.iter_mut() {
+                    if !matches!(field, Value::Expr(_) | Value::If(_)) {
+                        let field_path = value_path.as_ref().map(|p| provenance::field(p, name));
+                        let parent = self.set_value_path(field_path);
+                        self.resolve(field, origin)?;
+                        self.set_value_path(parent);
+                    }
+                }
+                // Expressions are evaluated last so that they can refer to the other fields.
+                let exprs = fields
+                    .iter()
+                    .filter(|(_, field)| matches!(field, Value::Expr(_) | Value::If(_)))
+                    .map(|(name, _)| name.clone())
+                    .collect::<Vec<_>>();
+                for name in exprs {
+                    let field_path = value_path.as_ref().map(|p| provenance::field(p, &name));
+                    let parent = self.set_value_path(field_path);
+                    let value = match &fields[&name] {
+                        Value::Expr(expr) => self.expr(&expr.clone(), origin, Some(fields)),
+                        Value::If(i) => self.if_(&i.clone(), origin, Some(fields)),
+                        _ => unreachable!(),
+                    };
+                    self.set_value_path(parent);
+                    fields.insert(name, value?);
+                }
+            }
+            Value::Map(items) => {
+                let mut resolved = IndexMap::with_capacity(items.0.len());
+                let mut interpolated = HashMap::new();
+                let value_path = self.value_path();
+                for (mut key, mut value) in std::mem::take(&mut items.0) {
+                    let key_span = match &key {
+                        Value::Expr(expr) => Some(expr.span),
+                        _ => None,
+                    };
+                    let entry_path = match (&value_path, &key) {
+                        (Some(path), Value::String(key)) => Some(provenance::field(path, key)),
+                        (Some(path), Value::Number(Number::Integer(key))) => {
+                            Some(provenance::field(path, &key.to_string()))
+                        }
+                        _ => None,
+                    };
+                    let parent = self.set_value_path(None);
+                    self.resolve(&mut key, origin)?;
+                    self.set_value_path(entry_path);
+                    self.resolve(&mut value, origin)?;
+                    self.set_value_path(parent);
+                    // Literal duplicates are merged by the parser, so a collision here involves
+                    // an interpolated key.
+                    let span = key_span.or_else(|| interpolated.get(&key).copied());
+                    if let (Some(span), Value::String(name)) = (span, &key) {
+                        if resolved.contains_key(&key) {
+                            self.errors.push(
+                                self.error(origin, span)
+                                    .with_message(format!("Duplicate key `{}`", name))
+                                    .with_label(
+                                        self.label(origin, span)
+                                            .with_message(format!("Interpolates to `{}`", name)),
+                                    )
+                                    .with_note("Only the last entry with this key is kept")
+                                    .finish(),
+                            );
+                        }
+                    }
+                    if let Some(span) = key_span {
+                        interpolated.insert(key.clone(), span);
+                    }
+                    resolved.insert(key, value);
+                }
+                items.0 = resolved;
+            }
+            Value::Seq(values) | Value::Tuple(_, values) => {
+                let value_path = self.value_path();
+                for (i, value) in values.iter_mut().enumerate() {
+                    let element_path = value_path.as_ref().map(|p| provenance::element(p, i));
+                    let parent = self.set_value_path(element_path);
+                    self.resolve(value, origin)?;
+                    self.set_value_path(parent);
+                }
+            }
+            Value::Option(Some(value)) => self.resolve(value, origin)?,
+            Value::Bool(_)
+            | Value::Char(_)
+            | Value::Number(_)
+            | Value::Option(None)
+            | Value::String(_)
+            | Value::Bytes(_)
+            | Value::Unit => {}
+        }
+        Ok(())
+    }
+}
+
+/// A file that is being resolved by a [`Loader`].
+struct Frame {
+    path: PathBuf,
+    /// The directives that have loaded or read a file so far.
+    edges: Vec<Edge>,
+    provenance: Provenance,
+    /// Path of the value that is being resolved, in the syntax of [`Provenance`]. `None` if no
+    /// provenance is recorded for the value.
+    value_path: Option<String>,
+    /// Definitions of this file that shadow an outer definition.
+    shadowed: Vec<Shadowed>,
+    /// Directories listed and missing paths searched while resolving this file.
+    watched: Vec<PathBuf>,
+    /// Whether a directive reported a warning, which cannot be replayed from the cache.
+    warned: bool,
+}
+
+/// A definition that shadows an outer definition, reported as a warning.
+struct Shadowed {
+    name: String,
+    span: Span,
+    previous: Span,
+}
+
+/// A resolved file in the cache of a [`Loader`].
+struct Entry {
+    value: Value,
+    source: String,
+    /// The directives that loaded or read a file while resolving this file.
+    edges: Vec<Edge>,
+    /// Origins of the values of this file, relative to the file.
+    provenance: Provenance,
+    /// Definitions that shadow an outer definition, reported again whenever the entry is used.
+    shadowed: Vec<Shadowed>,
+    /// Directories listed and missing paths searched while resolving this file, which
+    /// invalidate the entry when they change.
+    watched: Vec<PathBuf>,
+    /// Whether resolving this file and its dependencies produced no errors. Other entries
+    /// are dropped at the start of the next load.
+    clean: bool,
+}
+
+/// Restores the file system of a loader that was replaced by [`Loader::load_async`].
+#[cfg(feature = "async")]
+struct Restore<'a> {
+    loader: &'a mut Loader,
+    fs: Option<Arc<dyn FileSystem>>,
+}
+
+#[cfg(feature = "async")]
+impl Drop for Restore<'_> {
+    fn drop(&mut self) {
+        self.loader.fs = self.fs.take().unwrap();
+    }
+}
+
+/// A file that was read and parsed, but whose directives are not resolved yet.
+struct Parsed {
+    source: String,
+    value: Value,
+    errors: Vec<RB>,
+    /// Locations of the values, if provenance is recorded.
+    spans: IndexMap<String, Span>,
+}
+
+/// The work shared by the threads of [`Loader::prefetch`].
+struct Queue {
+    pending: Vec<PathBuf>,
+    /// Every file that was queued, so that each file is parsed only once.
+    seen: HashSet<PathBuf>,
+    /// Number of files that are being parsed. The work is done once no files are pending or
+    /// being parsed.
+    busy: usize,
+    bytes_read: u64,
+    parsed: HashMap<PathBuf, Parsed>,
+}
+
+/// The state of a path when it was last used, to detect changes.
+enum Stamp {
+    /// A file that was read.
+    File {
+        modified: Option<SystemTime>,
+        hash: u64,
+    },
+    /// A directory that was listed by `#include_glob` or `#include_dir`.
+    Dir { hash: u64 },
+    /// A path that was searched but did not exist.
+    Missing,
+}
+
+fn hash(contents: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Hashes the entries of a directory independently of the order they are listed in.
+fn hash_listing(entries: &[PathBuf]) -> u64 {
+    let mut entries = entries.iter().collect::<Vec<_>>();
+    entries.sort();
+    let mut hasher = DefaultHasher::new();
+    entries.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Error produced when a path in a directive does not resolve to an existing file.
+pub(crate) enum FindError {
+    UnknownRoot {
+        name: String,
+        available: Vec<String>,
+    },
+    NotFound {
+        searched: Vec<PathBuf>,
+    },
+    OutsideSandbox {
+        found: PathBuf,
+        root: PathBuf,
+    },
+    Symlink {
+        link: PathBuf,
+    },
+    FileLimit {
+        max_files: usize,
+    },
+    ByteLimit {
+        max_bytes: u64,
+    },
+    /// A directory searched by `#include_glob` or `#include_dir` exists but cannot be listed.
+    ReadDir {
+        dir: PathBuf,
+        error: String,
+    },
+}
+
+impl FindError {
+    fn report(&self, path: &str, origin: &Path, span: Span) -> RB {
+        let source_name = origin.to_str().unwrap().to_string();
+        let report = Report::build(ReportKind::Error, source_name.clone(), span.start);
+        let label = Label::new((source_name, span.start..span.end));
+        match self {
+            FindError::UnknownRoot { name, available } => report
+                .with_message(format!("Unknown root `@{}`", name))
+                .with_label(label.with_message(format!("`{}` is not a configured root", name)))
+                .with_note(if available.is_empty() {
+                    "No roots are configured, add them with `LoadOptions::root`".to_string()
+                } else {
+                    format!(
+                        "Available roots: {}",
+                        available
+                            .iter()
+                            .map(|name| format!("`@{}`", name))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )
+                }),
+            FindError::NotFound { searched } => report
+                .with_message(format!("Cannot find `{}`", path))
+                .with_label(label.with_message("No such file in any searched location"))
+                .with_note(format!(
+                    "Searched locations:\n{}",
+                    searched
+                        .iter()
+                        .map(|path| format!("  {}", path.display()))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                )),
+            FindError::OutsideSandbox { found, root } => report
+                .with_message(format!("`{}` is outside the sandbox", path))
+                .with_label(label.with_message(format!("Resolves to `{}`", found.display())))
+                .with_note(format!("Only files in `{}` can be loaded", root.display())),
+            FindError::Symlink { link } => report
+                .with_message(format!("`{}` goes through a symbolic link", path))
+                .with_label(label.with_message(format!("`{}` is a symbolic link", link.display())))
+                .with_note("Symbolic links are rejected by the sandbox"),
+            FindError::FileLimit { max_files } => report
+                .with_message(format!("Cannot load `{}`", path))
+                .with_label(label.with_message("Exceeds the sandbox file limit"))
+                .with_note(format!("At most {} files can be loaded", max_files)),
+            FindError::ByteLimit { max_bytes } => report
+                .with_message(format!("Cannot load `{}`", path))
+                .with_label(label.with_message("Exceeds the sandbox size limit"))
+                .with_note(format!("At most {} bytes can be loaded", max_bytes)),
+            FindError::ReadDir { dir, error } => report
+                .with_message(format!("Cannot list the directory `{}`", path))
+                .with_label(label.with_message(format!("Resolves to `{}`", dir.display())))
+                .with_note(error),
+        }
+    }
+
+    pub(crate) fn io_error(&self, path: &str) -> std::io::Error {
+        let message = match self {
+            FindError::UnknownRoot { name, .. } => {
+                format!("Unknown root `@{}` in `{}`", name, path)
+            }
+            FindError::NotFound { searched } => format!(
+                "Cannot find `{}`, searched {}",
+                path,
+                searched
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            FindError::OutsideSandbox { found, .. } => format!(
+                "`{}` resolves to `{}`, which is outside the sandbox",
+                path,
+                found.display()
+            ),
+            FindError::Symlink { link } => format!(
+                "`{}` goes through the symbolic link `{}`, which is rejected by the sandbox",
+                path,
+                link.display()
+            ),
+            FindError::FileLimit { max_files } => format!(
+                "Cannot load `{}`, the sandbox allows at most {} files",
+                path, max_files
+            ),
+            FindError::ByteLimit { max_bytes } => format!(
+                "Cannot load `{}`, the sandbox allows at most {} bytes",
+                path, max_bytes
+            ),
+            FindError::ReadDir { dir, error } => format!(
+                "Cannot list the directory `{}` at `{}`: {}",
+                path,
+                dir.display(),
+                error
+            ),
+        };
+        let kind = match self {
+            FindError::UnknownRoot { .. } | FindError::NotFound { .. } => {
+                std::io::ErrorKind::NotFound
+            }
+            FindError::ReadDir { .. } => std::io::ErrorKind::Other,
+            _ => std::io::ErrorKind::PermissionDenied,
+        };
+        std::io::Error::new(kind, message)
+    }
+}
+
+/// Error produced when a selection path does not exist in a value.
+#[derive(Debug)]
+struct SelectError {
+    /// The prefix of the path that was resolved successfully.
+    found: String,
+    /// The path segment that could not be resolved.
+    missing: String,
+    /// Keys that are available at the point where resolution failed.
+    available: Vec<String>,
+}
+
+impl SelectError {
+    fn report(
+        &self,
+        origin: &Path,
+        target: &str,
+        select: &str,
+        span: Span,
+    ) -> ReportBuilder<(String, Range<usize>)> {
+        let source_name = origin.to_str().unwrap().to_string();
+        let location = if self.found.is_empty() {
+            format!("`{}`", target)
+        } else {
+            format!("`{}` of `{}`", self.found, target)
+        };
+        let note = if self.available.is_empty() {
+            format!("{} has no fields or elements", location)
+        } else {
+            format!("Available keys: {}", self.available.join(", "))
+        };
+        Report::build(ReportKind::Error, source_name.clone(), span.start)
+            .with_message(format!("Path `{}` not found in `{}`", select, target))
+            .with_label(
+                Label::new((source_name, span.start..span.end))
+                    .with_message(format!("No entry `{}` in {}", self.missing, location)),
+            )
+            .with_note(note)
+    }
+}
+
+/// Resolves a selection path such as `goblin.stats` or `spells[0].damage` against `value`.
+///
+/// Segments are separated by `.` and may be followed by any number of `[index]` suffixes.
+/// A segment selects a struct field, a map entry with a matching string or integer key, or
+/// a list or tuple element if it is an integer.
+fn select_path<'a>(value: &'a Value, path: &str) -> Result<&'a Value, SelectError> {
+    let mut current = value;
+    let mut found = String::new();
+    for part in path.split('.') {
+        let (name, indices) = part.split_at(part.find('[').unwrap_or(part.len()));
+        let segments = Some(name)
+            .filter(|name| !name.is_empty())
+            .into_iter()
+            .chain(indices.split(['[', ']']).filter(|s| !s.is_empty()));
+        for segment in segments {
+            current = current.child(segment).ok_or_else(|| SelectError {
+                found: found.clone(),
+                missing: segment.to_string(),
+                available: available_keys(current),
+            })?;
+            if segment.parse::<usize>().is_ok() {
+                found.push_str(&format!("[{}]", segment));
+            } else {
+                if !found.is_empty() {
+                    found.push('.');
+                }
+                found.push_str(segment);
+            }
+        }
+    }
+    Ok(current)
+}
+
+/// Like [`select_path`], but returns a mutable reference.
+fn select_path_mut<'a>(value: &'a mut Value, path: &str) -> Result<&'a mut Value, SelectError> {
+    // Check the path first so that errors can list the available keys.
+    select_path(value, path)?;
+    Ok(value.get_path_mut(path).unwrap())
+}
+
+/// Splits a selection path into the path of the parent and the last segment.
+fn split_last_segment(path: &str) -> (&str, &str) {
+    match path.strip_suffix(']').and_then(|p| p.rsplit_once('[')) {
+        Some((parent, index)) => (parent, index),
+        None => match path.rsplit_once('.') {
+            Some((parent, name)) => (parent, name),
+            None => ("", path),
+        },
+    }
+}
+
+fn apply_patch(
+    target: &mut Value,
+    patch: &Patch,
+    origin: &Path,
+    base: &str,
+) -> Result<(), ReportBuilder<(String, Range<usize>)>> {
+    let not_found = |err: SelectError| err.report(origin, base, &patch.path, patch.span);
+    let invalid = |message: &str, label: String| {
+        let source_name = origin.to_str().unwrap().to_string();
+        Report::build(ReportKind::Error, source_name.clone(), patch.span.start)
+            .with_message(message)
+            .with_label(
+                Label::new((source_name, patch.span.start..patch.span.end)).with_message(label),
+            )
+    };
+    let (parent_path, last) = split_last_segment(&patch.path);
+    if !patch.path.is_empty() && last.is_empty() {
+        return Err(invalid(
+            &format!("Invalid path `{}`", patch.path),
+            "Expected a field name or index at the end of the path".to_string(),
+        ));
+    }
+    match &patch.op {
+        PatchOp::Set(value) => {
+            let parent = select_path_mut(target, parent_path).map_err(not_found)?;
+            if let Value::Map(map) = parent {
+                let key = map
+                    .segment_key(last)
+                    .unwrap_or_else(|| Value::String(last.to_string()));
+                map.0.insert(key, (**value).clone());
+            } else {
+                *select_path_mut(target, &patch.path).map_err(not_found)? = (**value).clone();
+            }
+        }
+        PatchOp::Remove => {
+            if patch.path.is_empty() {
+                return Err(invalid(
+                    "Cannot remove the root value",
+                    "Expected a path".to_string(),
+                ));
+            }
+            select_path(target, &patch.path).map_err(not_found)?;
+            let mut parent = select_path_mut(target, parent_path).map_err(not_found)?;
+            while let Value::Option(Some(value)) = parent {
+                parent = value;
+            }
+            let removed = match parent {
+                Value::Struct(s) => s.fields.shift_remove(last),
+                Value::Map(map) => match map.segment_key(last) {
+                    Some(key) => map.0.shift_remove(&key),
+                    None => None,
+                },
+                Value::Seq(values) | Value::Tuple(_, values) => match last.parse::<usize>() {
+                    Ok(index) if index < values.len() => Some(values.remove(index)),
+                    _ => None,
+                },
+                _ => None,
+            };
+            if removed.is_none() {
+                return Err(invalid(
+                    &format!("Cannot remove from {}", parent.kind_name()),
+                    format!("`{}` in `{}` is not a field or element", patch.path, base),
+                ));
+            }
+        }
+        PatchOp::Append(value) => match select_path_mut(target, &patch.path).map_err(not_found)? {
+            Value::Seq(values) => values.push((**value).clone()),
+            found => {
+                return Err(invalid(
+                    &format!("Cannot append to {}", found.kind_name()),
+                    format!("`{}` in `{}` is not a list", patch.path, base),
+                ));
+            }
+        },
+    }
+    Ok(())
+}
+
+fn available_keys(value: &Value) -> Vec<String> {
+    match value {
+        Value::Struct(s) => s.keys().map(|k| format!("`{}`", k)).collect(),
+        Value::Map(m) => m
+            .keys()
+            .filter_map(|k| match k {
+                Value::String(s) => Some(format!("`{}`", s)),
+                Value::Number(Number::Integer(i)) => Some(format!("`{}`", i)),
+                _ => None,
+            })
+            .collect(),
+        Value::Seq(values) | Value::Tuple(_, values) if !values.is_empty() => {
+            vec![format!("`[0]` to `[{}]`", values.len() - 1)]
+        }
+        Value::Option(Some(value)) => available_keys(value),
+        _ => vec![],
+    }
+}
+
+/// Matches a file name against a pattern in which `*` matches any sequence of characters and
+/// `?` matches any single character.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    n = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
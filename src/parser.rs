@@ -3,18 +3,53 @@ use std::ops::Range;
 use ariadne::{Label, Report, ReportBuilder, ReportKind};
 use indexmap::IndexMap;
 
-use crate::token::{Token, TokenKind};
-use crate::value::{Map, Number, Struct, Value};
-use crate::Lexer;
+use crate::expr::{BinaryOp, Expr, ExprKind, UnaryOp};
+use crate::provenance;
+use crate::token::{Span, Token, TokenKind};
+use crate::value::{
+    Argument, Cfg, CfgBranch, CfgPredicate, Def, DirectiveCall, Env, EnvType, If, Include,
+    IncludeKind, Map, Number, Patch, PatchOp, Ref, Scope, Struct, Value,
+};
+use crate::{Lexer, ParseOptions};
 
 type RB = ReportBuilder<(String, Range<usize>)>;
 type Result<T> = std::result::Result<T, ReportBuilder<(String, Range<usize>)>>;
 
+const DIRECTIVES: &[&str] = &[
+    "include",
+    "include_glob",
+    "include_dir",
+    "include_str",
+    "include_bytes",
+    "env",
+    "defs",
+    "let",
+    "ref",
+    "expr",
+    "if",
+    "cfg",
+    "set",
+    "remove",
+    "append",
+    "prototype",
+];
+
+/// Builtin functions that can be called in expressions.
+const FUNCTIONS: &[&str] = &["min", "max", "cfg"];
+
 pub struct Parser {
     pub(crate) tokens: Vec<Token>,
     current: usize,
     errors: Vec<ReportBuilder<(String, Range<usize>)>>,
     source_path: String,
+    options: ParseOptions,
+    /// Spans of the values parsed so far by their path, if recording is enabled.
+    spans: Option<IndexMap<String, Span>>,
+    /// Path of the value that is being parsed.
+    path: String,
+    /// Number of directives that are being parsed. Spans are not recorded inside directives,
+    /// since their arguments are not part of the document.
+    directive_depth: usize,
 }
 
 impl Parser {
@@ -25,10 +60,32 @@ impl Parser {
             current: 0,
             errors,
             source_path: source_path.to_string(),
+            options: ParseOptions::default(),
+            spans: None,
+            path: String::new(),
+            directive_depth: 0,
         }
     }
 
-    pub fn parse(mut self) -> (Value, Vec<RB>) {
+    pub fn with_options(mut self, options: &ParseOptions) -> Parser {
+        self.options = options.clone();
+        self
+    }
+
+    /// Records the span of every value outside of directives, see [`Parser::parse_with_spans`].
+    pub(crate) fn with_spans(mut self) -> Parser {
+        self.spans = Some(IndexMap::new());
+        self
+    }
+
+    pub fn parse(self) -> (Value, Vec<RB>) {
+        let (value, errors, _) = self.parse_with_spans();
+        (value, errors)
+    }
+
+    /// Parses the source and returns the spans of the values by their path in the syntax of
+    /// [`crate::Provenance`], if enabled with [`Parser::with_spans`].
+    pub(crate) fn parse_with_spans(mut self) -> (Value, Vec<RB>, IndexMap<String, Span>) {
         let value = self.value();
         if !self.is_at_end() {
             self.errors.push(
@@ -39,10 +96,46 @@ impl Parser {
                     ),
             );
         }
-        (value, self.errors)
+        (value, self.errors, self.spans.unwrap_or_default())
     }
 
     fn value(&mut self) -> Value {
+        let start = self.pos();
+        let value = self.unspanned_value();
+        if self.directive_depth == 0 && self.current > 0 {
+            let end = self.previous().span.end.max(start);
+            if let Some(spans) = &mut self.spans {
+                spans.insert(self.path.clone(), Span { start, end });
+            }
+        }
+        value
+    }
+
+    /// Parses the value of the field `name` of the current value.
+    fn field_value<N: ToString>(&mut self, name: N) -> Value {
+        match self.spans {
+            Some(_) => self.value_at(provenance::field(&self.path, &name.to_string())),
+            None => self.value(),
+        }
+    }
+
+    /// Parses the element at `index` of the current value.
+    fn element_value(&mut self, index: usize) -> Value {
+        match self.spans {
+            Some(_) => self.value_at(provenance::element(&self.path, index)),
+            None => self.value(),
+        }
+    }
+
+    /// Parses the value at `path`, relative to the path of the current value.
+    fn value_at(&mut self, path: String) -> Value {
+        let parent = std::mem::replace(&mut self.path, path);
+        let value = self.value();
+        self.path = parent;
+        value
+    }
+
+    fn unspanned_value(&mut self) -> Value {
         let val = match self.peek().kind {
             TokenKind::Ident => {
                 let start = self.peek().span.start;
@@ -65,25 +158,16 @@ impl Parser {
                 Ok(Value::Option(None))
             }
             TokenKind::Number => {
-                let text = self.advance().text.clone();
-                match text.parse::<i64>() {
-                    Ok(int) => Ok(Value::Number(Number::Integer(int))),
-                    Err(_) => match text.parse::<f64>() {
-                        Ok(float) => Ok(Value::Number(Number::from(float))),
-                        Err(err) => {
-                            Err(self.error()
-                                .with_message(format!("Malformed number `{}`", text))
-                                .with_label(
-                                    self.label()
-                                        .with_message(""),
-                                )
-                                .with_note(format!("Failed to parse number: {}", err)))
-                        }
-                    },
-                }
+                let start = self.pos();
+                self.number()
+                    .and_then(|n| self.bare_expr(start, Value::Number(n)))
             }
-            TokenKind::String => self.string().map(Value::String),
-            TokenKind::Hash => self.include(),
+            TokenKind::String => {
+                let start = self.pos();
+                self.interpolated_string()
+                    .and_then(|s| self.bare_expr(start, s))
+            }
+            TokenKind::Hash => self.directive(),
             token => Err(self.error()
                 .with_message("Expected one of `\"`, `[`, `{`, `(`, `true`, `false`, `None`, <ident>, <number>")
                 .with_label(
@@ -101,11 +185,175 @@ impl Parser {
         }
     }
 
+    fn number(&mut self) -> Result<Number> {
+        let text = self.advance().text.clone();
+        match text.parse::<i64>() {
+            Ok(int) => Ok(Number::Integer(int)),
+            Err(_) => match text.parse::<f64>() {
+                Ok(float) => Ok(Number::from(float)),
+                Err(err) => Err(self
+                    .error()
+                    .with_message(format!("Malformed number `{}`", text))
+                    .with_label(self.label().with_message(""))
+                    .with_note(format!("Failed to parse number: {}", err))),
+            },
+        }
+    }
+
+    /// Continues parsing an expression such as `60 / 4` if `value` is followed by an operator.
+    fn bare_expr(&mut self, start: usize, value: Value) -> Result<Value> {
+        if self.binary_op().is_none() {
+            return Ok(value);
+        }
+        let lhs = match value {
+            Value::Expr(expr) => expr,
+            value => Expr {
+                kind: ExprKind::Value(Box::new(value)),
+                span: Span {
+                    start,
+                    end: self.previous().span.end,
+                },
+            },
+        };
+        Ok(Value::Expr(self.binary(lhs, 0)?))
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        let lhs = self.unary()?;
+        self.binary(lhs, 0)
+    }
+
+    /// Parses any binary operators following `lhs` that bind at least as tightly as
+    /// `min_precedence`.
+    fn binary(&mut self, mut lhs: Expr, min_precedence: u8) -> Result<Expr> {
+        while let Some(op) = self
+            .binary_op()
+            .filter(|op| op.precedence() >= min_precedence)
+        {
+            self.advance();
+            let rhs = self.unary()?;
+            let rhs = self.binary(rhs, op.precedence() + 1)?;
+            lhs = Expr {
+                span: Span {
+                    start: lhs.span.start,
+                    end: rhs.span.end,
+                },
+                kind: ExprKind::Binary(op, Box::new(lhs), Box::new(rhs)),
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn binary_op(&self) -> Option<BinaryOp> {
+        match self.peek().kind {
+            TokenKind::Plus => Some(BinaryOp::Add),
+            TokenKind::Minus => Some(BinaryOp::Sub),
+            TokenKind::Star => Some(BinaryOp::Mul),
+            TokenKind::Slash => Some(BinaryOp::Div),
+            TokenKind::Percent => Some(BinaryOp::Rem),
+            TokenKind::EqualEqual => Some(BinaryOp::Eq),
+            TokenKind::BangEqual => Some(BinaryOp::Ne),
+            TokenKind::Less => Some(BinaryOp::Lt),
+            TokenKind::LessEqual => Some(BinaryOp::Le),
+            TokenKind::Greater => Some(BinaryOp::Gt),
+            TokenKind::GreaterEqual => Some(BinaryOp::Ge),
+            TokenKind::AndAnd => Some(BinaryOp::And),
+            TokenKind::OrOr => Some(BinaryOp::Or),
+            _ => None,
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        let start = self.pos();
+        let kind = match self.peek().kind {
+            TokenKind::Minus => {
+                self.advance();
+                ExprKind::Unary(UnaryOp::Neg, Box::new(self.unary()?))
+            }
+            TokenKind::Bang => {
+                self.advance();
+                ExprKind::Unary(UnaryOp::Not, Box::new(self.unary()?))
+            }
+            TokenKind::Number => ExprKind::Value(Box::new(Value::Number(self.number()?))),
+            TokenKind::String => match self.interpolated_string()? {
+                Value::Expr(expr) => expr.kind,
+                value => ExprKind::Value(Box::new(value)),
+            },
+            TokenKind::True | TokenKind::False => ExprKind::Value(Box::new(Value::Bool(
+                self.advance().kind == TokenKind::True,
+            ))),
+            TokenKind::Hash => ExprKind::Value(Box::new(self.directive()?)),
+            TokenKind::LeftParen => {
+                self.advance();
+                let expr = self.expr()?;
+                self.require(TokenKind::RightParen)?;
+                expr.kind
+            }
+            TokenKind::Ident => {
+                let name = self.advance().text.clone();
+                if self.consume(TokenKind::LeftParen) {
+                    if !FUNCTIONS.contains(&name.as_str()) {
+                        return Err(self
+                            .error()
+                            .with_message(format!("Unknown function `{}`", name))
+                            .with_label(
+                                self.label_span(start..self.previous().span.start)
+                                    .with_message(format!(
+                                        "Expected one of {}",
+                                        FUNCTIONS
+                                            .iter()
+                                            .map(|f| format!("`{}`", f))
+                                            .collect::<Vec<_>>()
+                                            .join(", ")
+                                    )),
+                            ));
+                    }
+                    if name == "cfg" {
+                        let predicate = self.cfg_predicate()?;
+                        self.require(TokenKind::RightParen)?;
+                        return Ok(Expr {
+                            kind: ExprKind::Cfg(predicate),
+                            span: Span {
+                                start,
+                                end: self.previous().span.end,
+                            },
+                        });
+                    }
+                    let mut args = vec![];
+                    while self.peek().kind != TokenKind::RightParen {
+                        args.push(self.expr()?);
+                        if !self.consume(TokenKind::Comma) {
+                            break;
+                        }
+                    }
+                    self.require(TokenKind::RightParen)?;
+                    ExprKind::Call(name, args)
+                } else {
+                    let select = self.select()?;
+                    ExprKind::Name { name, select }
+                }
+            }
+            token => {
+                return Err(self.error().with_message("Expected expression").with_label(
+                    self.label()
+                        .with_message(format!("Unexpected token `{}`", token)),
+                ))
+            }
+        };
+        Ok(Expr {
+            kind,
+            span: Span {
+                start,
+                end: self.previous().span.end,
+            },
+        })
+    }
+
     fn struct_or_tuple(&mut self, start: usize, name: Option<String>) -> Result<Value> {
         if (self.check2(TokenKind::Ident) && self.check3(TokenKind::Colon))
             || (self.check2(TokenKind::Hash)
                 && self.check3(TokenKind::Ident)
-                && self.check_text3("prototype"))
+                && (self.check_text3("prototype") || self.check_text3("cfg")))
         {
             self.structure(start, name)
         } else {
@@ -116,10 +364,25 @@ impl Parser {
     fn structure(&mut self, start: usize, name: Option<String>) -> Result<Value> {
         let mut fields = IndexMap::default();
         let mut prototype = None;
+        let mut prototype_span = None;
 
         if self.consume(TokenKind::LeftParen) {
             loop {
-                if self.consume(TokenKind::Hash) {
+                if self.peek().kind == TokenKind::Hash
+                    && self.check2(TokenKind::Ident)
+                    && self.tokens[self.current + 1].text == "cfg"
+                {
+                    self.advance();
+                    self.advance();
+                    self.require(TokenKind::LeftParen)?;
+                    let predicate = self.cfg_predicate()?;
+                    self.require(TokenKind::RightParen)?;
+                    let field_name = self.require(TokenKind::Ident)?.text.clone();
+                    self.require(TokenKind::Colon)?;
+                    let value = self.field_value(&field_name);
+                    add_cfg_branch(&mut fields, field_name, Some(predicate), value);
+                } else if self.peek().kind == TokenKind::Hash {
+                    let hash = self.advance().span.start;
                     let text = self.ident()?;
                     if text != "prototype" {
                         return Err(self
@@ -134,11 +397,15 @@ impl Parser {
                     let path = self.string()?;
                     self.require(TokenKind::RightParen)?;
                     prototype = Some(path);
+                    prototype_span = Some(Span {
+                        start: hash,
+                        end: self.previous().span.end,
+                    });
                 } else {
                     let field_name = self.require(TokenKind::Ident)?.text.clone();
                     self.require(TokenKind::Colon)?;
-                    let value = self.value();
-                    fields.insert(field_name, value);
+                    let value = self.field_value(&field_name);
+                    add_cfg_branch(&mut fields, field_name, None, value);
                 }
                 if !self.consume(TokenKind::Comma) {
                     break;
@@ -167,6 +434,7 @@ impl Parser {
             name,
             fields,
             prototype,
+            prototype_span,
         }))
     }
 
@@ -177,7 +445,7 @@ impl Parser {
                 if self.peek().kind == TokenKind::RightParen {
                     break;
                 }
-                values.push(self.value());
+                values.push(self.element_value(values.len()));
                 if !self.consume(TokenKind::Comma) {
                     break;
                 }
@@ -196,9 +464,21 @@ impl Parser {
         self.require(TokenKind::LeftBrace)?;
         let mut fields = IndexMap::default();
         while self.peek().kind != TokenKind::RightBrace {
+            // Keys are not values of the document, so their spans are not recorded.
+            self.directive_depth += 1;
             let key = self.value();
+            self.directive_depth -= 1;
             self.require(TokenKind::Colon)?;
-            let value = self.value();
+            let value = match &key {
+                Value::String(key) => self.field_value(key),
+                Value::Number(Number::Integer(key)) => self.field_value(*key),
+                _ => {
+                    self.directive_depth += 1;
+                    let value = self.value();
+                    self.directive_depth -= 1;
+                    value
+                }
+            };
             fields.insert(key, value);
             if !self.consume(TokenKind::Comma) {
                 break;
@@ -233,7 +513,7 @@ impl Parser {
             if self.peek().kind == TokenKind::RightBracket {
                 break;
             }
-            values.push(self.value());
+            values.push(self.element_value(values.len()));
             if !self.consume(TokenKind::Comma) {
                 // TODO(clemens): recover from missing comma
                 break;
@@ -258,75 +538,513 @@ impl Parser {
         Ok(Value::Seq(values))
     }
 
-    fn include(&mut self) -> Result<Value> {
+    fn directive(&mut self) -> Result<Value> {
+        self.directive_depth += 1;
+        let value = self.directive_body();
+        self.directive_depth -= 1;
+        value
+    }
+
+    fn directive_body(&mut self) -> Result<Value> {
         let start = self.pos();
         self.require(TokenKind::Hash)?;
         match self.ident()?.as_ref() {
-            "include" => {
+            "include" => self.include(start, IncludeKind::File),
+            "include_glob" => self.include(start, IncludeKind::Glob),
+            "include_dir" => self.include(start, IncludeKind::Dir),
+            "include_str" => self.include(start, IncludeKind::Str),
+            "include_bytes" => self.include(start, IncludeKind::Bytes),
+            "env" => self.env(start),
+            "defs" => self.defs(),
+            "let" => self.let_(),
+            "ref" => self.reference(start),
+            "expr" => {
                 self.require(TokenKind::LeftParen)?;
-                let path = self.string()?;
+                let expr = self.expr()?;
                 self.require(TokenKind::RightParen)?;
-                Ok(Value::Include(path))
+                Ok(Value::Expr(expr))
             }
+            "if" => self.if_(start),
+            "set" | "remove" | "append" => self.patch(start),
+            "cfg" => Err(self
+                .error()
+                .with_message("Unexpected #cfg directive")
+                .with_label(self.label_span(start..self.peek().span.end).with_message(
+                    "Expected value but found `#cfg`. `#cfg` can only precede struct fields.",
+                ))),
             "prototype" => Err(self
                 .error()
                 .with_message("Unexpected #prototype directive")
                 .with_label(self.label_span(start..self.peek().span.end).with_message(
                     "Expected value but found `#prototype`. Only structs can have prototypes.",
                 ))),
+            ident
+                if self.options.keep_unknown_directives
+                    || self.options.directives.iter().any(|d| d == ident) =>
+            {
+                self.custom_directive(start)
+            }
             ident => Err(self
                 .error()
                 .with_message(format!(
-                    "Unknown directive `#{}`. Valid directives are `include` and `prototype`.",
-                    ident
+                    "Unknown directive `#{}`. Valid directives are {}.",
+                    ident,
+                    DIRECTIVES
+                        .iter()
+                        .copied()
+                        .chain(self.options.directives.iter().map(String::as_str))
+                        .map(|d| format!("`{}`", d))
+                        .collect::<Vec<_>>()
+                        .join(", ")
                 ))
                 .with_label(self.label())),
         }
     }
 
+    fn include(&mut self, start: usize, kind: IncludeKind) -> Result<Value> {
+        let directive = self.previous().text.clone();
+        self.require(TokenKind::LeftParen)?;
+        let path = self.string()?;
+        let mut select = None;
+        if self.consume(TokenKind::Comma) && self.peek().kind != TokenKind::RightParen {
+            let arg = self.ident()?;
+            match kind {
+                IncludeKind::Str | IncludeKind::Bytes => {
+                    return Err(self.unknown_argument(&directive, &arg, "no further arguments"))
+                }
+                _ if arg != "path" => return Err(self.unknown_argument(&directive, &arg, "`path`")),
+                _ => {}
+            }
+            self.require(TokenKind::Colon)?;
+            select = Some(self.string()?);
+            self.consume(TokenKind::Comma);
+        }
+        self.require(TokenKind::RightParen)?;
+        Ok(Value::Include(Include {
+            kind,
+            path,
+            select,
+            span: Span {
+                start,
+                end: self.previous().span.end,
+            },
+        }))
+    }
+
+    fn env(&mut self, start: usize) -> Result<Value> {
+        self.require(TokenKind::LeftParen)?;
+        let name = self.string()?;
+        let mut default = None;
+        let mut ty = None;
+        while self.consume(TokenKind::Comma) && self.peek().kind != TokenKind::RightParen {
+            let arg = self.ident()?;
+            self.require(TokenKind::Colon)?;
+            match arg.as_ref() {
+                "default" => default = Some(Box::new(self.value())),
+                "type" => {
+                    ty = Some(match self.ident()?.as_ref() {
+                        "string" => EnvType::String,
+                        "number" => EnvType::Number,
+                        "bool" => EnvType::Bool,
+                        other => {
+                            return Err(self
+                                .error()
+                                .with_message(format!("Unknown type `{}`", other))
+                                .with_label(
+                                    self.label_span(
+                                        self.previous().span.start..self.previous().span.end,
+                                    )
+                                    .with_message("Expected `string`, `number` or `bool`"),
+                                ))
+                        }
+                    })
+                }
+                _ => return Err(self.unknown_argument("env", &arg, "`default` or `type`")),
+            }
+        }
+        self.require(TokenKind::RightParen)?;
+        let ty = ty.unwrap_or(match default.as_deref() {
+            Some(Value::Number(_)) => EnvType::Number,
+            Some(Value::Bool(_)) => EnvType::Bool,
+            _ => EnvType::String,
+        });
+        Ok(Value::Env(Env {
+            name,
+            default,
+            ty,
+            span: Span {
+                start,
+                end: self.previous().span.end,
+            },
+        }))
+    }
+
+    fn defs(&mut self) -> Result<Value> {
+        self.require(TokenKind::LeftParen)?;
+        let mut defs: Vec<Def> = vec![];
+        while self.peek().kind != TokenKind::RightParen {
+            let name = self.ident()?;
+            let span = self.previous().span;
+            if let Some(previous) = defs.iter().find(|def| def.name == name) {
+                return Err(self
+                    .error()
+                    .with_message(format!("Duplicate definition of `{}`", name))
+                    .with_label(
+                        self.label_span(span.start..span.end)
+                            .with_message("Defined again here"),
+                    )
+                    .with_label(
+                        self.label_span(previous.span.start..previous.span.end)
+                            .with_message("First defined here"),
+                    ));
+            }
+            self.require(TokenKind::Colon)?;
+            let value = self.value();
+            defs.push(Def { name, value, span });
+            if !self.consume(TokenKind::Comma) {
+                break;
+            }
+        }
+        self.require(TokenKind::RightParen)?;
+        Ok(Value::Scope(Scope {
+            defs,
+            value: Box::new(self.value()),
+        }))
+    }
+
+    fn let_(&mut self) -> Result<Value> {
+        self.require(TokenKind::LeftParen)?;
+        let name = self.ident()?;
+        let span = self.previous().span;
+        self.require(TokenKind::Comma)?;
+        let value = self.value();
+        self.consume(TokenKind::Comma);
+        self.require(TokenKind::RightParen)?;
+        Ok(Value::Scope(Scope {
+            defs: vec![Def { name, value, span }],
+            value: Box::new(self.value()),
+        }))
+    }
+
+    fn reference(&mut self, start: usize) -> Result<Value> {
+        self.require(TokenKind::LeftParen)?;
+        let name = self.ident()?;
+        let select = self.select()?;
+        self.require(TokenKind::RightParen)?;
+        Ok(Value::Ref(Ref {
+            name,
+            select,
+            span: Span {
+                start,
+                end: self.previous().span.end,
+            },
+        }))
+    }
+
+    /// Parses the arguments of a custom directive registered in [`ParseOptions::directives`], or
+    /// of an unknown directive if [`ParseOptions::keep_unknown_directives`] is set.
+    fn custom_directive(&mut self, start: usize) -> Result<Value> {
+        let name = self.previous().text.clone();
+        self.require(TokenKind::LeftParen)?;
+        let mut args = vec![];
+        while self.peek().kind != TokenKind::RightParen {
+            let arg_start = self.pos();
+            let arg_name = if self.peek().kind == TokenKind::Ident && self.check2(TokenKind::Colon)
+            {
+                let arg_name = self.advance().text.clone();
+                self.advance();
+                Some(arg_name)
+            } else {
+                None
+            };
+            let value = self.value();
+            args.push(Argument {
+                name: arg_name,
+                value,
+                span: Span {
+                    start: arg_start,
+                    end: self.previous().span.end,
+                },
+            });
+            if !self.consume(TokenKind::Comma) {
+                break;
+            }
+        }
+        self.require(TokenKind::RightParen)?;
+        Ok(Value::Directive(DirectiveCall {
+            name,
+            args,
+            span: Span {
+                start,
+                end: self.previous().span.end,
+            },
+        }))
+    }
+
+    /// Parses `#if(condition, then, otherwise)` after the directive name.
+    fn if_(&mut self, start: usize) -> Result<Value> {
+        self.require(TokenKind::LeftParen)?;
+        let condition = self.expr()?;
+        self.require(TokenKind::Comma)?;
+        let then = self.value();
+        self.require(TokenKind::Comma)?;
+        let otherwise = self.value();
+        self.consume(TokenKind::Comma);
+        self.require(TokenKind::RightParen)?;
+        Ok(Value::If(If {
+            condition,
+            then: Box::new(then),
+            otherwise: Box::new(otherwise),
+            span: Span {
+                start,
+                end: self.previous().span.end,
+            },
+        }))
+    }
+
+    /// Parses `#set("path", value)`, `#remove("path")` or `#append("path", value)` after the
+    /// directive name.
+    fn patch(&mut self, start: usize) -> Result<Value> {
+        let directive = self.previous().text.clone();
+        self.require(TokenKind::LeftParen)?;
+        let path = self.string()?;
+        let op = if directive == "remove" {
+            PatchOp::Remove
+        } else {
+            self.require(TokenKind::Comma)?;
+            let value = Box::new(self.value());
+            match directive.as_str() {
+                "set" => PatchOp::Set(value),
+                _ => PatchOp::Append(value),
+            }
+        };
+        self.consume(TokenKind::Comma);
+        self.require(TokenKind::RightParen)?;
+        Ok(Value::Patch(Patch {
+            op,
+            path,
+            span: Span {
+                start,
+                end: self.previous().span.end,
+            },
+        }))
+    }
+
+    /// Parses a predicate such as `debug`, `"release-lto"` or `all(server, not(debug))`.
+    fn cfg_predicate(&mut self) -> Result<CfgPredicate> {
+        if self.peek().kind == TokenKind::String {
+            return Ok(CfgPredicate::Name(self.string()?));
+        }
+        let name = self.ident()?;
+        if !matches!(name.as_str(), "not" | "all" | "any") || !self.consume(TokenKind::LeftParen) {
+            return Ok(CfgPredicate::Name(name));
+        }
+        let mut predicates = vec![];
+        while self.peek().kind != TokenKind::RightParen {
+            predicates.push(self.cfg_predicate()?);
+            if !self.consume(TokenKind::Comma) {
+                break;
+            }
+        }
+        self.require(TokenKind::RightParen)?;
+        match name.as_str() {
+            "all" => Ok(CfgPredicate::All(predicates)),
+            "any" => Ok(CfgPredicate::Any(predicates)),
+            _ if predicates.len() == 1 => Ok(CfgPredicate::Not(Box::new(predicates.remove(0)))),
+            _ => Err(self
+                .error()
+                .with_message("`not` expects one predicate")
+                .with_label(
+                    self.label_span(self.previous().span.start..self.previous().span.end)
+                        .with_message(format!("Found {} predicates", predicates.len())),
+                )),
+        }
+    }
+
+    /// Parses a selection path such as `.stats[0].hp` following a name.
+    fn select(&mut self) -> Result<Option<String>> {
+        let mut select = String::new();
+        loop {
+            if self.consume(TokenKind::Dot) {
+                if !select.is_empty() {
+                    select.push('.');
+                }
+                select.push_str(&self.ident()?);
+            } else if self.consume(TokenKind::LeftBracket) {
+                let index = self.require(TokenKind::Number)?.text.clone();
+                select.push_str(&format!("[{}]", index));
+                self.require(TokenKind::RightBracket)?;
+            } else {
+                break;
+            }
+        }
+        Ok(Some(select).filter(|s| !s.is_empty()))
+    }
+
+    /// Error for an unsupported named argument. Expects the argument name to be the previous token.
+    fn unknown_argument(&self, directive: &str, arg: &str, expected: &str) -> RB {
+        self.error()
+            .with_message(format!("Unknown argument `{}` to `#{}`", arg, directive))
+            .with_label(
+                self.label_span(self.previous().span.start..self.previous().span.end)
+                    .with_message(format!("Expected {}", expected)),
+            )
+    }
+
     fn string(&mut self) -> Result<String> {
+        let parts = self.string_parts(false)?;
+        Ok(parts
+            .into_iter()
+            .map(|part| match part.kind {
+                ExprKind::Value(value) => value.to_plain_string(),
+                _ => unreachable!(),
+            })
+            .collect())
+    }
+
+    /// Parses a string literal that may contain `${...}` placeholders. Strings without
+    /// placeholders are returned as plain strings.
+    fn interpolated_string(&mut self) -> Result<Value> {
+        let span = self.peek().span;
+        let parts = self.string_parts(self.options.interpolate)?;
+        if let [Expr {
+            kind: ExprKind::Value(value),
+            ..
+        }] = parts.as_slice()
+        {
+            if let Value::String(string) = &**value {
+                return Ok(Value::String(string.clone()));
+            }
+        }
+        Ok(Value::Expr(Expr {
+            kind: ExprKind::Format(parts),
+            span,
+        }))
+    }
+
+    /// Splits a string literal into literal parts and, if `interpolate` is set, placeholders.
+    /// Spans of the parts are character offsets into the source.
+    fn string_parts(&mut self, interpolate: bool) -> Result<Vec<Expr>> {
         // TODO: unicode escapes, 7bit character codes
         self.consume(TokenKind::String);
-        let mut string = String::new();
-        let mut escaped = false;
         let text = self.previous().text.clone();
-        for (i, char) in text[1..text.len() - 1].chars().enumerate() {
-            if escaped {
-                match char {
-                    'n' => string.push('\n'),
-                    'r' => string.push('\r'),
-                    't' => string.push('\t'),
-                    '\\' => string.push('\\'),
-                    '"' => string.push('"'),
-                    '0' => string.push('\0'),
-                    _ => {
+        let start = self.previous().span.start + 1;
+        let chars = text[1..text.len() - 1].chars().collect::<Vec<_>>();
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut literal_start = start;
+        let mut i = 0;
+        while i < chars.len() {
+            if chars[i] == '\\' && i + 1 < chars.len() {
+                match chars[i + 1] {
+                    'n' => literal.push('\n'),
+                    'r' => literal.push('\r'),
+                    't' => literal.push('\t'),
+                    '\\' => literal.push('\\'),
+                    '"' => literal.push('"'),
+                    '0' => literal.push('\0'),
+                    '$' => literal.push('$'),
+                    char => {
                         self.errors.push(
                             Report::build(
                                 ReportKind::Error,
                                 self.source_path.to_string(),
-                                self.peek().span.start + i - 1,
+                                start + i,
                             )
                             .with_message(format!("unknown character escape: `\\{}`", char))
-                            .with_label(
-                                self.label_span(
-                                    self.peek().span.start + i - 1..self.peek().span.start + i,
-                                )
-                                .with_message(""),
-                            )
+                            .with_label(self.label_span(start + i..start + i + 2).with_message(""))
                             .with_note(
-                                "Valid escape sequences are: `\\n`, `\\r`, `\\t`, `\\\"`, `\\0`",
+                                "Valid escape sequences are: `\\n`, `\\r`, `\\t`, `\\\"`, `\\0`, `\\$`",
                             ),
                         );
                     }
                 }
-                escaped = false;
-            } else if char == '\\' {
-                escaped = true;
+                i += 2;
+            } else if interpolate && chars[i] == '$' && chars.get(i + 1) == Some(&'{') {
+                let len = match chars[i + 2..].iter().position(|&c| c == '}') {
+                    Some(len) => len,
+                    None => {
+                        return Err(Report::build(
+                            ReportKind::Error,
+                            self.source_path.to_string(),
+                            start + i,
+                        )
+                        .with_message("Unterminated placeholder")
+                        .with_label(
+                            self.label_span(start + i..start + chars.len())
+                                .with_message("Expected `}` to close this placeholder"),
+                        )
+                        .with_note("Write `\\$` for a literal `$`"))
+                    }
+                };
+                if !literal.is_empty() {
+                    parts.push(Expr {
+                        kind: ExprKind::Value(Box::new(Value::String(std::mem::take(
+                            &mut literal,
+                        )))),
+                        span: Span {
+                            start: literal_start,
+                            end: start + i,
+                        },
+                    });
+                }
+                let inner = chars[i + 2..i + 2 + len].iter().collect::<String>();
+                let span = Span {
+                    start: start + i,
+                    end: start + i + len + 3,
+                };
+                parts.push(self.placeholder(&inner, span)?);
+                i += len + 3;
+                literal_start = start + i;
             } else {
-                string.push(char);
+                literal.push(chars[i]);
+                i += 1;
             }
         }
-        Ok(string)
+        if !literal.is_empty() || parts.is_empty() {
+            parts.push(Expr {
+                kind: ExprKind::Value(Box::new(Value::String(literal))),
+                span: Span {
+                    start: literal_start,
+                    end: start + chars.len(),
+                },
+            });
+        }
+        Ok(parts)
+    }
+
+    /// Parses the contents of a `${...}` placeholder, which is either a name followed by an
+    /// optional selection path or `env:` followed by the name of an environment variable.
+    fn placeholder(&self, inner: &str, span: Span) -> Result<Expr> {
+        let text = inner.trim();
+        let kind = if let Some(name) = text.strip_prefix("env:") {
+            let name = name.trim();
+            (!name.is_empty() && !name.contains(char::is_whitespace)).then(|| {
+                ExprKind::Value(Box::new(Value::Env(Env {
+                    name: name.to_string(),
+                    default: None,
+                    ty: EnvType::String,
+                    span,
+                })))
+            })
+        } else {
+            let (name, select) = text.split_at(text.find(['.', '[']).unwrap_or(text.len()));
+            (is_ident(name) && is_select_path(select)).then(|| ExprKind::Name {
+                name: name.to_string(),
+                select: Some(select.trim_start_matches('.').to_string()).filter(|s| !s.is_empty()),
+            })
+        };
+        match kind {
+            Some(kind) => Ok(Expr { kind, span }),
+            None => Err(
+                Report::build(ReportKind::Error, self.source_path.to_string(), span.start)
+                    .with_message(format!("Invalid placeholder `${{{}}}`", inner))
+                    .with_label(self.label_span(span.start..span.end).with_message(
+                        "Expected a name such as `${name}`, `${stats.hp}` or `${env:HOME}`",
+                    ))
+                    .with_note("Write `\\$` for a literal `$`"),
+            ),
+        }
     }
 
     fn check2(&self, kind: TokenKind) -> bool {
@@ -421,3 +1139,65 @@ impl Parser {
         ))
     }
 }
+
+fn is_ident(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some('_' | 'a'..='z' | 'A'..='Z'))
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+/// Checks that `text` is a sequence of `.field` and `[index]` segments.
+fn is_select_path(mut text: &str) -> bool {
+    while !text.is_empty() {
+        if let Some(rest) = text.strip_prefix('.') {
+            let end = rest.find(['.', '[']).unwrap_or(rest.len());
+            if !is_ident(&rest[..end]) {
+                return false;
+            }
+            text = &rest[end..];
+        } else if let Some(rest) = text.strip_prefix('[') {
+            match rest.split_once(']') {
+                Some((index, rest)) if index.parse::<usize>().is_ok() => text = rest,
+                _ => return false,
+            }
+        } else {
+            return false;
+        }
+    }
+    true
+}
+
+/// Adds a declaration of a struct field. Fields declared once without `#cfg` are stored
+/// directly, all others are collected into a `Value::Cfg`.
+fn add_cfg_branch(
+    fields: &mut IndexMap<String, Value>,
+    name: String,
+    predicate: Option<CfgPredicate>,
+    value: Value,
+) {
+    let branch = CfgBranch { predicate, value };
+    match fields.get_mut(&name) {
+        Some(Value::Cfg(cfg)) => cfg.branches.push(branch),
+        Some(existing) if branch.predicate.is_some() => {
+            let previous = CfgBranch {
+                predicate: None,
+                value: std::mem::replace(existing, Value::Unit),
+            };
+            *existing = Value::Cfg(Cfg {
+                branches: vec![previous, branch],
+            });
+        }
+        Some(existing) => *existing = branch.value,
+        None if branch.predicate.is_some() => {
+            fields.insert(
+                name,
+                Value::Cfg(Cfg {
+                    branches: vec![branch],
+                }),
+            );
+        }
+        None => {
+            fields.insert(name, branch.value);
+        }
+    }
+}
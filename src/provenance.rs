@@ -0,0 +1,165 @@
+use std::path::PathBuf;
+
+use indexmap::IndexMap;
+
+use crate::token::Span;
+
+/// Where each value of a loaded document was declared, recorded when
+/// [`crate::LoadOptions::provenance`] is set.
+///
+/// Values are identified by their path, using the syntax of `#include` selections: `goblin.hp`
+/// for struct fields and map entries with string or integer keys, `spells[0]` for elements of
+/// lists and tuples, and the empty string for the document itself.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Provenance {
+    origins: IndexMap<String, Origin>,
+}
+
+/// The declaration of a value.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Origin {
+    /// Canonical path of the file that contains the declaration.
+    pub file: PathBuf,
+    /// Location of the declaration in `file`. For values produced by a directive such as `#if`
+    /// or `#ref`, this is the location of the directive.
+    pub span: Span,
+    pub kind: OriginKind,
+}
+
+/// How a value became part of the loaded document.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OriginKind {
+    /// The value is declared in the loaded document.
+    Declared,
+    /// The value is declared in a file that was included with `#include` or one of its variants.
+    Included,
+    /// The value is a field inherited from a `#prototype`.
+    Inherited,
+    /// The value was set or appended by an overlay.
+    Overlay,
+}
+
+impl Provenance {
+    /// Returns the origin of the value at `path`. Values without an origin of their own, such as
+    /// the contents of an `#if` branch, return the origin of the closest enclosing value.
+    pub fn origin(&self, path: &str) -> Option<&Origin> {
+        let mut path = path;
+        loop {
+            if let Some(origin) = self.origins.get(path) {
+                return Some(origin);
+            }
+            if path.is_empty() {
+                return None;
+            }
+            path = parent(path);
+        }
+    }
+
+    /// Iterates over all recorded origins, in declaration order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Origin)> {
+        self.origins
+            .iter()
+            .map(|(path, origin)| (path.as_str(), origin))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.origins.is_empty()
+    }
+
+    pub(crate) fn insert(&mut self, path: String, origin: Origin) {
+        self.origins.insert(path, origin);
+    }
+
+    /// Records the origins of `other` for the value at `path`. Values that `other` declares
+    /// itself are marked with `kind`.
+    pub(crate) fn splice(&mut self, path: &str, other: &Provenance, kind: OriginKind) {
+        self.remove(path);
+        for (inner, origin) in &other.origins {
+            let mut origin = origin.clone();
+            if origin.kind == OriginKind::Declared {
+                origin.kind = kind;
+            }
+            self.origins.insert(join(path, inner), origin);
+        }
+    }
+
+    /// Returns the origins of the value at `path`, relative to that value.
+    pub(crate) fn subtree(&self, path: &str) -> Provenance {
+        let mut origins = IndexMap::new();
+        if let Some(origin) = self.origin(path) {
+            origins.insert(String::new(), origin.clone());
+        }
+        for (inner, origin) in &self.origins {
+            if let Some(relative) = strip_path_prefix(inner, path) {
+                origins.insert(relative.to_string(), origin.clone());
+            }
+        }
+        Provenance { origins }
+    }
+
+    /// Removes the origins of the value at `path` and all values inside of it.
+    pub(crate) fn remove(&mut self, path: &str) {
+        self.origins
+            .retain(|inner, _| strip_path_prefix(inner, path).is_none());
+    }
+
+    /// Updates the paths of the elements after `path[index]` after that element was removed.
+    pub(crate) fn remove_element(&mut self, path: &str, index: usize) {
+        self.remove(&format!("{}[{}]", path, index));
+        let prefix = format!("{}[", path);
+        self.origins = std::mem::take(&mut self.origins)
+            .into_iter()
+            .map(|(inner, origin)| {
+                let shifted = inner.strip_prefix(&prefix).and_then(|rest| {
+                    let (i, rest) = rest.split_once(']')?;
+                    let i = i.parse::<usize>().ok().filter(|&i| i > index)?;
+                    Some(format!("{}{}]{}", prefix, i - 1, rest))
+                });
+                (shifted.unwrap_or(inner), origin)
+            })
+            .collect();
+    }
+}
+
+/// Returns the path of the field `name` of the value at `path`.
+pub(crate) fn field(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", path, name)
+    }
+}
+
+/// Returns the path of the element `index` of the value at `path`.
+pub(crate) fn element(path: &str, index: usize) -> String {
+    format!("{}[{}]", path, index)
+}
+
+/// Appends the relative path `inner` to `path`.
+fn join(path: &str, inner: &str) -> String {
+    if path.is_empty() || inner.is_empty() || inner.starts_with('[') {
+        format!("{}{}", path, inner)
+    } else {
+        format!("{}.{}", path, inner)
+    }
+}
+
+/// Returns `inner` relative to `path` if it is `path` or a path inside of it.
+fn strip_path_prefix<'a>(inner: &'a str, path: &str) -> Option<&'a str> {
+    if path.is_empty() {
+        return Some(inner);
+    }
+    let rest = inner.strip_prefix(path)?;
+    if rest.is_empty() || rest.starts_with('[') {
+        Some(rest)
+    } else {
+        rest.strip_prefix('.')
+    }
+}
+
+fn parent(path: &str) -> &str {
+    match path.strip_suffix(']').and_then(|p| p.rfind('[')) {
+        Some(i) => &path[..i],
+        None => path.rfind('.').map_or("", |i| &path[..i]),
+    }
+}
@@ -1,12 +1,21 @@
 use std::fs::File;
 use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 use ariadne::Config;
 use indexmap::indexmap;
 
-use crate::load;
+use crate::expr::{BinaryOp, Expr, ExprKind};
 use crate::parser::Parser;
-use crate::value::{Map, Number, Struct, Value};
+use crate::token::Span;
+use crate::value::{Argument, DirectiveCall, Include, IncludeKind, Map, Number, Struct, Value};
+use crate::{
+    load, load_with_options, load_with_overlays, parse, parse_with_options, Diagnostic, Directive,
+    DirectiveContext, EdgeKind, FileSystem, LoadOptions, Loader, MemoryFs, OriginKind, Parse,
+    ParseOptions, Sandbox, StdFs, Watcher,
+};
 
 static SIMPLE_STRUCT: &str = r#"
 Config(
@@ -20,6 +29,7 @@ fn test_simple_struct() {
         name: Some("Config".to_string()),
         fields: indexmap! {"version".to_string() => Value::Number(Number::Integer(1))},
         prototype: None,
+        prototype_span: None,
     });
     test_parse(SIMPLE_STRUCT, expected);
 }
@@ -41,6 +51,7 @@ Config(
 fn test_unit_struct() {
     let expected = Value::Struct(Struct {
         prototype: None,
+        prototype_span: None,
         name: Some("Config".to_string()),
         fields: indexmap! {"version".to_string() => Value::Number(Number::from(1)), "foo".to_string() => Value::Tuple(Some("bar".to_string()), vec![])},
     });
@@ -118,6 +129,7 @@ fn test_struct_with_all_types() {
                         ]),
                     },
                     prototype:None,
+                    prototype_span: None,
                 }) => Value::String("bar".to_string()),
             })),
             "tuple".to_string() => Value::Tuple(None, vec![
@@ -129,6 +141,7 @@ fn test_struct_with_all_types() {
             "none".to_string() => Value::Option(None),
         },
         prototype: None,
+        prototype_span: None,
     });
     test_parse(STRUCT_WITH_ALL_TYPES, expected);
 }
@@ -185,12 +198,30 @@ fn test_include() {
     let expected = Value::Struct(Struct {
         name: Some("GoblinWizard".to_string()),
         prototype: Some("goblin.ron".to_string()),
+        prototype_span: None,
         fields: indexmap! {
             "name".to_string() => Value::String("Goblin Wizard".to_string()),
-            "spells".to_string() => Value::Include("spells.ron".to_string()),
+            "spells".to_string() => Value::Include(Include {
+                kind: IncludeKind::File,
+                path: "spells.ron".to_string(),
+                select: None,
+                span: Span { start: 0, end: 0 },
+            }),
         },
     });
     test_parse_with_includes(INCLUDE, expected);
+
+    // The location of an include does not affect comparisons, so it can be used as a map key.
+    let map = parse(
+        r#"{#include("a.ron"): 1, #include("a.ron"): 2, #include("b.ron"): 3}"#,
+        None,
+    )
+    .ok()
+    .unwrap();
+    match map {
+        Value::Map(map) => assert_eq!(map.len(), 2),
+        value => panic!("Expected map, found {:?}", value),
+    }
 }
 
 static LARGE: &str = r#"
@@ -271,8 +302,9 @@ XpV0(
 fn test_large() {
     let large_expected = Value::Struct(Struct {
         prototype: None,
+        prototype_span: None,
         name: Some("XpV0".to_string()),
-        fields: indexmap! {"project".to_string() => Value::String("dcc".to_string()), "containers".to_string() => Value::Map(Map(indexmap!{Value::String("trainer".to_string()) => Value::Struct(Struct{prototype:None, name:None, fields: indexmap!{"command".to_string() => Value::Seq(vec![Value::String("python".to_string()), Value::String("main.py".to_string())]), "env_secrets".to_string() => Value::Map(Map(indexmap!{Value::String("WANDB_API_KEY".to_string()) => Value::String("wandb-api-key".to_string())})), "replicas".to_string() => Value::Number(Number::from(1)), "gpu".to_string() => Value::Number(Number::from(1)), "gpu_mem".to_string() => Value::String("5GB".to_string()), "volumes".to_string() => Value::Map(Map(indexmap!{Value::String("/mnt/a/Dropbox/artifacts/xprun".to_string()) => Value::String("/mnt/xprun".to_string())})), "build".to_string() => Value::Seq(vec![Value::Tuple(Some("From".to_string()), vec![Value::String("nvcr.io/nvidia/pytorch:21.03-py3".to_string())]), Value::Tuple(Some("Run".to_string()), vec![Value::String("apt-get update".to_string())]), Value::Tuple(Some("Run".to_string()), vec![Value::String("apt-get install curl build-essential --yes".to_string())]), Value::Tuple(Some("Run".to_string()), vec![Value::String("curl --proto '=https' --tlsv1.2 -sSf https://sh.rustup.rs | sh -s -- -y".to_string())]), Value::Tuple(Some("Env".to_string()), vec![Value::String("PATH".to_string()), Value::String("/root/.cargo/bin:${PATH}".to_string())]), Value::Tuple(Some("Run".to_string()), vec![Value::String("pip install --upgrade pip".to_string())]), Value::Tuple(Some("Run".to_string()), vec![Value::String("pip install maturin".to_string())]), Value::Struct(Struct{prototype:None, name:Some("Repo".to_string()), fields: indexmap!{"url".to_string() => Value::String("git@github.com:cswinter/xprun.git".to_string()), "rev".to_string() => Value::String("eb59b24".to_string()), "cd".to_string() => Value::Bool(true)} }), Value::Tuple(Some("Run".to_string()), vec![Value::String("maturin build --cargo-extra-args=--features=python".to_string())]), Value::Tuple(Some("Run".to_string()), vec![Value::String("pip install target/wheels/xprun-0.1.0-cp38-cp38-manylinux_2_27_x86_64.whl".to_string())]), Value::Struct(Struct{prototype:None, name:Some("Repo".to_string()), fields: indexmap!{"url".to_string() => Value::String("git@github.com:cswinter/pyron.git".to_string()), "rev".to_string() => Value::String("23825de".to_string()), "cd".to_string() => Value::Bool(true)} }), Value::Tuple(Some("Run".to_string()), vec![Value::String("maturin build".to_string())]), Value::Tuple(Some("Run".to_string()), vec![Value::String("pip install target/wheels/pyron-0.1.0-cp38-cp38-manylinux_2_24_x86_64.whl".to_string())]), Value::Struct(Struct{prototype:None, name:Some("Repo".to_string()), fields: indexmap!{"path".to_string() => Value::String("requirements.txt".to_string()), "cd".to_string() => Value::Bool(true), "rm".to_string() => Value::Bool(true)} }), Value::Tuple(Some("Run".to_string()), vec![Value::String("pip install -r requirements.txt".to_string())]), Value::Struct(Struct{prototype:None, name:Some("Repo".to_string()), fields: indexmap!{"url".to_string() => Value::String("git@github.com:cswinter/hyperstate.git".to_string()), "rev".to_string() => Value::String("77893bf".to_string()), "cd".to_string() => Value::Bool(true)} }), Value::Tuple(Some("Run".to_string()), vec![Value::String("pip install -e .".to_string())]), Value::Struct(Struct{prototype:None, name:Some("Repo".to_string()), fields: indexmap!{"cd".to_string() => Value::Bool(true)} })])} }), Value::String("codecraftserver".to_string()) => Value::Struct(Struct{prototype:None, name:None, fields: indexmap!{"command".to_string() => Value::Seq(vec![Value::String("server-0.1.0-SNAPSHOT/bin/server".to_string()), Value::String("-Dplay.http.secret.key=ad31779d4ee49d5ad5162bf1429c32e2e9933f3b".to_string())]), "cpu".to_string() => Value::Number(Number::from(4)), "cpu_mem".to_string() => Value::String("20GiB".to_string()), "tty".to_string() => Value::Bool(true), "env".to_string() => Value::Map(Map(indexmap!{Value::String("SBT_OPTS".to_string()) => Value::String("-Xmx10G".to_string())})), "build".to_string() => Value::Seq(vec![Value::Tuple(Some("From".to_string()), vec![Value::String("hseeberger/scala-sbt:8u222_1.3.5_2.13.1".to_string())]), Value::Struct(Struct{prototype:None, name:Some("Repo".to_string()), fields: indexmap!{"url".to_string() => Value::String("https://github.com/cswinter/CodeCraftGame.git".to_string()), "rev".to_string() => Value::String("92304eb".to_string()), "cd".to_string() => Value::Bool(true), "rm".to_string() => Value::Bool(true)} }), Value::Tuple(Some("Run".to_string()), vec![Value::String("sbt publishLocal".to_string())]), Value::Struct(Struct{prototype:None, name:Some("Repo".to_string()), fields: indexmap!{"url".to_string() => Value::String("https://github.com/cswinter/CodeCraftServer.git".to_string()), "rev".to_string() => Value::String("df76892".to_string()), "cd".to_string() => Value::Bool(true), "rm".to_string() => Value::Bool(true)} }), Value::Tuple(Some("Run".to_string()), vec![Value::String("sbt compile".to_string())]), Value::Struct(Struct{prototype:None, name:Some("Repo".to_string()), fields: indexmap!{"url".to_string() => Value::String("https://github.com/cswinter/CodeCraftGame.git".to_string()), "rev".to_string() => Value::String("edc5a9f2".to_string()), "cd".to_string() => Value::Bool(true)} }), Value::Tuple(Some("Run".to_string()), vec![Value::String("sbt publishLocal".to_string())]), Value::Struct(Struct{prototype:None, name:Some("Repo".to_string()), fields: indexmap!{"url".to_string() => Value::String("https://github.com/cswinter/CodeCraftServer.git".to_string()), "rev".to_string() => Value::String("302a379".to_string()), "cd".to_string() => Value::Bool(true)} }), Value::Tuple(Some("Run".to_string()), vec![Value::String("sbt dist".to_string())]), Value::Tuple(Some("Run".to_string()), vec![Value::String("unzip server/target/universal/server-0.1.0-SNAPSHOT.zip".to_string())])])} })}))},
+        fields: indexmap! {"project".to_string() => Value::String("dcc".to_string()), "containers".to_string() => Value::Map(Map(indexmap!{Value::String("trainer".to_string()) => Value::Struct(Struct{prototype:None, prototype_span:None, name:None, fields: indexmap!{"command".to_string() => Value::Seq(vec![Value::String("python".to_string()), Value::String("main.py".to_string())]), "env_secrets".to_string() => Value::Map(Map(indexmap!{Value::String("WANDB_API_KEY".to_string()) => Value::String("wandb-api-key".to_string())})), "replicas".to_string() => Value::Number(Number::from(1)), "gpu".to_string() => Value::Number(Number::from(1)), "gpu_mem".to_string() => Value::String("5GB".to_string()), "volumes".to_string() => Value::Map(Map(indexmap!{Value::String("/mnt/a/Dropbox/artifacts/xprun".to_string()) => Value::String("/mnt/xprun".to_string())})), "build".to_string() => Value::Seq(vec![Value::Tuple(Some("From".to_string()), vec![Value::String("nvcr.io/nvidia/pytorch:21.03-py3".to_string())]), Value::Tuple(Some("Run".to_string()), vec![Value::String("apt-get update".to_string())]), Value::Tuple(Some("Run".to_string()), vec![Value::String("apt-get install curl build-essential --yes".to_string())]), Value::Tuple(Some("Run".to_string()), vec![Value::String("curl --proto '=https' --tlsv1.2 -sSf https://sh.rustup.rs | sh -s -- -y".to_string())]), Value::Tuple(Some("Env".to_string()), vec![Value::String("PATH".to_string()), Value::String("/root/.cargo/bin:${PATH}".to_string())]), Value::Tuple(Some("Run".to_string()), vec![Value::String("pip install --upgrade pip".to_string())]), Value::Tuple(Some("Run".to_string()), vec![Value::String("pip install maturin".to_string())]), Value::Struct(Struct{prototype:None, prototype_span:None, name:Some("Repo".to_string()), fields: indexmap!{"url".to_string() => Value::String("git@github.com:cswinter/xprun.git".to_string()), "rev".to_string() => Value::String("eb59b24".to_string()), "cd".to_string() => Value::Bool(true)} }), Value::Tuple(Some("Run".to_string()), vec![Value::String("maturin build --cargo-extra-args=--features=python".to_string())]), Value::Tuple(Some("Run".to_string()), vec![Value::String("pip install target/wheels/xprun-0.1.0-cp38-cp38-manylinux_2_27_x86_64.whl".to_string())]), Value::Struct(Struct{prototype:None, prototype_span:None, name:Some("Repo".to_string()), fields: indexmap!{"url".to_string() => Value::String("git@github.com:cswinter/pyron.git".to_string()), "rev".to_string() => Value::String("23825de".to_string()), "cd".to_string() => Value::Bool(true)} }), Value::Tuple(Some("Run".to_string()), vec![Value::String("maturin build".to_string())]), Value::Tuple(Some("Run".to_string()), vec![Value::String("pip install target/wheels/pyron-0.1.0-cp38-cp38-manylinux_2_24_x86_64.whl".to_string())]), Value::Struct(Struct{prototype:None, prototype_span:None, name:Some("Repo".to_string()), fields: indexmap!{"path".to_string() => Value::String("requirements.txt".to_string()), "cd".to_string() => Value::Bool(true), "rm".to_string() => Value::Bool(true)} }), Value::Tuple(Some("Run".to_string()), vec![Value::String("pip install -r requirements.txt".to_string())]), Value::Struct(Struct{prototype:None, prototype_span:None, name:Some("Repo".to_string()), fields: indexmap!{"url".to_string() => Value::String("git@github.com:cswinter/hyperstate.git".to_string()), "rev".to_string() => Value::String("77893bf".to_string()), "cd".to_string() => Value::Bool(true)} }), Value::Tuple(Some("Run".to_string()), vec![Value::String("pip install -e .".to_string())]), Value::Struct(Struct{prototype:None, prototype_span:None, name:Some("Repo".to_string()), fields: indexmap!{"cd".to_string() => Value::Bool(true)} })])} }), Value::String("codecraftserver".to_string()) => Value::Struct(Struct{prototype:None, prototype_span:None, name:None, fields: indexmap!{"command".to_string() => Value::Seq(vec![Value::String("server-0.1.0-SNAPSHOT/bin/server".to_string()), Value::String("-Dplay.http.secret.key=ad31779d4ee49d5ad5162bf1429c32e2e9933f3b".to_string())]), "cpu".to_string() => Value::Number(Number::from(4)), "cpu_mem".to_string() => Value::String("20GiB".to_string()), "tty".to_string() => Value::Bool(true), "env".to_string() => Value::Map(Map(indexmap!{Value::String("SBT_OPTS".to_string()) => Value::String("-Xmx10G".to_string())})), "build".to_string() => Value::Seq(vec![Value::Tuple(Some("From".to_string()), vec![Value::String("hseeberger/scala-sbt:8u222_1.3.5_2.13.1".to_string())]), Value::Struct(Struct{prototype:None, prototype_span:None, name:Some("Repo".to_string()), fields: indexmap!{"url".to_string() => Value::String("https://github.com/cswinter/CodeCraftGame.git".to_string()), "rev".to_string() => Value::String("92304eb".to_string()), "cd".to_string() => Value::Bool(true), "rm".to_string() => Value::Bool(true)} }), Value::Tuple(Some("Run".to_string()), vec![Value::String("sbt publishLocal".to_string())]), Value::Struct(Struct{prototype:None, prototype_span:None, name:Some("Repo".to_string()), fields: indexmap!{"url".to_string() => Value::String("https://github.com/cswinter/CodeCraftServer.git".to_string()), "rev".to_string() => Value::String("df76892".to_string()), "cd".to_string() => Value::Bool(true), "rm".to_string() => Value::Bool(true)} }), Value::Tuple(Some("Run".to_string()), vec![Value::String("sbt compile".to_string())]), Value::Struct(Struct{prototype:None, prototype_span:None, name:Some("Repo".to_string()), fields: indexmap!{"url".to_string() => Value::String("https://github.com/cswinter/CodeCraftGame.git".to_string()), "rev".to_string() => Value::String("edc5a9f2".to_string()), "cd".to_string() => Value::Bool(true)} }), Value::Tuple(Some("Run".to_string()), vec![Value::String("sbt publishLocal".to_string())]), Value::Struct(Struct{prototype:None, prototype_span:None, name:Some("Repo".to_string()), fields: indexmap!{"url".to_string() => Value::String("https://github.com/cswinter/CodeCraftServer.git".to_string()), "rev".to_string() => Value::String("302a379".to_string()), "cd".to_string() => Value::Bool(true)} }), Value::Tuple(Some("Run".to_string()), vec![Value::String("sbt dist".to_string())]), Value::Tuple(Some("Run".to_string()), vec![Value::String("unzip server/target/universal/server-0.1.0-SNAPSHOT.zip".to_string())])])} })}))},
     });
     test_parse(LARGE, large_expected);
 }
@@ -288,6 +320,7 @@ fn test_struct() {
                 "y".to_string() => Value::Number(Number::Integer(7)),
             },
             prototype: None,
+            prototype_span: None,
         }),
     );
     test_parse(
@@ -299,6 +332,7 @@ fn test_struct() {
                 "y".to_string() => Value::Number(Number::Integer(7)),
             },
             prototype: None,
+            prototype_span: None,
         }),
     );
     test_parse(
@@ -455,11 +489,1985 @@ fn test_resolve() {
     let value = load(tmp_dir.path().join("config.ron")).unwrap().value;
     let expected = Value::Struct(Struct {
         prototype: None,
+        prototype_span: None,
         name: Some("GoblinWizard".to_string()),
-        fields: indexmap! {"name".to_string() => Value::String("Goblin Wizard".to_string()), "spells".to_string() => Value::Seq(vec![Value::Struct(Struct{prototype:None, name:Some("Spell".to_string()), fields: indexmap!{"name".to_string() => Value::String("Fireball".to_string()), "damage".to_string() => Value::Number(Number::from(10)), "manaCost".to_string() => Value::Number(Number::from(5))} }), Value::Struct(Struct{prototype:None, name:Some("Spell".to_string()), fields: indexmap!{"name".to_string() => Value::String("Lightning Bolt".to_string()), "damage".to_string() => Value::Number(Number::from(15)), "manaCost".to_string() => Value::Number(Number::from(10))} })]), "minHealth".to_string() => Value::Number(Number::from(10)), "maxHealth".to_string() => Value::Number(Number::from(20)), "resists".to_string() => Value::Seq(vec![Value::String("fire".to_string()), Value::String("cold".to_string())]), "weaknesses".to_string() => Value::Seq(vec![Value::String("lightning".to_string()), Value::String("poison".to_string())])},
+        fields: indexmap! {"name".to_string() => Value::String("Goblin Wizard".to_string()), "spells".to_string() => Value::Seq(vec![Value::Struct(Struct{prototype:None, prototype_span:None, name:Some("Spell".to_string()), fields: indexmap!{"name".to_string() => Value::String("Fireball".to_string()), "damage".to_string() => Value::Number(Number::from(10)), "manaCost".to_string() => Value::Number(Number::from(5))} }), Value::Struct(Struct{prototype:None, prototype_span:None, name:Some("Spell".to_string()), fields: indexmap!{"name".to_string() => Value::String("Lightning Bolt".to_string()), "damage".to_string() => Value::Number(Number::from(15)), "manaCost".to_string() => Value::Number(Number::from(10))} })]), "minHealth".to_string() => Value::Number(Number::from(10)), "maxHealth".to_string() => Value::Number(Number::from(20)), "resists".to_string() => Value::Seq(vec![Value::String("fire".to_string()), Value::String("cold".to_string())]), "weaknesses".to_string() => Value::Seq(vec![Value::String("lightning".to_string()), Value::String("poison".to_string())])},
     });
     if value != expected {
         println!("{}", value.fmt_as_rust());
     }
     assert_eq!(value, expected)
 }
+
+static INCLUDE_SELECT: &str = r#"
+Goblin(
+    stats: #include("monsters.ron", path: "goblin.stats"),
+    firstSpell: #include("monsters.ron", path: "wizard.spells[0]"),
+    mana: #include("monsters.ron", path: "goblin.mana"),
+)
+"#;
+
+static MONSTERS: &str = r#"
+{
+    "goblin": (
+        name: "Goblin",
+        stats: (hp: 20, speed: 3),
+    ),
+    "wizard": (
+        spells: ["fireball", "lightning"],
+    ),
+}
+"#;
+
+#[test]
+fn test_include_select() {
+    test_parse(
+        r#"#include("monsters.ron", path: "goblin.stats")"#,
+        Value::Include(Include {
+            kind: IncludeKind::File,
+            path: "monsters.ron".to_string(),
+            select: Some("goblin.stats".to_string()),
+            span: Span { start: 0, end: 46 },
+        }),
+    );
+
+    let tmp_dir = write_files(&[("config.ron", INCLUDE_SELECT), ("monsters.ron", MONSTERS)]);
+    let parse = load(tmp_dir.path().join("config.ron")).unwrap();
+    let expected = Value::Struct(Struct {
+        prototype: None,
+        prototype_span: None,
+        name: Some("Goblin".to_string()),
+        fields: indexmap! {
+            "stats".to_string() => Value::Struct(Struct {
+                prototype: None,
+                prototype_span: None,
+                name: None,
+                fields: indexmap! {
+                    "hp".to_string() => Value::Number(Number::from(20)),
+                    "speed".to_string() => Value::Number(Number::from(3)),
+                },
+            }),
+            "firstSpell".to_string() => Value::String("fireball".to_string()),
+            "mana".to_string() => Value::Unit,
+        },
+    });
+    assert_eq!(parse.value, expected);
+    assert_eq!(parse.errors.len(), 1);
+    let errors = render_errors(&parse);
+    assert!(errors.contains("Path `goblin.mana` not found in `monsters.ron`"));
+    assert!(errors.contains("No entry `mana` in `goblin` of `monsters.ron`"));
+    assert!(errors.contains("Available keys: `name`, `stats`"));
+}
+
+static SPELLBOOK: &str = r#"
+(
+    list: #include_glob("spells/*.ron"),
+    byName: #include_dir("spells/*.ron", path: "damage"),
+)
+"#;
+
+#[test]
+fn test_include_glob() {
+    let tmp_dir = write_files(&[
+        ("spellbook.ron", SPELLBOOK),
+        ("spells/lightning.ron", "Spell(damage: 15)"),
+        ("spells/fireball.ron", "Spell(damage: 10)"),
+        ("spells/notes.txt", "not a spell"),
+    ]);
+    let parse = load(tmp_dir.path().join("spellbook.ron")).unwrap();
+    let spell = |damage: i64| {
+        Value::Struct(Struct {
+            prototype: None,
+            prototype_span: None,
+            name: Some("Spell".to_string()),
+            fields: indexmap! {"damage".to_string() => Value::Number(Number::from(damage))},
+        })
+    };
+    let expected = Value::Struct(Struct {
+        prototype: None,
+        prototype_span: None,
+        name: None,
+        fields: indexmap! {
+            "list".to_string() => Value::Seq(vec![spell(10), spell(15)]),
+            "byName".to_string() => Value::Map(Map(indexmap! {
+                Value::String("fireball".to_string()) => Value::Number(Number::from(10)),
+                Value::String("lightning".to_string()) => Value::Number(Number::from(15)),
+            })),
+        },
+    });
+    assert!(parse.errors.is_empty());
+    assert_eq!(parse.value, expected);
+
+    // Directories that cannot be listed are reported like missing ones.
+    let source =
+        r#"(a: #include_glob("spells/notes.txt/*.ron"), b: #include_dir("missing/*.ron"))"#;
+    let mut file = File::create(tmp_dir.path().join("broken.ron")).unwrap();
+    file.write_all(source.as_bytes()).unwrap();
+    let parse = load(tmp_dir.path().join("broken.ron")).unwrap();
+    assert_eq!(parse.errors.len(), 2);
+    let errors = render_errors(&parse);
+    assert!(
+        errors.contains("Cannot list the directory `spells/notes.txt`"),
+        "{}",
+        errors
+    );
+    assert!(errors.contains("Cannot find `missing`"), "{}", errors);
+    assert_eq!(parse.value.get_path("a"), Some(&Value::Seq(vec![])));
+    assert_eq!(parse.value.get_path("b"), Some(&Value::Map(Map::new())));
+}
+
+#[test]
+fn test_include_str_and_bytes() {
+    let tmp_dir = write_files(&[
+        (
+            "item.ron",
+            r#"Item(description: #include_str("desc.txt"), icon: #include_bytes("icon.bin"))"#,
+        ),
+        ("desc.txt", "A rusty sword.\n"),
+        ("icon.bin", "\x01\x02\x7f"),
+    ]);
+    let parse = load(tmp_dir.path().join("item.ron")).unwrap();
+    let expected = Value::Struct(Struct {
+        prototype: None,
+        prototype_span: None,
+        name: Some("Item".to_string()),
+        fields: indexmap! {
+            "description".to_string() => Value::String("A rusty sword.\n".to_string()),
+            "icon".to_string() => Value::Bytes(vec![1, 2, 127]),
+        },
+    });
+    assert!(parse.errors.is_empty());
+    assert_eq!(parse.value, expected);
+    let dependencies = parse
+        .dependencies
+        .iter()
+        .map(|path| path.file_name().unwrap().to_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(dependencies, vec!["item.ron", "desc.txt", "icon.bin"]);
+}
+
+static ENV: &str = r#"
+Server(
+    url: #env("RON_PARSER_TEST_URL"),
+    port: #env("RON_PARSER_TEST_PORT", default: 8080),
+    verbose: #env("RON_PARSER_TEST_VERBOSE", type: bool),
+    workers: #env("RON_PARSER_TEST_WORKERS", default: 4),
+)
+"#;
+
+#[test]
+fn test_env() {
+    std::env::set_var("RON_PARSER_TEST_URL", "postgres://localhost");
+    std::env::set_var("RON_PARSER_TEST_VERBOSE", "true");
+    std::env::set_var("RON_PARSER_TEST_WORKERS", "16");
+    let tmp_dir = write_files(&[("server.ron", ENV)]);
+
+    let parse = load(tmp_dir.path().join("server.ron")).unwrap();
+    let expected = Value::Struct(Struct {
+        prototype: None,
+        prototype_span: None,
+        name: Some("Server".to_string()),
+        fields: indexmap! {
+            "url".to_string() => Value::String("postgres://localhost".to_string()),
+            "port".to_string() => Value::Number(Number::from(8080)),
+            "verbose".to_string() => Value::Bool(true),
+            "workers".to_string() => Value::Number(Number::from(16)),
+        },
+    });
+    assert!(parse.errors.is_empty());
+    assert_eq!(parse.value, expected);
+
+    let options = LoadOptions {
+        allow_env: false,
+        ..LoadOptions::default()
+    };
+    let parse = load_with_options(tmp_dir.path().join("server.ron"), &options).unwrap();
+    assert_eq!(parse.errors.len(), 4);
+    assert!(render_errors(&parse).contains("Environment variables are disabled"));
+}
+
+#[test]
+fn test_env_missing() {
+    let tmp_dir = write_files(&[("config.ron", r#"(key: #env("RON_PARSER_TEST_MISSING"))"#)]);
+    let parse = load(tmp_dir.path().join("config.ron")).unwrap();
+    assert_eq!(parse.errors.len(), 1);
+    let errors = render_errors(&parse);
+    assert!(errors.contains("Environment variable `RON_PARSER_TEST_MISSING` is not set"));
+}
+
+static DEFS: &str = r#"
+#defs(
+    BASE_HP: 20,
+    CONSTANTS: #include("constants.ron"),
+)
+Goblin(
+    minHealth: #ref(BASE_HP),
+    speed: #ref(CONSTANTS.speeds[1]),
+    boss: #let(BASE_HP, 100) (
+        maxHealth: #ref(BASE_HP),
+        armor: #ref(ARMOR),
+    ),
+)
+"#;
+
+#[test]
+fn test_defs() {
+    let tmp_dir = write_files(&[
+        ("goblin.ron", DEFS),
+        ("constants.ron", "(speeds: [1, 3, 5])"),
+    ]);
+    let parse = load(tmp_dir.path().join("goblin.ron")).unwrap();
+    let expected = Value::Struct(Struct {
+        prototype: None,
+        prototype_span: None,
+        name: Some("Goblin".to_string()),
+        fields: indexmap! {
+            "minHealth".to_string() => Value::Number(Number::from(20)),
+            "speed".to_string() => Value::Number(Number::from(3)),
+            "boss".to_string() => Value::Struct(Struct {
+                prototype: None,
+                prototype_span: None,
+                name: None,
+                fields: indexmap! {
+                    "maxHealth".to_string() => Value::Number(Number::from(100)),
+                    "armor".to_string() => Value::Unit,
+                },
+            }),
+        },
+    });
+    assert_eq!(parse.value, expected);
+    assert_eq!(parse.errors.len(), 1);
+    assert_eq!(parse.warnings.len(), 1);
+    let errors = render_errors(&parse);
+    assert!(errors.contains("Definition of `BASE_HP` shadows an outer definition"));
+    assert!(errors.contains("Undefined name `ARMOR`"));
+    assert!(errors.contains("Defined names: `BASE_HP`, `CONSTANTS`"));
+
+    // Warnings do not make a file invalid, and are reported again when it comes from the cache.
+    let tmp_dir = write_files(&[("shadow.ron", "#defs(A: 1) (a: #let(A, 2) #ref(A))")]);
+    let path = tmp_dir.path().join("shadow.ron");
+    let reads = Arc::new(AtomicUsize::new(0));
+    let mut loader =
+        Loader::with_file_system(LoadOptions::default(), CountingFs(Arc::clone(&reads)));
+    for _ in 0..2 {
+        let parse = loader.load(&path).unwrap();
+        assert!(parse.errors.is_empty());
+        assert_eq!(parse.warnings.len(), 1);
+    }
+    assert_eq!(reads.load(Ordering::SeqCst), 1);
+    let mut watcher = Watcher::new(loader);
+    assert!(watcher.watch(&path).1.is_ok());
+}
+
+static EXPR: &str = r#"
+#defs(BASE_HP: 20)
+Goblin(
+    maxHealth: #expr(BASE_HP * 1.5),
+    cooldown: 60 / 4,
+    slow: 7 / 2,
+    mana: 10 - 2 * 3,
+    negative: #expr(-(BASE_HP + 1) -4),
+    capped: #expr(min(BASE_HP * 2, 30)),
+    title: "Goblin " + "King " + 2,
+    armor: #expr(maxHealth / 10),
+    broken: #expr(BASE_HP / (2 - 2)),
+    invalid: #expr("fire" * 2),
+    overflow: #expr((0 - 9223372036854775807 - 1) / -1),
+    offsets: [-1, -2.5e-1, -.5],
+    gap: 4 -1,
+)
+"#;
+
+#[test]
+fn test_expr() {
+    test_parse(
+        "60 / 4",
+        Value::Expr(Expr {
+            kind: ExprKind::Binary(
+                BinaryOp::Div,
+                Box::new(Expr {
+                    kind: ExprKind::Value(Box::new(Value::Number(Number::from(60)))),
+                    span: Span { start: 0, end: 2 },
+                }),
+                Box::new(Expr {
+                    kind: ExprKind::Value(Box::new(Value::Number(Number::from(4)))),
+                    span: Span { start: 5, end: 6 },
+                }),
+            ),
+            span: Span { start: 0, end: 6 },
+        }),
+    );
+
+    let tmp_dir = write_files(&[("goblin.ron", EXPR)]);
+    let parse = load(tmp_dir.path().join("goblin.ron")).unwrap();
+    let expected = Value::Struct(Struct {
+        prototype: None,
+        prototype_span: None,
+        name: Some("Goblin".to_string()),
+        fields: indexmap! {
+            "maxHealth".to_string() => Value::Number(Number::from(30.0)),
+            "cooldown".to_string() => Value::Number(Number::from(15)),
+            "slow".to_string() => Value::Number(Number::from(3.5)),
+            "mana".to_string() => Value::Number(Number::from(4)),
+            "negative".to_string() => Value::Number(Number::from(-25)),
+            "capped".to_string() => Value::Number(Number::from(30)),
+            "title".to_string() => Value::String("Goblin King 2".to_string()),
+            "armor".to_string() => Value::Number(Number::from(3.0)),
+            "broken".to_string() => Value::Unit,
+            "invalid".to_string() => Value::Unit,
+            "overflow".to_string() => Value::Unit,
+            "offsets".to_string() => Value::Seq(vec![
+                Value::Number(Number::from(-1)),
+                Value::Number(Number::from(-0.25)),
+                Value::Number(Number::from(-0.5)),
+            ]),
+            "gap".to_string() => Value::Number(Number::from(3)),
+        },
+    });
+    assert_eq!(parse.value, expected);
+    assert_eq!(parse.errors.len(), 3);
+    let errors = render_errors(&parse);
+    assert!(errors.contains("Division by zero"));
+    assert!(errors.contains("Cannot apply `*` to a string and a number"));
+    assert!(errors.contains("Integer overflow"));
+}
+
+#[test]
+fn test_directives_in_tuples() {
+    let fs = MemoryFs::new()
+        .with_file(
+            "a.ron",
+            r#"(
+    a: Some(#expr(1 + 2)),
+    b: (1, #expr(2 + 3)),
+    c: Point(#include("c.ron")),
+    d: Some(Wind(#if(true, 1, 2))),
+)"#,
+        )
+        .with_file("c.ron", "4");
+    let options = LoadOptions {
+        provenance: true,
+        ..LoadOptions::default()
+    };
+    let parse = Loader::with_file_system(options, fs).load("a.ron").unwrap();
+    assert!(parse.errors.is_empty(), "{}", render_errors(&parse));
+    assert!(parse.warnings.is_empty(), "{}", render_errors(&parse));
+    let expected = crate::parse(
+        "(a: Some(3), b: (1, 5), c: Point(4), d: Some(Wind(1)))",
+        None,
+    )
+    .ok()
+    .unwrap();
+    assert_eq!(parse.value, expected);
+    assert!(parse.value.clone().try_into_ron().is_ok());
+    let origin = parse.provenance.origin("c[0]").unwrap();
+    assert_eq!(origin.kind, OriginKind::Included);
+}
+
+static INTERPOLATION: &str = r#"
+#defs(SCHOOL: "Fire", STATS: (level: 12))
+Wizard(
+    #prototype("base.ron"),
+    title: "${name} the Wizard",
+    summary: "Level ${STATS.level} ${SCHOOL} mage (${name})",
+    home: "${env:RON_PARSER_TEST_HOME}",
+    price: "\${price}",
+    dollar: "$5 {or} so",
+    levels: {"${SCHOOL}": "Level ${STATS.level}"},
+)
+"#;
+
+#[test]
+fn test_interpolation() {
+    let input = r#""${name} the ${title}""#;
+    let options = ParseOptions {
+        interpolate: true,
+        ..ParseOptions::default()
+    };
+    let parser = Parser::new(input, "<unknown>").with_options(&options);
+    let (val, errors) = parser.parse();
+    assert!(errors.is_empty());
+    let name = |name: &str, start, end| Expr {
+        kind: ExprKind::Name {
+            name: name.to_string(),
+            select: None,
+        },
+        span: Span { start, end },
+    };
+    assert_eq!(
+        val,
+        Value::Expr(Expr {
+            kind: ExprKind::Format(vec![
+                name("name", 1, 8),
+                Expr {
+                    kind: ExprKind::Value(Box::new(Value::String(" the ".to_string()))),
+                    span: Span { start: 8, end: 13 },
+                },
+                name("title", 13, 21),
+            ]),
+            span: Span { start: 0, end: 22 },
+        })
+    );
+    test_parse(input, Value::String("${name} the ${title}".to_string()));
+
+    std::env::set_var("RON_PARSER_TEST_HOME", "/home/gandalf");
+    let tmp_dir = write_files(&[
+        ("base.ron", r#"Wizard(name: "Gandalf", level: 3)"#),
+        ("gandalf.ron", INTERPOLATION),
+    ]);
+    let options = LoadOptions {
+        interpolate: true,
+        ..LoadOptions::default()
+    };
+    let parse = load_with_options(tmp_dir.path().join("gandalf.ron"), &options).unwrap();
+    let expected = Value::Struct(Struct {
+        prototype: None,
+        prototype_span: None,
+        name: Some("Wizard".to_string()),
+        fields: indexmap! {
+            "title".to_string() => Value::String("Gandalf the Wizard".to_string()),
+            "summary".to_string() => Value::String("Level 12 Fire mage (Gandalf)".to_string()),
+            "home".to_string() => Value::String("/home/gandalf".to_string()),
+            "price".to_string() => Value::String("${price}".to_string()),
+            "dollar".to_string() => Value::String("$5 {or} so".to_string()),
+            "levels".to_string() => Value::Map(Map(indexmap! {
+                Value::String("Fire".to_string()) => Value::String("Level 12".to_string()),
+            })),
+            "name".to_string() => Value::String("Gandalf".to_string()),
+            "level".to_string() => Value::Number(Number::from(3)),
+        },
+    });
+    assert!(parse.errors.is_empty(), "{}", render_errors(&parse));
+    assert_eq!(parse.value, expected);
+
+    // Placeholders are kept verbatim unless interpolation is enabled.
+    let parse = load(tmp_dir.path().join("gandalf.ron")).unwrap();
+    assert!(parse.errors.is_empty(), "{}", render_errors(&parse));
+    assert!(matches!(&parse.value, Value::Struct(s)
+        if s.fields["title"] == Value::String("${name} the Wizard".to_string())));
+
+    let tmp_dir = write_files(&[(
+        "broken.ron",
+        r#"#defs(STATS: (level: 1), S: "a") (a: "${nmae}", b: "${name.}", c: "${STATS}", d: "${unterminated", e: {"${S}": 1, "a": 2})"#,
+    )]);
+    let parse = load_with_options(tmp_dir.path().join("broken.ron"), &options).unwrap();
+    let errors = render_errors(&parse);
+    assert_eq!(parse.errors.len(), 5, "{}", errors);
+    assert!(errors.contains("Duplicate key `a`"));
+    assert!(errors.contains("Undefined name `nmae`"));
+    assert!(errors.contains("Invalid placeholder `${name.}`"));
+    assert!(errors.contains("Unterminated placeholder"));
+    assert!(errors.contains("Cannot interpolate a struct"));
+}
+
+static PROFILES: &str = r#"
+Config(
+    #prototype("base.ron"),
+    tick_rate: #if(profile == "debug", 1, 60),
+    log_level: "info",
+    #cfg(debug) log_level: "trace",
+    #cfg(any(server, not(debug))) threads: 8,
+    assets: #if(cfg(all(debug, hot_reload)), "assets/", #include("assets.ron")),
+    fast: #if(tick_rate >= 30 && !cfg(debug), true, false),
+)
+"#;
+
+#[test]
+fn test_profiles() {
+    let tmp_dir = write_files(&[
+        ("base.ron", r#"Config(threads: 1, name: "game")"#),
+        ("assets.ron", r#""packed.bin""#),
+        ("config.ron", PROFILES),
+    ]);
+    let config = |fields: Vec<(&str, Value)>| {
+        Value::Struct(Struct {
+            prototype: None,
+            prototype_span: None,
+            name: Some("Config".to_string()),
+            fields: fields
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+        })
+    };
+
+    let options = LoadOptions {
+        profile: Some("debug".to_string()),
+        flags: vec!["hot_reload".to_string()],
+        ..LoadOptions::default()
+    };
+    let parse = load_with_options(tmp_dir.path().join("config.ron"), &options).unwrap();
+    assert!(parse.errors.is_empty(), "{}", render_errors(&parse));
+    // `threads` has no matching declaration and is inherited from the prototype.
+    let expected = config(vec![
+        ("tick_rate", Value::Number(Number::from(1))),
+        ("log_level", Value::String("trace".to_string())),
+        ("assets", Value::String("assets/".to_string())),
+        ("fast", Value::Bool(false)),
+        ("threads", Value::Number(Number::from(1))),
+        ("name", Value::String("game".to_string())),
+    ]);
+    assert_eq!(parse.value, expected);
+    assert_eq!(parse.dependencies.len(), 2);
+
+    let options = LoadOptions {
+        profile: Some("release".to_string()),
+        ..LoadOptions::default()
+    };
+    let parse = load_with_options(tmp_dir.path().join("config.ron"), &options).unwrap();
+    assert!(parse.errors.is_empty(), "{}", render_errors(&parse));
+    let expected = config(vec![
+        ("tick_rate", Value::Number(Number::from(60))),
+        ("log_level", Value::String("info".to_string())),
+        ("threads", Value::Number(Number::from(8))),
+        ("assets", Value::String("packed.bin".to_string())),
+        ("fast", Value::Bool(true)),
+        ("name", Value::String("game".to_string())),
+    ]);
+    assert_eq!(parse.value, expected);
+
+    let tmp_dir = write_files(&[("broken.ron", r#"(x: #if(1 + 1, 1, 2))"#)]);
+    let parse = load(tmp_dir.path().join("broken.ron")).unwrap();
+    assert_eq!(parse.errors.len(), 1);
+    assert!(render_errors(&parse).contains("Condition of `#if` must be a bool"));
+}
+
+static OVERLAY: &str = r#"
+[
+    #set("port", 443),
+    #set("limits.download", 100),
+    #remove("debug"),
+    #append("plugins", "metrics"),
+    #set("prot", 1),
+    #append("host", "example.com"),
+    #remove("plugins[3]"),
+]
+"#;
+
+#[test]
+fn test_overlays() {
+    let tmp_dir = write_files(&[
+        (
+            "base.ron",
+            r#"Server(host: "localhost", port: 8080, debug: true, plugins: ["auth"], limits: {"upload": 10})"#,
+        ),
+        ("prod.patch.ron", OVERLAY),
+    ]);
+    let parse = load_with_overlays(
+        tmp_dir.path().join("base.ron"),
+        &[tmp_dir.path().join("prod.patch.ron")],
+    )
+    .unwrap();
+    let expected = Value::Struct(Struct {
+        prototype: None,
+        prototype_span: None,
+        name: Some("Server".to_string()),
+        fields: indexmap! {
+            "host".to_string() => Value::String("localhost".to_string()),
+            "port".to_string() => Value::Number(Number::from(443)),
+            "plugins".to_string() => Value::Seq(vec![
+                Value::String("auth".to_string()),
+                Value::String("metrics".to_string()),
+            ]),
+            "limits".to_string() => Value::Map(Map(indexmap! {
+                Value::String("upload".to_string()) => Value::Number(Number::from(10)),
+                Value::String("download".to_string()) => Value::Number(Number::from(100)),
+            })),
+        },
+    });
+    assert_eq!(parse.value, expected);
+    let errors = render_errors(&parse);
+    assert_eq!(parse.errors.len(), 3, "{}", errors);
+    assert!(errors.contains("Path `prot` not found"));
+    assert!(errors.contains("Available keys: `host`, `port`, `plugins`, `limits`"));
+    assert!(errors.contains("Cannot append to a string"));
+    assert!(errors.contains("Path `plugins[3]` not found"));
+    assert_eq!(parse.dependencies.len(), 2);
+
+    let parse = load(tmp_dir.path().join("prod.patch.ron")).unwrap();
+    assert_eq!(parse.errors.len(), 7);
+    assert!(render_errors(&parse).contains("Patches can only be used in overlay files"));
+
+    let tmp_dir = write_files(&[
+        ("base.ron", "(hp: 3, spells: [1, 2])"),
+        ("list.ron", "[1, 2]"),
+        (
+            "broken.patch.ron",
+            r#"[#remove("hp."), #remove("spells."), #remove(""), #remove("hp.max"), #include("nested.ron")]"#,
+        ),
+        ("root.patch.ron", r#"#remove("")"#),
+        ("nested.ron", r#"#set("hp", 4)"#),
+    ]);
+    let parse = load_with_overlays(
+        tmp_dir.path().join("base.ron"),
+        &[tmp_dir.path().join("broken.patch.ron")],
+    )
+    .unwrap();
+    let errors = render_errors(&parse);
+    assert_eq!(parse.errors.len(), 5, "{}", errors);
+    assert!(errors.contains("Invalid path `hp.`"));
+    assert!(errors.contains("Invalid path `spells.`"));
+    assert!(errors.contains("Cannot remove the root value"));
+    assert!(errors.contains("Path `hp.max` not found"));
+    assert!(errors.contains("Patches can only be used in overlay files"));
+    assert!(matches!(&parse.value, Value::Struct(s) if s.fields.len() == 2));
+    let parse = load_with_overlays(
+        tmp_dir.path().join("list.ron"),
+        &[tmp_dir.path().join("root.patch.ron")],
+    )
+    .unwrap();
+    assert_eq!(parse.errors.len(), 1);
+    assert!(render_errors(&parse).contains("Cannot remove the root value"));
+}
+
+static CUSTOM_DIRECTIVES: &str = r##"
+#defs(ICON: "fire.png")
+Spell(
+    icon: #asset(#ref(ICON), preload: true),
+    color: #color("#ff8000"),
+    glow: #color("orange"),
+)
+"##;
+
+struct Asset;
+
+impl Directive for Asset {
+    fn resolve(
+        &self,
+        call: &DirectiveCall,
+        cx: &mut DirectiveContext,
+    ) -> Result<Value, Diagnostic> {
+        let path = match call.positional().next() {
+            Some(Argument {
+                value: Value::String(path),
+                ..
+            }) => path,
+            _ => return Err(cx.error(call.span, "`#asset` expects a path")),
+        };
+        let bytes = cx
+            .read(path)
+            .map_err(|err| cx.error(call.span, format!("Cannot read `{}`: {}", path, err)))?;
+        let preload = call.named("preload").map(|arg| arg.value.clone());
+        Ok(Value::Struct(Struct {
+            prototype: None,
+            prototype_span: None,
+            name: Some("Asset".to_string()),
+            fields: indexmap! {
+                "path".to_string() => Value::String(path.clone()),
+                "size".to_string() => Value::Number(Number::from(bytes.len() as i64)),
+                "preload".to_string() => preload.unwrap_or(Value::Bool(false)),
+            },
+        }))
+    }
+}
+
+fn color(call: &DirectiveCall, cx: &mut DirectiveContext) -> Result<Value, Diagnostic> {
+    let arg = &call.args[0];
+    let channels = match &arg.value {
+        Value::String(hex) if hex.len() == 7 && hex.starts_with('#') => (1..7)
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+            .collect::<Option<Vec<_>>>(),
+        _ => None,
+    };
+    match channels {
+        Some(channels) => Ok(Value::Tuple(
+            None,
+            channels
+                .into_iter()
+                .map(|c| Value::Number(Number::from(i64::from(c))))
+                .collect(),
+        )),
+        None => Err(cx
+            .error(arg.span, "Invalid color")
+            .with_note("Colors are written as `\"#rrggbb\"`")),
+    }
+}
+
+#[test]
+fn test_custom_directives() {
+    let tmp_dir = write_files(&[
+        ("spell.ron", CUSTOM_DIRECTIVES),
+        ("fire.png", "not really a png"),
+    ]);
+    let options = LoadOptions::default()
+        .directive("asset", Asset)
+        .directive("color", color);
+    let parse = load_with_options(tmp_dir.path().join("spell.ron"), &options).unwrap();
+    let expected = Value::Struct(Struct {
+        prototype: None,
+        prototype_span: None,
+        name: Some("Spell".to_string()),
+        fields: indexmap! {
+            "icon".to_string() => Value::Struct(Struct {
+                prototype: None,
+                prototype_span: None,
+                name: Some("Asset".to_string()),
+                fields: indexmap! {
+                    "path".to_string() => Value::String("fire.png".to_string()),
+                    "size".to_string() => Value::Number(Number::from(16)),
+                    "preload".to_string() => Value::Bool(true),
+                },
+            }),
+            "color".to_string() => Value::Tuple(None, vec![
+                Value::Number(Number::from(255)),
+                Value::Number(Number::from(128)),
+                Value::Number(Number::from(0)),
+            ]),
+            "glow".to_string() => Value::Unit,
+        },
+    });
+    assert_eq!(parse.value, expected);
+    assert_eq!(parse.errors.len(), 1);
+    assert!(render_errors(&parse).contains("Invalid color"));
+    assert_eq!(parse.dependencies.len(), 2);
+
+    let parse = load(tmp_dir.path().join("spell.ron")).unwrap();
+    assert!(render_errors(&parse).contains("Unknown directive `#asset`"));
+}
+
+#[test]
+fn test_unknown_directives() {
+    let input = r#"(a: #lookup("x", default: 3))"#;
+    let options = ParseOptions {
+        keep_unknown_directives: true,
+        ..ParseOptions::default()
+    };
+    let value = match parse_with_options(input, None, &options) {
+        Ok(value) => value,
+        Err(parse) => panic!("{}", render_errors(&parse)),
+    };
+    let expected = Value::Struct(Struct {
+        prototype: None,
+        prototype_span: None,
+        name: None,
+        fields: indexmap! {
+            "a".to_string() => Value::Directive(DirectiveCall {
+                name: "lookup".to_string(),
+                args: vec![
+                    Argument {
+                        name: None,
+                        value: Value::String("x".to_string()),
+                        span: Span { start: 12, end: 15 },
+                    },
+                    Argument {
+                        name: Some("default".to_string()),
+                        value: Value::Number(Number::from(3)),
+                        span: Span { start: 17, end: 27 },
+                    },
+                ],
+                span: Span { start: 4, end: 28 },
+            }),
+        },
+    });
+    assert_eq!(value, expected);
+    assert!(parse(input, None).is_err());
+
+    let tmp_dir = write_files(&[
+        (
+            "a.ron",
+            r#"#defs(N: 3) (a: #lookup("x", default: #ref(N)), b: #include("b.ron"))"#,
+        ),
+        ("b.ron", "#translate(\"greeting\")"),
+    ]);
+    let options = LoadOptions {
+        keep_unknown_directives: true,
+        ..LoadOptions::default()
+    };
+    let parse = load_with_options(tmp_dir.path().join("a.ron"), &options).unwrap();
+    match &parse.value {
+        Value::Struct(s) => {
+            assert!(matches!(&s.fields["a"], Value::Directive(call)
+                if call.args[1].value == Value::Number(Number::from(3))));
+            assert!(matches!(&s.fields["b"], Value::Directive(call) if call.name == "translate"));
+        }
+        value => panic!("Expected struct, found {:?}", value),
+    }
+    let errors = render_errors(&parse);
+    assert!(parse.errors.is_empty());
+    assert_eq!(parse.warnings.len(), 2);
+    assert!(errors.contains("Unresolved directive `#lookup`"));
+    assert!(errors.contains("Unresolved directive `#translate`"));
+}
+
+#[test]
+fn test_memory_fs() {
+    let fs = MemoryFs::new()
+        .with_file(
+            "game/monsters.ron",
+            r#"(goblin: #include("goblin.ron"), orc: #include("../shared/orc.ron"), spells: #include_dir("spells/*.ron"))"#,
+        )
+        .with_file("game/goblin.ron", "(hp: 20)")
+        .with_file("shared/orc.ron", "(hp: 40)")
+        .with_file("game/spells/fireball.ron", "8")
+        .with_file("game/spells/frost.ron", "5")
+        .with_file("game/spells/notes.txt", "ignored");
+    let mut loader = Loader::with_file_system(LoadOptions::default(), fs);
+    let parse = loader.load("game/monsters.ron").unwrap();
+    assert!(parse.errors.is_empty(), "{}", render_errors(&parse));
+    let hp = |hp: i64| {
+        Value::Struct(Struct {
+            prototype: None,
+            prototype_span: None,
+            name: None,
+            fields: indexmap! { "hp".to_string() => Value::Number(Number::from(hp)) },
+        })
+    };
+    let expected = Value::Struct(Struct {
+        prototype: None,
+        prototype_span: None,
+        name: None,
+        fields: indexmap! {
+            "goblin".to_string() => hp(20),
+            "orc".to_string() => hp(40),
+            "spells".to_string() => Value::Map(Map(indexmap! {
+                Value::String("fireball".to_string()) => Value::Number(Number::from(8)),
+                Value::String("frost".to_string()) => Value::Number(Number::from(5)),
+            })),
+        },
+    });
+    assert_eq!(parse.value, expected);
+    assert_eq!(
+        parse.dependencies,
+        [
+            "/game/monsters.ron",
+            "/game/goblin.ron",
+            "/shared/orc.ron",
+            "/game/spells/fireball.ron",
+            "/game/spells/frost.ron",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect::<Vec<_>>()
+    );
+
+    let parse = loader.load("/game/goblin.ron").unwrap();
+    assert_eq!(parse.value, hp(20));
+    assert_eq!(parse.dependencies.len(), 1);
+
+    let err = loader.load("game/missing.ron").err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_load_async() {
+    let fs = MemoryFs::new()
+        .with_file(
+            "game/monsters.ron",
+            r#"(goblin: #include("goblin.ron"), orc: #include("../shared/orc.ron"), spells: #include_dir("spells/*.ron"), lore: #include_str("lore.txt"))"#,
+        )
+        .with_file("game/goblin.ron", r#"(#prototype("base.ron"), hp: 20)"#)
+        .with_file("game/base.ron", "(hp: 1, speed: 3)")
+        .with_file("shared/orc.ron", r#"(hp: 40, weapon: #include("axe.ron"))"#)
+        .with_file("game/spells/fireball.ron", "8")
+        .with_file("game/spells/frost.ron", "5")
+        .with_file("game/lore.txt", "Goblins are green.");
+    let expected = Loader::with_file_system(LoadOptions::default(), fs.clone())
+        .load("game/monsters.ron")
+        .unwrap();
+    let async_fs = AsyncMemoryFs::new(fs);
+    // The loader keeps reading from its own file system for anything but `load_async`.
+    let mut loader = Loader::with_file_system(LoadOptions::default(), MemoryFs::new());
+    let future = loader.load_async(&async_fs, "game/monsters.ron");
+    fn assert_send<T: Send>(_: &T) {}
+    assert_send(&future);
+    let parse = block_on(future).unwrap();
+    assert_eq!(parse.value, expected.value);
+    assert_eq!(parse.dependencies, expected.dependencies);
+    assert_eq!(render_errors(&parse), render_errors(&expected));
+    assert!(render_errors(&parse).contains("Cannot find `axe.ron`"));
+    assert!(async_fs.max_in_flight.load(Ordering::SeqCst) > 1);
+    assert!(loader.load("game/monsters.ron").is_err());
+
+    let err = block_on(loader.load_async(&async_fs, "game/missing.ron"))
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+
+    // Matches of a glob are fetched before the glob is resolved, even if nothing else is missing.
+    let fs = MemoryFs::new()
+        .with_file("book.ron", r#"(spells: #include_glob("spells/*.ron"))"#)
+        .with_file("spells/fireball.ron", "8");
+    let async_fs = AsyncMemoryFs::new(fs);
+    let parse = block_on(loader.load_async(&async_fs, "book.ron")).unwrap();
+    assert!(parse.errors.is_empty(), "{}", render_errors(&parse));
+    assert_eq!(
+        parse.value.get_path("spells"),
+        Some(&Value::Seq(vec![Value::Number(Number::from(8))]))
+    );
+
+    let fs = MemoryFs::new()
+        .with_file("mod/main.ron", r#"(a: #include("shared/a.ron"))"#)
+        .with_file("mod/shared/a.ron", "1");
+    let mut async_fs = AsyncMemoryFs::new(fs);
+    async_fs.links.push(PathBuf::from("/mod/shared"));
+    let options = LoadOptions::default().sandbox(Sandbox::new("/mod").reject_symlinks());
+    let mut loader = Loader::with_file_system(options, MemoryFs::new());
+    let parse = block_on(loader.load_async(&async_fs, "mod/main.ron")).unwrap();
+    let errors = render_errors(&parse);
+    assert!(
+        errors.contains("`shared/a.ron` goes through a symbolic link"),
+        "{}",
+        errors
+    );
+}
+
+#[test]
+fn test_value_paths() {
+    let mut value = parse(
+        r#"(
+            spells: [(name: "fireball", damage: 8), (name: "frost", damage: 5)],
+            loot: {"gold": 10, 3: ("gem", 2), "a/b~c": true},
+        )"#,
+        None,
+    )
+    .ok()
+    .unwrap();
+    let int = |i: i64| Value::Number(Number::from(i));
+    assert_eq!(value.get_path("spells[0].damage"), Some(&int(8)));
+    assert_eq!(value.get_path("loot.gold"), Some(&int(10)));
+    assert_eq!(value.get_path("loot.3[1]"), Some(&int(2)));
+    assert_eq!(value.get_path(""), Some(&value));
+    assert_eq!(value.get_path("spells[2]"), None);
+    assert_eq!(value.get_path("spells.name"), None);
+    assert_eq!(value.pointer("/spells/1/damage"), Some(&int(5)));
+    assert_eq!(value.pointer("/loot/a~1b~0c"), Some(&Value::Bool(true)));
+    assert_eq!(value.pointer(""), Some(&value));
+    assert_eq!(value.pointer("spells"), None);
+
+    *value.get_path_mut("spells[1].damage").unwrap() = int(6);
+    *value.pointer_mut("/loot/3/0").unwrap() = Value::String("ruby".to_string());
+    assert_eq!(value.pointer("/spells/1/damage"), Some(&int(6)));
+    assert_eq!(
+        value.get_path("loot.3[0]"),
+        Some(&Value::String("ruby".to_string()))
+    );
+}
+
+#[test]
+fn test_value_conversions() {
+    use std::collections::HashMap;
+    use std::convert::{TryFrom, TryInto};
+
+    use crate::{FromValue, TypeError};
+
+    let value = parse(
+        r#"(
+            name: "goblin",
+            hp: 20,
+            speed: 1.5,
+            tags: ["small", "green"],
+            loot: {"gold": 10, "gems": 2},
+            boss: Some(false),
+            pet: None,
+            spells: [(damage: 8), (damage: "high")],
+        )"#,
+        None,
+    )
+    .ok()
+    .unwrap();
+    let field = |name: &str| value.get_path(name).unwrap().clone();
+    assert_eq!(field("name").as_str(), Some("goblin"));
+    assert_eq!(field("hp").as_i64(), Some(20));
+    assert_eq!(field("hp").as_f64(), Some(20.0));
+    assert_eq!(field("speed").as_i64(), None);
+    assert!(value.is_struct() && field("tags").is_seq() && field("loot").is_map());
+    assert_eq!(field("boss").as_option(), Some(Some(&Value::Bool(false))));
+    assert_eq!(field("pet").as_option(), Some(None));
+    assert!(!field("name").is_option());
+
+    assert_eq!(String::try_from(field("name")), Ok("goblin".to_string()));
+    assert_eq!(u8::try_from(field("hp")), Ok(20));
+    assert_eq!(f32::try_from(field("speed")), Ok(1.5));
+    let tags: Vec<String> = field("tags").try_into().unwrap();
+    assert_eq!(tags, ["small", "green"]);
+    let loot: HashMap<String, u32> = field("loot").try_into().unwrap();
+    assert_eq!(loot["gold"], 10);
+    assert_eq!(Option::<bool>::try_from(field("boss")), Ok(Some(false)));
+    assert_eq!(Option::<bool>::try_from(field("pet")), Ok(None));
+    assert_eq!(
+        Option::<Vec<String>>::from_value(Value::from(Some(field("tags")))),
+        Ok(Some(tags.clone()))
+    );
+
+    let err = i64::try_from(field("name")).unwrap_err();
+    assert_eq!(err.to_string(), "Expected an integer, found a string");
+    let err = i64::try_from(field("speed")).unwrap_err();
+    assert_eq!(err.to_string(), "Expected an integer, found a float");
+    let err = i8::try_from(Value::from(300)).unwrap_err();
+    assert_eq!(err.expected, "an integer in the range of `i8`");
+    let err = Vec::<HashMap<String, i64>>::from_value(field("spells")).unwrap_err();
+    assert_eq!(
+        err,
+        TypeError {
+            expected: "an integer",
+            found: "a string",
+            path: "[1].damage".to_string(),
+        }
+    );
+    assert_eq!(
+        err.to_string(),
+        "Expected an integer at `[1].damage`, found a string"
+    );
+
+    assert_eq!(Value::from("goblin"), field("name"));
+    assert_eq!(Value::from(vec!["small", "green"]), field("tags"));
+    assert_eq!(
+        Value::from(Some(false)),
+        Value::Option(Some(Box::new(Value::Bool(false))))
+    );
+    let roundtrip: HashMap<String, u32> = Value::from(loot.clone()).try_into().unwrap();
+    assert_eq!(roundtrip, loot);
+    for n in [0, i64::MAX as u64, 1 << 63, u64::MAX - 2047] {
+        assert_eq!(u64::try_from(Value::from(n)), Ok(n));
+    }
+    assert_eq!(
+        u64::try_from(Value::from(18_446_744_073_709_551_616.0))
+            .unwrap_err()
+            .to_string(),
+        "Expected an integer in the range of `u64`, found a float"
+    );
+    assert_eq!(
+        Vec::<i64>::try_from(parse("Some(3)", None).ok().unwrap())
+            .unwrap_err()
+            .to_string(),
+        "Expected a list, found an option"
+    );
+}
+
+#[test]
+fn test_from_ron_value() {
+    let value = parse(
+        r#"Goblin(hp: 20, speed: 1.5, loot: {"gold": 10}, tags: ["small"], pos: (1, 2), pet: None)"#,
+        None,
+    )
+    .ok()
+    .unwrap();
+    let ron_value = ron::Value::from(value.clone());
+    assert_eq!(Value::from(ron_value), value);
+
+    let named = parse("Point(1, 2)", None).ok().unwrap();
+    let unnamed = Value::from(ron::Value::from(named));
+    assert_eq!(
+        unnamed,
+        Value::Tuple(None, vec![Value::from(1), Value::from(2)])
+    );
+    assert_eq!(Number::from(ron::Number::from(2.5)), Number::from(2.5));
+}
+
+#[test]
+fn test_try_into_ron() {
+    use crate::{IntoRonError, TupleNames};
+
+    let value = parse(
+        r#"(pos: Point(1, 2), spell: Fire(5), pet: Some("cat"), stats: Wind(1, 2))"#,
+        None,
+    )
+    .ok()
+    .unwrap();
+    let int = |i: i64| ron::Value::Number(ron::Number::from(i));
+    let tagged = |name: &str, value: ron::Value| {
+        ron::Value::Map(ron::value::Map(
+            std::iter::once((ron::Value::String(name.to_string()), value)).collect(),
+        ))
+    };
+    let fields = |value: ron::Value| match value {
+        ron::Value::Struct(s) => s.fields,
+        value => panic!("Expected struct, found {:?}", value),
+    };
+
+    let ron = fields(value.clone().try_into_ron().unwrap());
+    assert_eq!(ron["pos"], ron::Value::Tuple(vec![int(1), int(2)]));
+    assert_eq!(ron["spell"], ron::Value::Tuple(vec![int(5)]));
+    let cat = ron::Value::String("cat".to_string());
+    assert_eq!(ron["pet"], ron::Value::Option(Some(Box::new(cat.clone()))));
+
+    let ron = fields(
+        value
+            .clone()
+            .try_into_ron_with(&TupleNames::Tagged)
+            .unwrap(),
+    );
+    assert_eq!(
+        ron["pos"],
+        tagged("Point", ron::Value::Tuple(vec![int(1), int(2)]))
+    );
+    assert_eq!(ron["spell"], tagged("Fire", int(5)));
+    assert_eq!(ron["pet"], ron::Value::Option(Some(Box::new(cat))));
+
+    let variants = TupleNames::TaggedVariants(vec!["Fire".to_string(), "Wind".to_string()]);
+    let ron = fields(value.try_into_ron_with(&variants).unwrap());
+    assert_eq!(ron["pos"], ron::Value::Tuple(vec![int(1), int(2)]));
+    assert_eq!(ron["spell"], tagged("Fire", int(5)));
+    assert_eq!(
+        ron["stats"],
+        tagged("Wind", ron::Value::Tuple(vec![int(1), int(2)]))
+    );
+
+    let (value, errors) = Parser::new(
+        r#"(spells: [1, #include("spells.ron")], boss: (#prototype("goblin.ron"), hp: 1))"#,
+        "<unknown>",
+    )
+    .parse();
+    assert!(errors.is_empty());
+    let err = value.clone().try_into_ron().unwrap_err();
+    assert_eq!(
+        err,
+        IntoRonError {
+            found: "an unresolved include",
+            path: "spells[1]".to_string(),
+            span: Some(Span { start: 13, end: 35 }),
+        }
+    );
+    assert_eq!(
+        err.to_string(),
+        "Cannot convert an unresolved include at `spells[1]` to `ron::Value`"
+    );
+    let mut value = value;
+    *value.get_path_mut("spells[1]").unwrap() = Value::from(2);
+    let err = value.try_into_ron().unwrap_err();
+    assert_eq!(err.found, "a struct with an unresolved prototype");
+    assert_eq!(err.path, "boss");
+}
+
+#[test]
+fn test_deserialize_ron() {
+    use crate::TupleNames;
+
+    #[derive(Debug, PartialEq, serde::Deserialize)]
+    struct Point(i64, i64);
+    #[derive(Debug, PartialEq, serde::Deserialize)]
+    enum Spell {
+        Fire(i64),
+        Wind(i64, i64),
+    }
+    #[derive(Debug, PartialEq, serde::Deserialize)]
+    struct Book {
+        pos: Point,
+        spells: Vec<Spell>,
+    }
+
+    let value = parse(
+        "Book(pos: Point(1, 2), spells: [Fire(5), Wind(1, 2)])",
+        None,
+    )
+    .ok()
+    .unwrap();
+    let book = Book {
+        pos: Point(1, 2),
+        spells: vec![Spell::Fire(5), Spell::Wind(1, 2)],
+    };
+
+    let pos = match value.clone().try_into_ron().unwrap() {
+        ron::Value::Struct(s) => s.fields["pos"].clone(),
+        ron => panic!("Expected struct, found {:?}", ron),
+    };
+    assert_eq!(pos.into_rust::<Point>().unwrap(), Point(1, 2));
+
+    // The deserializer of `ron::Value` does not support enums, however they are encoded.
+    let variants = TupleNames::TaggedVariants(vec!["Fire".to_string(), "Wind".to_string()]);
+    let ron = value.try_into_ron_with(&variants).unwrap();
+    let err = ron.clone().into_rust::<Book>().unwrap_err();
+    assert!(err.to_string().contains("expected enum Spell"), "{}", err);
+    let json = serde_json::to_value(&ron).unwrap();
+    assert_eq!(serde_json::from_value::<Book>(json).unwrap(), book);
+}
+
+#[test]
+fn test_search_paths() {
+    let fs = MemoryFs::new()
+        .with_file(
+            "game/monsters.ron",
+            r#"(goblin: #include("@core/creatures/goblin.ron"), fireball: #include("fireball.ron"), spells: #include_glob("@core/spells/*.ron"))"#,
+        )
+        .with_file("engine/core/creatures/goblin.ron", "(hp: 20)")
+        .with_file("engine/core/spells/frost.ron", "5")
+        .with_file("shared/spells/fireball.ron", "8")
+        .with_file(
+            "game/broken.ron",
+            r#"(a: #include("missing.ron"), b: #include("@mods/dragon.ron"), c: (#prototype("base.ron"), hp: 1))"#,
+        );
+    let options = LoadOptions::default()
+        .root("core", "/engine/core")
+        .search_path("/shared/spells");
+    let mut loader = Loader::with_file_system(options, fs);
+    let parse = loader.load("game/monsters.ron").unwrap();
+    assert!(parse.errors.is_empty(), "{}", render_errors(&parse));
+    let expected = Value::Struct(Struct {
+        prototype: None,
+        prototype_span: None,
+        name: None,
+        fields: indexmap! {
+            "goblin".to_string() => Value::Struct(Struct {
+                prototype: None,
+                prototype_span: None,
+                name: None,
+                fields: indexmap! { "hp".to_string() => Value::Number(Number::from(20)) },
+            }),
+            "fireball".to_string() => Value::Number(Number::from(8)),
+            "spells".to_string() => Value::Seq(vec![Value::Number(Number::from(5))]),
+        },
+    });
+    assert_eq!(parse.value, expected);
+
+    let parse = loader.load("game/broken.ron").unwrap();
+    assert_eq!(parse.errors.len(), 3);
+    assert_eq!(
+        parse.value.get_path("c"),
+        Some(&Value::Struct(Struct::from_iter([(
+            "hp".to_string(),
+            Value::Number(Number::from(1))
+        )])))
+    );
+    let errors = render_errors(&parse);
+    assert!(errors.contains("Cannot find `base.ron`"), "{}", errors);
+    assert!(errors.contains("Cannot find `missing.ron`"), "{}", errors);
+    assert!(errors.contains("/game/missing.ron"), "{}", errors);
+    assert!(errors.contains("/shared/spells/missing.ron"), "{}", errors);
+    assert!(errors.contains("Unknown root `@mods`"), "{}", errors);
+    assert!(errors.contains("Available roots: `@core`"), "{}", errors);
+
+    let fs = MemoryFs::new()
+        .with_file("a.ron", r#"(#prototype("b.ron"), x: 1)"#)
+        .with_file("b.ron", "[1, 2]");
+    let parse = Loader::with_file_system(LoadOptions::default(), fs)
+        .load("a.ron")
+        .unwrap();
+    assert_eq!(parse.errors.len(), 1);
+    let errors = render_errors(&parse);
+    assert!(
+        errors.contains("Prototype must be a struct, found a list"),
+        "{}",
+        errors
+    );
+    assert!(errors.contains("`b.ron` is not a struct"), "{}", errors);
+}
+
+#[test]
+fn test_sandbox() {
+    let fs = MemoryFs::new()
+        .with_file(
+            "mods/goblin/main.ron",
+            r#"(
+                stats: #include("stats.ron"),
+                secret: #include("../../secret.ron"),
+                passwd: #include_str("/etc/passwd"),
+                other: #include("../orc/main.ron"),
+            )"#,
+        )
+        .with_file("mods/goblin/stats.ron", "(hp: 20)")
+        .with_file("mods/goblin/big.ron", r#""a very long string""#)
+        .with_file(
+            "mods/goblin/many.ron",
+            r#"[#include("stats.ron"), #include("big.ron")]"#,
+        )
+        .with_file("mods/goblin/one.ron", r#"#include("stats.ron")"#)
+        .with_file("mods/goblin/two.ron", r#"#include("one.ron")"#)
+        .with_file("mods/orc/main.ron", "()")
+        .with_file("secret.ron", r#""hunter2""#)
+        .with_file("etc/passwd", "root:x:0:0");
+    let options = LoadOptions::default().sandbox(Sandbox::new("/mods/goblin"));
+    let mut loader = Loader::with_file_system(options, fs.clone());
+    let parse = loader.load("mods/goblin/main.ron").unwrap();
+    assert_eq!(parse.errors.len(), 3);
+    let errors = render_errors(&parse);
+    assert!(
+        errors.contains("`../../secret.ron` is outside the sandbox"),
+        "{}",
+        errors
+    );
+    assert!(errors.contains("Resolves to `/secret.ron`"), "{}", errors);
+    assert!(
+        errors.contains("`/etc/passwd` is outside the sandbox"),
+        "{}",
+        errors
+    );
+    assert!(
+        errors.contains("`../orc/main.ron` is outside the sandbox"),
+        "{}",
+        errors
+    );
+    assert!(!errors.contains("hunter2"));
+    match parse.value {
+        Value::Struct(Struct { fields, .. }) => {
+            assert_eq!(fields["secret"], Value::Unit);
+            assert_eq!(fields["passwd"], Value::Unit);
+        }
+        value => panic!("Expected struct, found {:?}", value),
+    }
+
+    let options = LoadOptions::default().sandbox(Sandbox::new("/mods/goblin").max_files(2));
+    let mut loader = Loader::with_file_system(options, fs.clone());
+    let parse = loader.load("mods/goblin/many.ron").unwrap();
+    assert_eq!(parse.errors.len(), 1);
+    let errors = render_errors(&parse);
+    assert!(
+        errors.contains("At most 2 files can be loaded"),
+        "{}",
+        errors
+    );
+    // Files from the cache count towards the limits too.
+    assert!(loader
+        .load("mods/goblin/one.ron")
+        .unwrap()
+        .errors
+        .is_empty());
+    let parse = loader.load("mods/goblin/two.ron").unwrap();
+    assert_eq!(parse.errors.len(), 1);
+    assert!(render_errors(&parse).contains("At most 2 files can be loaded"));
+
+    let options = LoadOptions::default().sandbox(Sandbox::new("/mods/goblin").max_bytes(64));
+    let parse = Loader::with_file_system(options, fs)
+        .load("mods/goblin/many.ron")
+        .unwrap();
+    assert_eq!(parse.errors.len(), 1);
+    let errors = render_errors(&parse);
+    assert!(errors.contains("Cannot load `big.ron`"), "{}", errors);
+    assert!(
+        errors.contains("At most 64 bytes can be loaded"),
+        "{}",
+        errors
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_sandbox_symlinks() {
+    let tmp_dir = write_files(&[
+        ("mod/main.ron", r#"(a: #include("shared/a.ron"))"#),
+        ("mod/real/a.ron", "1"),
+        ("mod/glob.ron", r#"#include_glob("data/*.ron")"#),
+        ("mod/data/ok.ron", "2"),
+        ("outside/secret.ron", r#""hunter2""#),
+    ]);
+    std::os::unix::fs::symlink(
+        tmp_dir.path().join("mod/real"),
+        tmp_dir.path().join("mod/shared"),
+    )
+    .unwrap();
+    std::os::unix::fs::symlink(
+        tmp_dir.path().join("outside/secret.ron"),
+        tmp_dir.path().join("mod/data/evil.ron"),
+    )
+    .unwrap();
+    let main = tmp_dir.path().join("mod/main.ron");
+
+    let sandbox = Sandbox::new(tmp_dir.path().join("mod"));
+    let parse = load_with_options(&main, &LoadOptions::default().sandbox(sandbox.clone())).unwrap();
+    assert!(parse.errors.is_empty(), "{}", render_errors(&parse));
+
+    let options = LoadOptions::default().sandbox(sandbox.clone().reject_symlinks());
+    let parse = load_with_options(&main, &options).unwrap();
+    let errors = render_errors(&parse);
+    assert!(
+        errors.contains("`shared/a.ron` goes through a symbolic link"),
+        "{}",
+        errors
+    );
+
+    // Files that the sandbox rejects are not read ahead of resolution either.
+    for threads in [1, 2] {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let options = LoadOptions::default()
+            .sandbox(sandbox.clone())
+            .threads(threads);
+        let mut loader = Loader::with_file_system(options, CountingFs(Arc::clone(&reads)));
+        let parse = loader.load(tmp_dir.path().join("mod/glob.ron")).unwrap();
+        let errors = render_errors(&parse);
+        assert!(errors.contains("is outside the sandbox"), "{}", errors);
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+    }
+}
+
+#[test]
+fn test_dependency_graph() {
+    let fs = MemoryFs::new()
+        .with_file(
+            "game/goblin.ron",
+            r#"Goblin(#prototype("base.ron"), lore: #include_str("lore.txt"), icon: #asset("icon.png"))"#,
+        )
+        .with_file("game/base.ron", r#"(stats: #include("stats.ron"), hp: 1)"#)
+        .with_file("game/stats.ron", "(str: 3)")
+        .with_file("game/lore.txt", "Likes shiny things")
+        .with_file("game/icon.png", "png");
+    let options = LoadOptions::default().directive("asset", Asset);
+    let parse = Loader::with_file_system(options, fs)
+        .load("game/goblin.ron")
+        .unwrap();
+    assert!(parse.errors.is_empty(), "{}", render_errors(&parse));
+    let graph = &parse.graph;
+    assert_eq!(graph.nodes, parse.dependencies);
+    let edges = graph
+        .edges
+        .iter()
+        .map(|edge| {
+            (
+                edge.from.to_str().unwrap(),
+                edge.to.to_str().unwrap(),
+                edge.kind.name(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        edges,
+        [
+            ("/game/goblin.ron", "/game/base.ron", "prototype"),
+            ("/game/goblin.ron", "/game/lore.txt", "include_str"),
+            ("/game/goblin.ron", "/game/icon.png", "asset"),
+            ("/game/base.ron", "/game/stats.ron", "include"),
+        ]
+    );
+    assert_eq!(graph.edges[0].span, Some(Span { start: 7, end: 29 }));
+    assert_eq!(graph.edges[1].kind, EdgeKind::Include(IncludeKind::Str));
+    assert!(graph.edges[1].span.is_some());
+
+    assert_eq!(
+        graph.makefile("goblin.bin"),
+        "goblin.bin: \\\n  /game/goblin.ron \\\n  /game/base.ron \\\n  /game/stats.ron \\\n  \
+         /game/lore.txt \\\n  /game/icon.png\n\n/game/base.ron:\n\n/game/stats.ron:\n\n\
+         /game/lore.txt:\n\n/game/icon.png:\n"
+    );
+    let dot = graph.dot();
+    assert!(dot.starts_with("digraph dependencies {\n"));
+    assert!(dot.contains("    \"/game/base.ron\" -> \"/game/stats.ron\" [label=\"#include\"];\n"));
+}
+
+#[test]
+fn test_watcher() {
+    let fs = MemoryFs::new()
+        .with_file("goblin.ron", "(hp: 20)")
+        .with_file(
+            "cave.ron",
+            r#"Cave(goblin: Goblin(#prototype("goblin.ron"), name: "Grak"))"#,
+        )
+        .with_file("forest.ron", "Forest(trees: 3)");
+    let mut watcher = Watcher::new(Loader::with_file_system(LoadOptions::default(), fs.clone()));
+    assert!(watcher.watch("cave.ron").1.is_ok());
+    assert!(watcher.watch("forest.ron").1.is_ok());
+    assert!(watcher.poll().is_empty());
+
+    fs.insert("goblin.ron", "(hp: 25)");
+    let events = watcher.poll();
+    assert_eq!(events.len(), 1);
+    let (path, result) = &events[0];
+    assert_eq!(path, Path::new("cave.ron"));
+    let value = result.as_ref().ok().unwrap().clone();
+    assert!(format!("{:?}", value).contains("Integer(25)"));
+    assert!(watcher.poll().is_empty());
+
+    fs.insert("goblin.ron", "(hp: )");
+    let events = watcher.poll();
+    assert_eq!(events.len(), 1);
+    assert!(events[0].1.is_err());
+
+    // An entry point that cannot be read is reported once, and again when it is back.
+    fs.remove("forest.ron");
+    let events = watcher.poll();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].0, Path::new("forest.ron"));
+    let errors = render_errors(events[0].1.as_ref().err().unwrap());
+    assert!(errors.contains("Cannot load `forest.ron`"), "{}", errors);
+    assert!(watcher.poll().is_empty());
+    fs.insert("forest.ron", "Forest(trees: 4)");
+    let events = watcher.poll();
+    assert_eq!(events.len(), 1);
+    assert!(events[0].1.is_ok());
+
+    // New files matching a glob and missing include targets that are created are changes too.
+    fs.insert("spells/fireball.ron", "(damage: 8)");
+    fs.insert(
+        "spells.ron",
+        r#"(spells: #include_glob("spells/*.ron"), bonus: #include("bonus.ron"))"#,
+    );
+    assert!(watcher.watch("spells.ron").1.is_err());
+    assert!(watcher.poll().is_empty());
+    fs.insert("spells/frost.ron", "(damage: 5)");
+    let events = watcher.poll();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].0, Path::new("spells.ron"));
+    let parse = events[0].1.as_ref().err().unwrap();
+    assert!(format!("{:?}", parse.value).contains("Integer(5)"));
+    fs.insert("bonus.ron", "(damage: 2)");
+    let events = watcher.poll();
+    assert_eq!(events.len(), 1);
+    assert!(events[0].1.is_ok());
+    assert!(watcher.poll().is_empty());
+    watcher.unwatch("spells.ron");
+
+    watcher.unwatch("cave.ron");
+    fs.insert("goblin.ron", "(hp: 30)");
+    assert!(watcher.poll().is_empty());
+
+    let receiver = watcher.spawn(std::time::Duration::from_millis(1));
+    fs.insert("forest.ron", "Forest(trees: 5)");
+    let (path, result) = receiver
+        .recv_timeout(std::time::Duration::from_secs(10))
+        .unwrap();
+    assert_eq!(path, Path::new("forest.ron"));
+    assert!(result.is_ok());
+}
+
+#[test]
+fn test_provenance() {
+    let fs = MemoryFs::new()
+        .with_file(
+            "goblin_wizard.ron",
+            r#"Goblin(
+    #prototype("goblin.ron"),
+    name: "Wizard",
+    spells: #include_glob("spells/*.ron"),
+    lore: #include_str("lore.txt"),
+    mana: #if(true, 10, 20),
+    extra: #if(true, #include("extra.ron"), 0),
+)"#,
+        )
+        .with_file("extra.ron", "(x: 1)")
+        .with_file(
+            "goblin.ron",
+            "(\n    name: \"Goblin\",\n    minHealth: 10,\n    drops: [\"gold\"],\n)",
+        )
+        .with_file("spells/fireball.ron", "(damage: 8)")
+        .with_file("lore.txt", "Old and wise")
+        .with_file(
+            "patch.ron",
+            r#"[#set("mana", 15), #remove("drops[0]"), #append("spells", (damage: 1))]"#,
+        );
+    let options = LoadOptions {
+        provenance: true,
+        ..LoadOptions::default()
+    };
+    let mut loader = Loader::with_file_system(options, fs.clone());
+    let parse = loader.load("goblin_wizard.ron").unwrap();
+    assert!(parse.errors.is_empty(), "{}", render_errors(&parse));
+    let provenance = &parse.provenance;
+    let origin = |path: &str| {
+        let origin = provenance.origin(path).unwrap();
+        (
+            origin.file.to_str().unwrap().to_string(),
+            origin.kind,
+            parse.line_of(origin),
+        )
+    };
+    let declared = |file: &str, line| (file.to_string(), OriginKind::Declared, Some(line));
+    let included = |file: &str, line| (file.to_string(), OriginKind::Included, Some(line));
+    let inherited = |file: &str, line| (file.to_string(), OriginKind::Inherited, Some(line));
+    assert_eq!(origin(""), declared("/goblin_wizard.ron", 1));
+    assert_eq!(origin("name"), declared("/goblin_wizard.ron", 3));
+    assert_eq!(origin("minHealth"), inherited("/goblin.ron", 3));
+    assert_eq!(origin("drops[0]"), inherited("/goblin.ron", 4));
+    assert_eq!(
+        origin("spells[0].damage"),
+        included("/spells/fireball.ron", 1)
+    );
+    // Files that are not parsed are not in `sources`.
+    assert_eq!(
+        origin("lore"),
+        ("/lore.txt".to_string(), OriginKind::Included, None)
+    );
+    // Values produced by directives have the origin of the directive.
+    assert_eq!(origin("mana"), declared("/goblin_wizard.ron", 6));
+    assert_eq!(origin("extra.x"), included("/extra.ron", 1));
+    let span = provenance.origin("minHealth").unwrap().span;
+    assert_eq!(
+        &fs_source(&parse, "/goblin.ron")[span.start..span.end],
+        "10"
+    );
+
+    let options = LoadOptions {
+        provenance: true,
+        ..LoadOptions::default()
+    };
+    let parse = Loader::with_file_system(options, fs)
+        .load_with_overlays("goblin_wizard.ron", &["patch.ron"])
+        .unwrap();
+    assert!(parse.errors.is_empty(), "{}", render_errors(&parse));
+    let provenance = &parse.provenance;
+    assert_eq!(provenance.origin("mana").unwrap().kind, OriginKind::Overlay);
+    assert_eq!(
+        provenance.origin("spells[1]").unwrap().kind,
+        OriginKind::Overlay
+    );
+    assert_eq!(
+        provenance.origin("spells[0]").unwrap().kind,
+        OriginKind::Included
+    );
+    assert_eq!(
+        provenance
+            .iter()
+            .filter(|(path, _)| path.starts_with("drops["))
+            .count(),
+        0
+    );
+
+    // Map keys in brackets are removed like fields.
+    let fs = MemoryFs::new()
+        .with_file(
+            "items.ron",
+            r#"(items: {"foo": 1, "bar": 2}, list: [1, 2, 3])"#,
+        )
+        .with_file(
+            "patch.ron",
+            r#"[#remove("items[foo]"), #remove("list[0]")]"#,
+        );
+    let options = LoadOptions {
+        provenance: true,
+        ..LoadOptions::default()
+    };
+    let parse = Loader::with_file_system(options, fs)
+        .load_with_overlays("items.ron", &["patch.ron"])
+        .unwrap();
+    assert!(parse.errors.is_empty(), "{}", render_errors(&parse));
+    let mut paths = parse
+        .provenance
+        .iter()
+        .map(|(path, _)| path)
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert_eq!(
+        paths,
+        ["", "items", "items.bar", "list", "list[0]", "list[1]"]
+    );
+
+    let parse = load_with_options("example/simple.ron", &LoadOptions::default());
+    assert!(parse.map_or(true, |parse| parse.provenance.is_empty()));
+}
+
+fn fs_source<'a>(parse: &'a Parse, file: &str) -> &'a str {
+    &parse
+        .sources
+        .iter()
+        .find(|(name, _)| name == file)
+        .unwrap()
+        .1
+}
+
+/// Counts the files that are parsed.
+struct CountingFs(Arc<AtomicUsize>);
+
+impl FileSystem for CountingFs {
+    fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf> {
+        StdFs.canonicalize(path)
+    }
+
+    fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        StdFs.read(path)
+    }
+
+    fn read_to_string(&self, path: &Path) -> std::io::Result<String> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        StdFs.read_to_string(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> std::io::Result<Vec<PathBuf>> {
+        StdFs.read_dir(path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        StdFs.is_file(path)
+    }
+
+    fn modified(&self, path: &Path) -> std::io::Result<std::time::SystemTime> {
+        StdFs.modified(path)
+    }
+}
+
+#[test]
+fn test_loader_cache() {
+    let tmp_dir = write_files(&[
+        ("a.ron", r#"(shared: #include("shared.ron"), name: "a")"#),
+        ("b.ron", r#"(shared: #include("shared.ron"), name: "b")"#),
+        ("shared.ron", "(hp: 1)"),
+        ("broken.ron", r#"(x: #include("missing.ron"))"#),
+    ]);
+    let path = |name: &str| tmp_dir.path().join(name).canonicalize().unwrap();
+    let reads = Arc::new(AtomicUsize::new(0));
+    let mut loader =
+        Loader::with_file_system(LoadOptions::default(), CountingFs(Arc::clone(&reads)));
+    let shared = |hp: i64| {
+        Value::Struct(Struct {
+            prototype: None,
+            prototype_span: None,
+            name: None,
+            fields: indexmap! { "hp".to_string() => Value::Number(Number::from(hp)) },
+        })
+    };
+    let field = |parse: &Parse, name: &str| match &parse.value {
+        Value::Struct(Struct { fields, .. }) => fields[name].clone(),
+        value => panic!("Expected struct, found {:?}", value),
+    };
+
+    let parses = loader.load_all(&[path("a.ron"), path("b.ron")]);
+    assert_eq!(reads.load(Ordering::SeqCst), 3);
+    let b = parses[1].as_ref().unwrap();
+    assert_eq!(field(b, "shared"), shared(1));
+    assert_eq!(b.dependencies, vec![path("b.ron"), path("shared.ron")]);
+    assert_eq!(b.sources.len(), 2);
+
+    let a = loader.load(path("a.ron")).unwrap();
+    assert_eq!(reads.load(Ordering::SeqCst), 3);
+    assert_eq!(field(&a, "shared"), shared(1));
+    assert_eq!(a.dependencies, vec![path("a.ron"), path("shared.ron")]);
+    assert!(!loader.is_stale(path("a.ron")));
+    assert!(loader.is_stale(path("broken.ron")));
+
+    std::fs::write(path("shared.ron"), "(hp: 2)").unwrap();
+    assert!(loader.is_stale(path("a.ron")));
+    assert_eq!(loader.refresh(), vec![path("shared.ron")]);
+    let a = loader.load(path("a.ron")).unwrap();
+    assert_eq!(reads.load(Ordering::SeqCst), 5);
+    assert_eq!(field(&a, "shared"), shared(2));
+
+    loader.invalidate(path("b.ron"));
+    loader.load(path("b.ron")).unwrap();
+    assert_eq!(reads.load(Ordering::SeqCst), 6);
+
+    // Files with diagnostics are loaded again, so that the diagnostics are reported again.
+    for _ in 0..2 {
+        let parse = loader.load(path("broken.ron")).unwrap();
+        assert_eq!(parse.errors.len(), 1);
+    }
+    assert_eq!(reads.load(Ordering::SeqCst), 8);
+}
+
+#[test]
+fn test_parallel_loading() {
+    let tmp_dir = write_files(&[
+        (
+            "a.ron",
+            r#"(creatures: #include_dir("creatures/*.ron"), shared: #include("shared.ron"))"#,
+        ),
+        (
+            "b.ron",
+            r#"(shared: #include("shared.ron"), broken: #include("broken.ron"))"#,
+        ),
+        ("shared.ron", "(hp: 1)"),
+        ("broken.ron", "(hp: )"),
+        ("base.ron", "(hp: 10, speed: 3)"),
+        (
+            "creatures/goblin.ron",
+            r#"(#prototype("../base.ron"), hp: 5)"#,
+        ),
+        (
+            "creatures/orc.ron",
+            r#"(weapon: #include("../missing.ron"))"#,
+        ),
+        (
+            "creatures/troll.ron",
+            r#"(hp: #if(true, 30, #include("../big.ron")))"#,
+        ),
+        ("big.ron", "1000"),
+    ]);
+    let paths = ["a.ron", "b.ron"].map(|name| tmp_dir.path().join(name));
+    let load = |threads: usize| {
+        Loader::new(LoadOptions::default().threads(threads))
+            .load_all(&paths)
+            .into_iter()
+            .map(|parse| {
+                let parse = parse.unwrap();
+                (
+                    parse.value.clone(),
+                    render_errors(&parse),
+                    parse.dependencies,
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    let sequential = load(1);
+    assert_eq!(sequential[0].1.matches("Error").count(), 1);
+    assert_eq!(sequential[1].1.matches("Error").count(), 1);
+    for threads in [0, 2, 8] {
+        assert_eq!(load(threads), sequential);
+    }
+}
+
+#[test]
+fn test_include_cycle() {
+    let tmp_dir = write_files(&[
+        ("a.ron", r#"(b: #include("b.ron"))"#),
+        ("b.ron", r#"(c: #include("c.ron"))"#),
+        ("c.ron", r#"(#prototype("a.ron"), hp: 1)"#),
+        ("self.ron", r#"(me: #include("self.ron"))"#),
+    ]);
+    for threads in [1, 4] {
+        let mut loader = Loader::new(LoadOptions::default().threads(threads));
+        let parse = loader.load(tmp_dir.path().join("a.ron")).unwrap();
+        let errors = render_errors(&parse);
+        assert_eq!(parse.errors.len(), 1, "{}", errors);
+        assert!(errors.contains("a.ron` depends on itself"), "{}", errors);
+        assert!(errors.contains("b.ron` -> `"), "{}", errors);
+        let edge = parse
+            .graph
+            .edges
+            .iter()
+            .find(|edge| edge.kind == EdgeKind::Prototype);
+        assert_eq!(edge.unwrap().span, Some(Span { start: 1, end: 20 }));
+
+        let parse = loader.load(tmp_dir.path().join("self.ron")).unwrap();
+        let errors = render_errors(&parse);
+        assert!(errors.contains("Cycle closed here"), "{}", errors);
+        // Files on a cycle are not cached, so the cycle is reported by every load.
+        let parse = loader.load(tmp_dir.path().join("self.ron")).unwrap();
+        assert_eq!(parse.errors.len(), 1);
+    }
+}
+
+#[test]
+fn test_load_str() {
+    let tmp_dir = write_files(&[
+        ("base.ron", "(hp: 10, speed: 3)"),
+        ("creatures/orc.ron", "(hp: 40)"),
+        (
+            "creatures/goblin.ron",
+            r#"(#prototype("../base.ron"), hp: 20)"#,
+        ),
+    ]);
+    let path = |name: &str| tmp_dir.path().join(name);
+    let stats = |hp: i64, speed: i64| {
+        Value::Struct(Struct {
+            prototype: None,
+            prototype_span: None,
+            name: None,
+            fields: indexmap! {
+                "hp".to_string() => Value::Number(Number::from(hp)),
+                "speed".to_string() => Value::Number(Number::from(speed)),
+            },
+        })
+    };
+
+    // The file does not exist, but includes are relative to it.
+    let source = r#"(#prototype("../base.ron"), hp: 5, friend: #include("orc.ron"))"#;
+    let parse = crate::load_str(source, path("creatures/kobold.ron")).unwrap();
+    assert!(parse.errors.is_empty(), "{}", render_errors(&parse));
+    match &parse.value {
+        Value::Struct(Struct { fields, .. }) => {
+            assert_eq!(fields["hp"], Value::Number(Number::from(5)));
+            assert_eq!(fields["speed"], Value::Number(Number::from(3)));
+        }
+        value => panic!("Expected struct, found {:?}", value),
+    }
+    let kobold = path("creatures").canonicalize().unwrap().join("kobold.ron");
+    assert_eq!(parse.dependencies[0], kobold);
+    assert_eq!(parse.graph.edges.len(), 2);
+
+    // Diagnostics point into the string.
+    let parse = crate::load_str(r#"(hp: #include("troll.ron"))"#, &kobold).unwrap();
+    let errors = render_errors(&parse);
+    assert!(errors.contains("kobold.ron:1:6"), "{}", errors);
+
+    // The string takes the place of an existing file, but is not cached in its place.
+    let mut loader = Loader::new(LoadOptions::default().threads(2));
+    let goblin = path("creatures/goblin.ron");
+    assert_eq!(loader.load(&goblin).unwrap().value, stats(20, 3));
+    let source = r#"(#prototype("../base.ron"), hp: 25)"#;
+    assert_eq!(
+        loader.load_str(source, &goblin).unwrap().value,
+        stats(25, 3)
+    );
+    assert_eq!(loader.load(&goblin).unwrap().value, stats(20, 3));
+}
+
+fn write_files(files: &[(&str, &str)]) -> tempdir::TempDir {
+    let tmp_dir = tempdir::TempDir::new("root").unwrap();
+    for (name, contents) in files {
+        let path = tmp_dir.path().join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut file = File::create(path).unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+    }
+    tmp_dir
+}
+
+fn render_errors(parse: &Parse) -> String {
+    let mut out = vec![];
+    for error in parse.errors.iter().chain(&parse.warnings) {
+        error
+            .write(ariadne::sources(parse.sources.clone()), &mut out)
+            .unwrap();
+    }
+    String::from_utf8(out).unwrap()
+}
+
+/// Serves a [`MemoryFs`] asynchronously, with every read waiting for the next poll. The paths in
+/// `links` are reported as symbolic links.
+#[cfg(feature = "async")]
+struct AsyncMemoryFs {
+    fs: MemoryFs,
+    links: Vec<PathBuf>,
+    in_flight: AtomicUsize,
+    max_in_flight: AtomicUsize,
+}
+
+#[cfg(feature = "async")]
+impl AsyncMemoryFs {
+    fn new(fs: MemoryFs) -> AsyncMemoryFs {
+        AsyncMemoryFs {
+            fs,
+            links: vec![],
+            in_flight: AtomicUsize::new(0),
+            max_in_flight: AtomicUsize::new(0),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl crate::AsyncFileSystem for AsyncMemoryFs {
+    fn canonicalize<'a>(&'a self, path: &'a Path) -> crate::FsFuture<'a, PathBuf> {
+        Box::pin(std::future::ready(self.fs.canonicalize(path)))
+    }
+
+    fn read<'a>(&'a self, path: &'a Path) -> crate::FsFuture<'a, Vec<u8>> {
+        Box::pin(async move {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            yield_now().await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            self.fs.read(path)
+        })
+    }
+
+    fn read_dir<'a>(&'a self, path: &'a Path) -> crate::FsFuture<'a, Vec<PathBuf>> {
+        Box::pin(std::future::ready(self.fs.read_dir(path)))
+    }
+
+    fn is_symlink<'a>(&'a self, path: &'a Path) -> crate::FsFuture<'a, bool> {
+        Box::pin(std::future::ready(Ok(self
+            .links
+            .iter()
+            .any(|link| link == path))))
+    }
+}
+
+#[cfg(feature = "async")]
+async fn yield_now() {
+    let mut yielded = false;
+    std::future::poll_fn(|cx| {
+        if yielded {
+            std::task::Poll::Ready(())
+        } else {
+            yielded = true;
+            cx.waker().wake_by_ref();
+            std::task::Poll::Pending
+        }
+    })
+    .await
+}
+
+#[cfg(feature = "async")]
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    struct Unpark(std::thread::Thread);
+    impl std::task::Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+    let waker = std::task::Waker::from(Arc::new(Unpark(std::thread::current())));
+    let mut cx = std::task::Context::from_waker(&waker);
+    let mut future = std::pin::pin!(future);
+    loop {
+        if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        std::thread::park();
+    }
+}
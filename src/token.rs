@@ -7,7 +7,7 @@ pub struct Token {
     pub text: String,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Copy)]
+#[derive(Debug, Clone, Eq, PartialEq, Copy, Hash, Ord, PartialOrd)]
 pub struct Span {
     pub start: usize,
     pub end: usize,
@@ -24,8 +24,24 @@ pub enum TokenKind {
 
     Comma,
     Colon,
+    Dot,
     Hash,
 
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Bang,
+    BangEqual,
+    EqualEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    AndAnd,
+    OrOr,
+
     Comment,
     Whitespace,
     Newline,
@@ -51,7 +67,22 @@ impl TokenKind {
             TokenKind::RightBracket => "]",
             TokenKind::Comma => ",",
             TokenKind::Colon => ":",
+            TokenKind::Dot => ".",
             TokenKind::Hash => "#",
+            TokenKind::Plus => "+",
+            TokenKind::Minus => "-",
+            TokenKind::Star => "*",
+            TokenKind::Slash => "/",
+            TokenKind::Percent => "%",
+            TokenKind::Bang => "!",
+            TokenKind::BangEqual => "!=",
+            TokenKind::EqualEqual => "==",
+            TokenKind::Less => "<",
+            TokenKind::LessEqual => "<=",
+            TokenKind::Greater => ">",
+            TokenKind::GreaterEqual => ">=",
+            TokenKind::AndAnd => "&&",
+            TokenKind::OrOr => "||",
             TokenKind::Comment => "<COMMENT>",
             TokenKind::Whitespace => "\\s",
             TokenKind::Newline => "\\n",
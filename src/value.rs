@@ -5,6 +5,10 @@ use std::{
     ops::{Index, IndexMut},
 };
 
+use crate::expr::Expr;
+use crate::provenance;
+use crate::token::Span;
+
 /// A `Value` to `Value` map.
 ///
 /// This structure either uses a [BTreeMap](std::collections::BTreeMap) or the
@@ -20,6 +24,17 @@ impl Map {
         Default::default()
     }
 
+    /// Returns the key of the entry that a path segment selects, which is either a string or an
+    /// integer.
+    pub(crate) fn segment_key(&self, segment: &str) -> Option<Value> {
+        let key = Value::String(segment.to_string());
+        if self.0.contains_key(&key) {
+            return Some(key);
+        }
+        let key = Value::Number(Number::Integer(segment.parse::<i64>().ok()?));
+        self.0.contains_key(&key).then_some(key)
+    }
+
     /// Returns the number of elements in the map.
     pub fn len(&self) -> usize {
         self.0.len()
@@ -129,6 +144,9 @@ pub enum Number {
 pub struct Struct {
     pub name: Option<String>,
     pub prototype: Option<String>,
+    /// Location of the `#prototype(...)` directive in the file the struct was parsed from.
+    /// Ignored by comparisons.
+    pub prototype_span: Option<Span>,
     pub fields: StructInner,
 }
 
@@ -138,6 +156,7 @@ impl Struct {
         Struct {
             name,
             prototype,
+            prototype_span: None,
             fields: Default::default(),
         }
     }
@@ -197,6 +216,7 @@ impl FromIterator<(String, Value)> for Struct {
             name: None,
             fields: StructInner::from_iter(iter),
             prototype: None,
+            prototype_span: None,
         }
     }
 }
@@ -427,6 +447,244 @@ impl Ord for Float {
     }
 }
 
+/// The form of an `#include` directive.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum IncludeKind {
+    /// `#include("file.ron")` is replaced with the contents of a single file.
+    File,
+    /// `#include_glob("dir/*.ron")` is replaced with a list of all matching files, sorted by path.
+    Glob,
+    /// `#include_dir("dir/*.ron")` is replaced with a map from file stem to the contents of
+    /// each matching file, sorted by path.
+    Dir,
+    /// `#include_str("desc.txt")` is replaced with the contents of a UTF-8 file as a string.
+    Str,
+    /// `#include_bytes("icon.png")` is replaced with the raw contents of a file.
+    Bytes,
+}
+
+impl IncludeKind {
+    /// The name of the directive, without the leading `#`.
+    pub fn directive_name(&self) -> &'static str {
+        match self {
+            IncludeKind::File => "include",
+            IncludeKind::Glob => "include_glob",
+            IncludeKind::Dir => "include_dir",
+            IncludeKind::Str => "include_str",
+            IncludeKind::Bytes => "include_bytes",
+        }
+    }
+}
+
+/// An unresolved `#include` directive.
+#[derive(Clone, Debug)]
+pub struct Include {
+    pub kind: IncludeKind,
+    /// Path of the included file, relative to the including file. For `#include_glob` and
+    /// `#include_dir`, the final path component may contain `*` and `?` wildcards.
+    pub path: String,
+    /// Selects a nested value of the included document, e.g. `goblin.stats` or `spells[0]`.
+    pub select: Option<String>,
+    /// Location of the directive in the including file. Ignored by comparisons.
+    pub span: Span,
+}
+
+impl Include {
+    fn key(&self) -> (IncludeKind, &str, Option<&str>) {
+        (self.kind, &self.path, self.select.as_deref())
+    }
+}
+
+impl Eq for Include {}
+
+impl PartialEq for Include {
+    fn eq(&self, other: &Include) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Hash for Include {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
+}
+
+impl Ord for Include {
+    fn cmp(&self, other: &Include) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl PartialOrd for Include {
+    fn partial_cmp(&self, other: &Include) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The type an environment variable read by `#env` is converted to.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum EnvType {
+    String,
+    Number,
+    Bool,
+}
+
+/// An unresolved `#env` directive.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Env {
+    /// Name of the environment variable.
+    pub name: String,
+    /// Value used if the variable is not set. If `None`, the variable is required.
+    pub default: Option<Box<Value>>,
+    /// Given by the `type` argument, or inferred from the type of `default`.
+    pub ty: EnvType,
+    /// Location of the directive in the including file.
+    pub span: Span,
+}
+
+/// A named value introduced by `#defs(NAME: value, ...)` or `#let(NAME, value)`.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Def {
+    pub name: String,
+    pub value: Value,
+    /// Location of the name in the defining file.
+    pub span: Span,
+}
+
+/// A value preceded by definitions that can be referenced anywhere inside of it.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Scope {
+    pub defs: Vec<Def>,
+    pub value: Box<Value>,
+}
+
+/// An unresolved `#ref` directive.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Ref {
+    /// Name of the referenced definition.
+    pub name: String,
+    /// Selects a nested value of the definition, e.g. `stats.hp` in `#ref(GOBLIN.stats.hp)`.
+    pub select: Option<String>,
+    /// Location of the directive in the referencing file.
+    pub span: Span,
+}
+
+/// An unresolved `#if(condition, then, otherwise)` directive.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct If {
+    /// Must evaluate to a bool, e.g. `profile == "debug"`.
+    pub condition: Expr,
+    pub then: Box<Value>,
+    pub otherwise: Box<Value>,
+    /// Location of the directive.
+    pub span: Span,
+}
+
+/// A condition given to `#cfg`. Names match the active profile or any enabled flag.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum CfgPredicate {
+    Name(String),
+    Not(Box<CfgPredicate>),
+    All(Vec<CfgPredicate>),
+    Any(Vec<CfgPredicate>),
+}
+
+/// One declaration of a struct field that is declared several times or has a `#cfg` attribute.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct CfgBranch {
+    /// `None` for a declaration without `#cfg`, which always matches.
+    pub predicate: Option<CfgPredicate>,
+    pub value: Value,
+}
+
+/// An unresolved struct field with `#cfg` attributes. The last matching declaration is used,
+/// and the field is omitted if none match.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Cfg {
+    pub branches: Vec<CfgBranch>,
+}
+
+impl CfgPredicate {
+    /// Evaluates the predicate, with `is_set` deciding whether a name matches.
+    pub fn eval(&self, is_set: &dyn Fn(&str) -> bool) -> bool {
+        match self {
+            CfgPredicate::Name(name) => is_set(name),
+            CfgPredicate::Not(p) => !p.eval(is_set),
+            CfgPredicate::All(ps) => ps.iter().all(|p| p.eval(is_set)),
+            CfgPredicate::Any(ps) => ps.iter().any(|p| p.eval(is_set)),
+        }
+    }
+
+    pub fn fmt_as_rust(&self) -> String {
+        let list = |ps: &[CfgPredicate]| {
+            ps.iter()
+                .map(|p| p.fmt_as_rust())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        match self {
+            CfgPredicate::Name(name) => format!("CfgPredicate::Name(\"{}\".to_string())", name),
+            CfgPredicate::Not(p) => format!("CfgPredicate::Not(Box::new({}))", p.fmt_as_rust()),
+            CfgPredicate::All(ps) => format!("CfgPredicate::All(vec![{}])", list(ps)),
+            CfgPredicate::Any(ps) => format!("CfgPredicate::Any(vec![{}])", list(ps)),
+        }
+    }
+}
+
+/// The change made by a patch in an overlay file.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum PatchOp {
+    /// `#set("path", value)` replaces an existing value, or adds an entry to a map.
+    Set(Box<Value>),
+    /// `#remove("path")` removes a struct field, map entry or list element.
+    Remove,
+    /// `#append("path", value)` adds an element to the end of a list.
+    Append(Box<Value>),
+}
+
+/// A patch in an overlay file, applied to the base document by [`crate::load_with_overlays`].
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Patch {
+    pub op: PatchOp,
+    /// Path of the patched value in the base document, e.g. `server.port` or `spells[0]`.
+    pub path: String,
+    /// Location of the directive in the overlay file.
+    pub span: Span,
+}
+
+/// An argument to a custom directive, either positional (`"icon.png"`) or named (`size: 32`).
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Argument {
+    pub name: Option<String>,
+    pub value: Value,
+    /// Location of the argument, including its name.
+    pub span: Span,
+}
+
+/// An unresolved use of a custom directive such as `#asset("icon.png", size: 32)`.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct DirectiveCall {
+    /// Name of the directive, without the `#`.
+    pub name: String,
+    pub args: Vec<Argument>,
+    /// Location of the directive.
+    pub span: Span,
+}
+
+impl DirectiveCall {
+    /// Returns the positional arguments, in order.
+    pub fn positional(&self) -> impl Iterator<Item = &Argument> {
+        self.args.iter().filter(|arg| arg.name.is_none())
+    }
+
+    /// Returns the named argument `name`, if given.
+    pub fn named(&self, name: &str) -> Option<&Argument> {
+        self.args
+            .iter()
+            .find(|arg| arg.name.as_deref() == Some(name))
+    }
+}
+
 #[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub enum Value {
     Bool(bool),
@@ -438,11 +696,290 @@ pub enum Value {
     String(String),
     Seq(Vec<Value>),
     Tuple(Option<String>, Vec<Value>),
-    Include(String),
+    Bytes(Vec<u8>),
+    Include(Include),
+    Env(Env),
+    Scope(Scope),
+    Ref(Ref),
+    Expr(Expr),
+    If(If),
+    Cfg(Cfg),
+    Patch(Patch),
+    Directive(DirectiveCall),
     Unit,
 }
 
 impl Value {
+    /// Describes the variant of the value for use in error messages, e.g. "a string".
+    pub(crate) fn kind_name(&self) -> &'static str {
+        match self {
+            Value::Bool(_) => "a bool",
+            Value::Char(_) => "a char",
+            Value::Map(_) => "a map",
+            Value::Struct(_) => "a struct",
+            Value::Number(_) => "a number",
+            Value::Option(_) => "an option",
+            Value::String(_) => "a string",
+            Value::Seq(_) => "a list",
+            Value::Tuple(_, _) => "a tuple",
+            Value::Bytes(_) => "bytes",
+            Value::Include(_) => "an unresolved include",
+            Value::Env(_) => "an unresolved environment variable",
+            Value::Scope(_) => "an unresolved scope",
+            Value::Ref(_) => "an unresolved reference",
+            Value::Expr(_) => "an unresolved expression",
+            Value::If(_) => "an unresolved `#if`",
+            Value::Cfg(_) => "an unresolved `#cfg` field",
+            Value::Patch(_) => "a patch",
+            Value::Directive(_) => "an unresolved directive",
+            Value::Unit => "unit",
+        }
+    }
+
+    pub fn is_unit(&self) -> bool {
+        matches!(self, Value::Unit)
+    }
+
+    pub fn is_bool(&self) -> bool {
+        self.as_bool().is_some()
+    }
+
+    pub fn is_char(&self) -> bool {
+        self.as_char().is_some()
+    }
+
+    pub fn is_string(&self) -> bool {
+        self.as_str().is_some()
+    }
+
+    pub fn is_number(&self) -> bool {
+        self.as_number().is_some()
+    }
+
+    pub fn is_i64(&self) -> bool {
+        self.as_i64().is_some()
+    }
+
+    pub fn is_struct(&self) -> bool {
+        self.as_struct().is_some()
+    }
+
+    pub fn is_map(&self) -> bool {
+        self.as_map().is_some()
+    }
+
+    pub fn is_seq(&self) -> bool {
+        self.as_seq().is_some()
+    }
+
+    pub fn is_tuple(&self) -> bool {
+        self.as_tuple().is_some()
+    }
+
+    pub fn is_bytes(&self) -> bool {
+        self.as_bytes().is_some()
+    }
+
+    /// Whether the value is `None` or `Some(...)`, see [`Value::as_option`].
+    pub fn is_option(&self) -> bool {
+        self.as_option().is_some()
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_char(&self) -> Option<char> {
+        match self {
+            Value::Char(c) => Some(*c),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<Number> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Returns the value if it is an integer. Floats are not converted.
+    pub fn as_i64(&self) -> Option<i64> {
+        self.as_number()?.as_i64()
+    }
+
+    /// Returns the value if it is a non-negative integer.
+    pub fn as_u64(&self) -> Option<u64> {
+        u64::try_from(self.as_i64()?).ok()
+    }
+
+    /// Returns the value of a float or integer as a float.
+    pub fn as_f64(&self) -> Option<f64> {
+        Some(self.as_number()?.into_f64())
+    }
+
+    pub fn as_struct(&self) -> Option<&Struct> {
+        match self {
+            Value::Struct(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_struct_mut(&mut self) -> Option<&mut Struct> {
+        match self {
+            Value::Struct(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&Map> {
+        match self {
+            Value::Map(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    pub fn as_map_mut(&mut self) -> Option<&mut Map> {
+        match self {
+            Value::Map(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    pub fn as_seq(&self) -> Option<&[Value]> {
+        match self {
+            Value::Seq(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_seq_mut(&mut self) -> Option<&mut Vec<Value>> {
+        match self {
+            Value::Seq(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    /// Returns the name and the elements of a tuple.
+    pub fn as_tuple(&self) -> Option<(Option<&str>, &[Value])> {
+        match self {
+            Value::Tuple(name, values) => Some((name.as_deref(), values)),
+            _ => None,
+        }
+    }
+
+    pub fn as_tuple_mut(&mut self) -> Option<&mut Vec<Value>> {
+        match self {
+            Value::Tuple(_, values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// Returns `Some(None)` for `None` and `Some(Some(value))` for `Some(value)`, which is
+    /// parsed as a tuple named `Some`.
+    pub fn as_option(&self) -> Option<Option<&Value>> {
+        match self {
+            Value::Option(option) => Some(option.as_deref()),
+            Value::Tuple(Some(name), values) if name == "Some" && values.len() == 1 => {
+                Some(Some(&values[0]))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the value at `path`, which uses the syntax of `#include` selections such as
+    /// `spells[0].damage`.
+    ///
+    /// Each segment selects a struct field, a map entry with a matching string or integer key,
+    /// or an element of a list or tuple. `Some` is looked through, and the empty path selects
+    /// the value itself. Use [`Value::pointer`] for keys that contain `.` or `[`.
+    pub fn get_path(&self, path: &str) -> Option<&Value> {
+        path_segments(path).try_fold(self, |value, segment| value.child(segment))
+    }
+
+    /// Like [`Value::get_path`], but returns a mutable reference.
+    pub fn get_path_mut(&mut self, path: &str) -> Option<&mut Value> {
+        path_segments(path).try_fold(self, |value, segment| value.child_mut(segment))
+    }
+
+    /// Returns the value at the JSON pointer `pointer` (RFC 6901), such as `/spells/0/damage`.
+    ///
+    /// Segments select values like in [`Value::get_path`], with `~1` standing for `/` and `~0`
+    /// for `~`. The empty pointer selects the value itself.
+    pub fn pointer(&self, pointer: &str) -> Option<&Value> {
+        pointer_segments(pointer)?.try_fold(self, |value, segment| value.child(&segment))
+    }
+
+    /// Like [`Value::pointer`], but returns a mutable reference.
+    pub fn pointer_mut(&mut self, pointer: &str) -> Option<&mut Value> {
+        pointer_segments(pointer)?.try_fold(self, |value, segment| value.child_mut(&segment))
+    }
+
+    /// Returns the struct field, map entry or element that a single path segment selects.
+    pub(crate) fn child(&self, segment: &str) -> Option<&Value> {
+        match self {
+            Value::Struct(s) => s.fields.get(segment),
+            Value::Map(m) => m.0.get(&m.segment_key(segment)?),
+            Value::Seq(values) | Value::Tuple(_, values) => {
+                values.get(segment.parse::<usize>().ok()?)
+            }
+            Value::Option(Some(value)) => value.child(segment),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn child_mut(&mut self, segment: &str) -> Option<&mut Value> {
+        match self {
+            Value::Struct(s) => s.fields.get_mut(segment),
+            Value::Map(m) => {
+                let key = m.segment_key(segment)?;
+                m.0.get_mut(&key)
+            }
+            Value::Seq(values) | Value::Tuple(_, values) => {
+                values.get_mut(segment.parse::<usize>().ok()?)
+            }
+            Value::Option(Some(value)) => value.child_mut(segment),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` for strings, numbers, bools and chars.
+    pub(crate) fn is_scalar(&self) -> bool {
+        matches!(
+            self,
+            Value::String(_) | Value::Number(_) | Value::Bool(_) | Value::Char(_)
+        )
+    }
+
+    /// Formats a scalar without quotes, as it appears when concatenated to a string.
+    pub(crate) fn to_plain_string(&self) -> String {
+        match self {
+            Value::String(s) => s.clone(),
+            Value::Number(Number::Integer(i)) => i.to_string(),
+            Value::Number(Number::Float(f)) => f.0.to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Char(c) => c.to_string(),
+            _ => self.kind_name().to_string(),
+        }
+    }
+
     pub fn fmt_as_rust(&self) -> String {
         match self {
             Value::Bool(b) => format!("Value::Bool({})", b),
@@ -493,12 +1030,124 @@ impl Value {
                     .collect::<Vec<_>>()
                     .join(", ")
             ),
-            Value::Include(s) => format!("Value::Include(\"{}\".to_string())", s),
+            Value::Bytes(b) => format!(
+                "Value::Bytes(vec![{}])",
+                b.iter()
+                    .map(|b| b.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Value::Include(include) => format!(
+                "Value::Include(Include{{kind: IncludeKind::{:?}, path: \"{}\".to_string(), select: {}, span: Span{{start: {}, end: {}}} }})",
+                include.kind,
+                include.path,
+                match &include.select {
+                    None => "None".to_string(),
+                    Some(s) => format!("Some(\"{}\".to_string())", s),
+                },
+                include.span.start,
+                include.span.end,
+            ),
+            Value::Env(env) => format!(
+                "Value::Env(Env{{name: \"{}\".to_string(), default: {}, ty: EnvType::{:?}, span: Span{{start: {}, end: {}}} }})",
+                env.name,
+                match &env.default {
+                    None => "None".to_string(),
+                    Some(v) => format!("Some(Box::new({}))", v.fmt_as_rust()),
+                },
+                env.ty,
+                env.span.start,
+                env.span.end,
+            ),
+            Value::Scope(scope) => format!(
+                "Value::Scope(Scope{{defs: vec![{}], value: Box::new({})}})",
+                scope
+                    .defs
+                    .iter()
+                    .map(|def| format!(
+                        "Def{{name: \"{}\".to_string(), value: {}, span: Span{{start: {}, end: {}}} }}",
+                        def.name,
+                        def.value.fmt_as_rust(),
+                        def.span.start,
+                        def.span.end,
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                scope.value.fmt_as_rust(),
+            ),
+            Value::Ref(r) => format!(
+                "Value::Ref(Ref{{name: \"{}\".to_string(), select: {}, span: Span{{start: {}, end: {}}} }})",
+                r.name,
+                match &r.select {
+                    None => "None".to_string(),
+                    Some(s) => format!("Some(\"{}\".to_string())", s),
+                },
+                r.span.start,
+                r.span.end,
+            ),
+            Value::Expr(expr) => format!("Value::Expr({})", expr.fmt_as_rust()),
+            Value::If(i) => format!(
+                "Value::If(If{{condition: {}, then: Box::new({}), otherwise: Box::new({}), span: Span{{start: {}, end: {}}} }})",
+                i.condition.fmt_as_rust(),
+                i.then.fmt_as_rust(),
+                i.otherwise.fmt_as_rust(),
+                i.span.start,
+                i.span.end,
+            ),
+            Value::Cfg(cfg) => format!(
+                "Value::Cfg(Cfg{{branches: vec![{}]}})",
+                cfg.branches
+                    .iter()
+                    .map(|branch| format!(
+                        "CfgBranch{{predicate: {}, value: {}}}",
+                        match &branch.predicate {
+                            None => "None".to_string(),
+                            Some(p) => format!("Some({})", p.fmt_as_rust()),
+                        },
+                        branch.value.fmt_as_rust(),
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+            Value::Patch(patch) => format!(
+                "Value::Patch(Patch{{op: {}, path: \"{}\".to_string(), span: Span{{start: {}, end: {}}} }})",
+                match &patch.op {
+                    PatchOp::Set(value) => format!("PatchOp::Set(Box::new({}))", value.fmt_as_rust()),
+                    PatchOp::Remove => "PatchOp::Remove".to_string(),
+                    PatchOp::Append(value) =>
+                        format!("PatchOp::Append(Box::new({}))", value.fmt_as_rust()),
+                },
+                patch.path,
+                patch.span.start,
+                patch.span.end,
+            ),
+            Value::Directive(call) => format!(
+                "Value::Directive(DirectiveCall{{name: \"{}\".to_string(), args: vec![{}], span: Span{{start: {}, end: {}}} }})",
+                call.name,
+                call.args
+                    .iter()
+                    .map(|arg| format!(
+                        "Argument{{name: {}, value: {}, span: Span{{start: {}, end: {}}} }}",
+                        match &arg.name {
+                            None => "None".to_string(),
+                            Some(name) => format!("Some(\"{}\".to_string())", name),
+                        },
+                        arg.value.fmt_as_rust(),
+                        arg.span.start,
+                        arg.span.end,
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                call.span.start,
+                call.span.end,
+            ),
             Value::Unit => "Value::Unit".to_string(),
         }
     }
 }
 
+/// Unresolved directives become unit and the names of tuples are dropped, see
+/// [`Value::try_into_ron`] for a conversion that rejects directives.
 impl From<Value> for ron::Value {
     fn from(value: Value) -> Self {
         match value {
@@ -518,8 +1167,254 @@ impl From<Value> for ron::Value {
             Value::String(s) => ron::Value::String(s),
             Value::Seq(s) => ron::Value::Seq(s.into_iter().map(ron::Value::from).collect()),
             Value::Tuple(_, t) => ron::Value::Tuple(t.into_iter().map(ron::Value::from).collect()),
-            Value::Include(_) => ron::Value::Unit,
+            Value::Bytes(b) => ron::Value::Seq(
+                b.into_iter()
+                    .map(|b| ron::Value::Number(ron::Number::from(i64::from(b))))
+                    .collect(),
+            ),
+            Value::Scope(scope) => (*scope.value).into(),
+            Value::Include(_)
+            | Value::Env(_)
+            | Value::Ref(_)
+            | Value::Expr(_)
+            | Value::If(_)
+            | Value::Cfg(_)
+            | Value::Patch(_)
+            | Value::Directive(_) => ron::Value::Unit,
             Value::Unit => ron::Value::Unit,
         }
     }
 }
+
+/// How [`Value::try_into_ron_with`] encodes the names of tuples, which `ron::Value` cannot
+/// represent.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TupleNames {
+    /// Names are dropped, so `Point(1, 2)` becomes `(1, 2)`. Tuple structs and newtype structs
+    /// can be deserialized from the result, but enum variants cannot.
+    Drop,
+    /// Named tuples become a map from the name to the contents, so `Fire(5)` becomes
+    /// `{"Fire": 5}` and `Point(1, 2)` becomes `{"Point": (1, 2)}`. This is how serde
+    /// represents enum variants in self-describing formats such as JSON, so newtype and tuple
+    /// variants can be deserialized after serializing the result to such a format. The
+    /// deserializer of `ron::Value` itself does not support enums.
+    Tagged,
+    /// Like [`TupleNames::Tagged`] for the given names, usually the variants of the enums that
+    /// are deserialized, and like [`TupleNames::Drop`] for all other names, so that tuple structs
+    /// and enum variants can be used in the same document.
+    TaggedVariants(Vec<String>),
+}
+
+impl TupleNames {
+    fn is_tagged(&self, name: &str) -> bool {
+        match self {
+            TupleNames::Drop => false,
+            TupleNames::Tagged => true,
+            TupleNames::TaggedVariants(names) => names.iter().any(|n| n == name),
+        }
+    }
+}
+
+/// Error returned by [`Value::try_into_ron`] for a value that `ron::Value` cannot represent.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IntoRonError {
+    /// Describes the value, e.g. "an unresolved include".
+    pub found: &'static str,
+    /// Path of the value, such as `spells[0]`, or the empty string for the converted value
+    /// itself.
+    pub path: String,
+    /// Location of the directive, if the value is one.
+    pub span: Option<Span>,
+}
+
+impl std::fmt::Display for IntoRonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Cannot convert {}", self.found)?;
+        if !self.path.is_empty() {
+            write!(f, " at `{}`", self.path)?;
+        }
+        write!(f, " to `ron::Value`")
+    }
+}
+
+impl std::error::Error for IntoRonError {}
+
+impl Value {
+    /// Converts the value to a `ron::Value`, failing on directives that have not been resolved,
+    /// such as an `#include` or a struct with a `#prototype`, instead of replacing them with
+    /// unit like `From` does.
+    ///
+    /// `Some(value)`, which is parsed as a tuple named `Some`, becomes an option, and the names
+    /// of other tuples are dropped.
+    pub fn try_into_ron(self) -> Result<ron::Value, IntoRonError> {
+        self.try_into_ron_with(&TupleNames::Drop)
+    }
+
+    /// Like [`Value::try_into_ron`], with the names of tuples encoded as `tuple_names` says.
+    pub fn try_into_ron_with(self, tuple_names: &TupleNames) -> Result<ron::Value, IntoRonError> {
+        self.into_ron_at(tuple_names, "")
+    }
+
+    fn into_ron_at(self, tuple_names: &TupleNames, path: &str) -> Result<ron::Value, IntoRonError> {
+        let elements = |values: Vec<Value>| {
+            values
+                .into_iter()
+                .enumerate()
+                .map(|(i, value)| value.into_ron_at(tuple_names, &provenance::element(path, i)))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(match self {
+            Value::Map(m) => ron::Value::Map(ron::value::Map(
+                m.0.into_iter()
+                    .map(|(k, v)| {
+                        let path = if k.is_scalar() {
+                            provenance::field(path, &k.to_plain_string())
+                        } else {
+                            path.to_string()
+                        };
+                        Ok((
+                            k.into_ron_at(tuple_names, &path)?,
+                            v.into_ron_at(tuple_names, &path)?,
+                        ))
+                    })
+                    .collect::<Result<_, _>>()?,
+            )),
+            Value::Struct(s) if s.prototype.is_none() => ron::Value::Struct(ron::value::Struct {
+                name: s.name,
+                fields: s
+                    .fields
+                    .into_iter()
+                    .map(|(k, v)| {
+                        let value = v.into_ron_at(tuple_names, &provenance::field(path, &k))?;
+                        Ok((k, value))
+                    })
+                    .collect::<Result<_, _>>()?,
+            }),
+            Value::Option(v) => ron::Value::Option(match v {
+                Some(v) => Some(Box::new(v.into_ron_at(tuple_names, path)?)),
+                None => None,
+            }),
+            Value::Tuple(Some(name), mut values) if name == "Some" && values.len() == 1 => {
+                let value = values.remove(0);
+                ron::Value::Option(Some(Box::new(value.into_ron_at(tuple_names, path)?)))
+            }
+            Value::Tuple(name, values) => {
+                let mut values = elements(values)?;
+                match name {
+                    Some(name) if tuple_names.is_tagged(&name) => {
+                        let contents = match values.len() {
+                            1 => values.remove(0),
+                            _ => ron::Value::Tuple(values),
+                        };
+                        let entry = (ron::Value::String(name), contents);
+                        ron::Value::Map(ron::value::Map(std::iter::once(entry).collect()))
+                    }
+                    _ => ron::Value::Tuple(values),
+                }
+            }
+            Value::Seq(values) => ron::Value::Seq(elements(values)?),
+            Value::Scope(scope) => scope.value.into_ron_at(tuple_names, path)?,
+            Value::Bool(_)
+            | Value::Char(_)
+            | Value::Number(_)
+            | Value::String(_)
+            | Value::Bytes(_)
+            | Value::Unit => self.into(),
+            Value::Struct(_)
+            | Value::Include(_)
+            | Value::Env(_)
+            | Value::Ref(_)
+            | Value::Expr(_)
+            | Value::If(_)
+            | Value::Cfg(_)
+            | Value::Patch(_)
+            | Value::Directive(_) => {
+                let found = match &self {
+                    Value::Struct(_) => "a struct with an unresolved prototype",
+                    value => value.kind_name(),
+                };
+                let span = match &self {
+                    Value::Include(include) => Some(include.span),
+                    Value::Env(env) => Some(env.span),
+                    Value::Ref(r) => Some(r.span),
+                    Value::Expr(expr) => Some(expr.span),
+                    Value::If(i) => Some(i.span),
+                    Value::Patch(patch) => Some(patch.span),
+                    Value::Directive(call) => Some(call.span),
+                    _ => None,
+                };
+                return Err(IntoRonError {
+                    found,
+                    path: path.to_string(),
+                    span,
+                });
+            }
+        })
+    }
+}
+
+/// Converts a value produced by the `ron` crate, e.g. with `ron::from_str` or `ron::ser`.
+///
+/// `ron::Value` does not know about directives, so the result never contains them and structs
+/// have no prototype. It also does not record tuple names, so every tuple is unnamed, including
+/// `Some(...)` which `ron` parses into [`Value::Option`] rather than a tuple.
+impl From<ron::Value> for Value {
+    fn from(value: ron::Value) -> Self {
+        match value {
+            ron::Value::Bool(b) => Value::Bool(b),
+            ron::Value::Char(c) => Value::Char(c),
+            ron::Value::Map(m) => Value::Map(m.into()),
+            ron::Value::Struct(s) => Value::Struct(Struct {
+                name: s.name,
+                prototype: None,
+                prototype_span: None,
+                fields: s.fields.into_iter().map(|(k, v)| (k, v.into())).collect(),
+            }),
+            ron::Value::Number(n) => Value::Number(n.into()),
+            ron::Value::Option(v) => Value::Option(v.map(|v| Box::new((*v).into()))),
+            ron::Value::String(s) => Value::String(s),
+            ron::Value::Seq(s) => Value::Seq(s.into_iter().map(Value::from).collect()),
+            ron::Value::Tuple(t) => Value::Tuple(None, t.into_iter().map(Value::from).collect()),
+            ron::Value::Unit => Value::Unit,
+        }
+    }
+}
+
+impl From<ron::Map> for Map {
+    fn from(map: ron::Map) -> Self {
+        Map(map
+            .0
+            .into_iter()
+            .map(|(k, v)| (k.into(), v.into()))
+            .collect())
+    }
+}
+
+impl From<ron::Number> for Number {
+    fn from(number: ron::Number) -> Self {
+        match number {
+            ron::Number::Integer(i) => Number::Integer(i),
+            ron::Number::Float(f) => Number::Float(Float(f.get())),
+        }
+    }
+}
+
+/// Splits a path such as `spells[0].damage` into field names and indices.
+pub(crate) fn path_segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split(['.', '[', ']']).filter(|s| !s.is_empty())
+}
+
+/// Splits a JSON pointer into its unescaped segments, or returns `None` if it is not empty and
+/// does not start with `/`.
+fn pointer_segments(pointer: &str) -> Option<impl Iterator<Item = String> + '_> {
+    let segments = match pointer {
+        "" => None,
+        pointer => Some(pointer.strip_prefix('/')?.split('/')),
+    };
+    Some(
+        segments
+            .into_iter()
+            .flatten()
+            .map(|segment| segment.replace("~1", "/").replace("~0", "~")),
+    )
+}
@@ -0,0 +1,158 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
+
+use ariadne::{Report, ReportKind};
+use indexmap::IndexMap;
+
+use crate::{DependencyGraph, Loader, Parse, Provenance, Value};
+
+/// An entry point that was reloaded, with its new value or the diagnostics that loading it
+/// produced.
+pub type WatchEvent = (PathBuf, Result<Value, Parse>);
+
+/// Reloads documents when the files they depend on change.
+///
+/// The watcher polls modification times and contents through the [`crate::FileSystem`] of its
+/// [`Loader`], so it does not need any platform specific file notifications. Only entry points
+/// that depend on a changed file, for example through `#include` or `#prototype`, are reloaded.
+pub struct Watcher {
+    loader: Loader,
+    entries: IndexMap<PathBuf, Watched>,
+}
+
+/// The result of the last load of an entry point.
+enum Watched {
+    Loaded {
+        /// The files, directories and missing paths that the result depends on.
+        dependencies: Vec<PathBuf>,
+    },
+    /// The entry point could not be read, so it is retried on every poll.
+    Failed { error: String },
+}
+
+impl Watcher {
+    pub fn new(loader: Loader) -> Watcher {
+        Watcher {
+            loader,
+            entries: IndexMap::new(),
+        }
+    }
+
+    pub fn loader(&self) -> &Loader {
+        &self.loader
+    }
+
+    /// Starts watching the entry point at `path` and returns the result of loading it.
+    pub fn watch<P: Into<PathBuf>>(&mut self, path: P) -> WatchEvent {
+        let path = path.into();
+        let result = self.load(&path);
+        (path, result)
+    }
+
+    /// Stops watching the entry point at `path`.
+    pub fn unwatch<P: AsRef<Path>>(&mut self, path: P) {
+        self.entries.shift_remove(path.as_ref());
+    }
+
+    /// Checks all watched files for changes and reloads the entry points that are affected.
+    pub fn poll(&mut self) -> Vec<WatchEvent> {
+        let changed = self.loader.refresh();
+        let affected = self
+            .entries
+            .iter()
+            .filter(|(_, watched)| match watched {
+                Watched::Loaded { dependencies } => {
+                    dependencies.iter().any(|path| changed.contains(path))
+                }
+                Watched::Failed { .. } => true,
+            })
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        let mut events = vec![];
+        for path in affected {
+            let previous_error = match &self.entries[&path] {
+                Watched::Failed { error } => Some(error.clone()),
+                Watched::Loaded { .. } => None,
+            };
+            let result = self.load(&path);
+            // Entry points that still cannot be read are only reported when the error changes.
+            match (&self.entries[&path], previous_error) {
+                (Watched::Failed { error }, Some(previous)) if *error == previous => {}
+                _ => events.push((path, result)),
+            }
+        }
+        events
+    }
+
+    /// Polls for changes every `interval` on a background thread and sends the events to the
+    /// returned channel. The thread exits once the receiver is dropped and another event occurs.
+    pub fn spawn(mut self, interval: Duration) -> Receiver<WatchEvent> {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            for event in self.poll() {
+                if sender.send(event).is_err() {
+                    return;
+                }
+            }
+        });
+        receiver
+    }
+
+    /// Polls for changes every `interval` and calls `callback` for every event, until the
+    /// callback returns `false`.
+    pub fn run<F: FnMut(WatchEvent) -> bool>(&mut self, interval: Duration, mut callback: F) {
+        loop {
+            std::thread::sleep(interval);
+            for event in self.poll() {
+                if !callback(event) {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn load(&mut self, path: &Path) -> Result<Value, Parse> {
+        match self.loader.load(path) {
+            Ok(parse) => {
+                // Creating a file in a listed directory or at a missing path can also change
+                // the result.
+                let dependencies = parse
+                    .dependencies
+                    .iter()
+                    .chain(&self.loader.watched)
+                    .cloned()
+                    .collect();
+                self.entries
+                    .insert(path.to_path_buf(), Watched::Loaded { dependencies });
+                if parse.errors.is_empty() {
+                    Ok(parse.value)
+                } else {
+                    Err(parse)
+                }
+            }
+            Err(err) => {
+                let source_name = path.to_string_lossy().to_string();
+                let message = format!("Cannot load `{}`: {}", source_name, err);
+                self.entries.insert(
+                    path.to_path_buf(),
+                    Watched::Failed {
+                        error: message.clone(),
+                    },
+                );
+                Err(Parse {
+                    value: Value::Unit,
+                    errors: vec![Report::build(ReportKind::Error, source_name, 0)
+                        .with_message(message)
+                        .finish()],
+                    warnings: vec![],
+                    sources: vec![],
+                    dependencies: vec![],
+                    graph: DependencyGraph::default(),
+                    provenance: Provenance::default(),
+                })
+            }
+        }
+    }
+}